@@ -0,0 +1,48 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use stackmachine::stackmachine::fast::{self, FastMachine};
+use stackmachine::stackmachine::{reader, Program, StackMachine};
+
+// A function with a branch, called many times over, with arithmetic between
+// the calls
+fn workload() -> Program {
+    let mut source = String::from(
+        "const 0\npushstr step\nfunction\n\
+         const 100\nlt\n\
+         if\nconst 3\nelse\nconst -7\nendif\n\
+         endfunction\n\
+         const 0\n",
+    );
+    for i in 0..500 {
+        source.push_str(&format!(
+            "const {}\nconst 0\npushstr step\ncall\nadd\nconst 5\nsub\n",
+            i % 200
+        ));
+    }
+    reader::parse(&source, "bench.sm")
+}
+
+fn bench_engines(c: &mut Criterion) {
+    let program = workload();
+    let compiled = fast::compile(&program).unwrap();
+
+    let mut group = c.benchmark_group("workload");
+    group.bench_function("interpreter", |b| {
+        b.iter(|| {
+            let mut sm = StackMachine::new(2u32.pow(8));
+            sm.execute_program(black_box(&program));
+            sm.stack
+        })
+    });
+    group.bench_function("fast", |b| {
+        b.iter(|| {
+            let mut fm = FastMachine::new();
+            fm.run(black_box(&compiled)).unwrap();
+            fm.stack
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_engines);
+criterion_main!(benches);
@@ -4,9 +4,14 @@ pub mod stackmachine;
 pub mod tests {
 
     use super::stackmachine::builder::Builder;
+    use super::stackmachine::check;
+    use super::stackmachine::files::Access;
     use super::stackmachine::function::Op;
     use super::stackmachine::reader;
-    use super::stackmachine::StackMachine;
+    use super::stackmachine::sink::Capture;
+    use super::stackmachine::Trap;
+    use super::stackmachine::{ForkPolicy, StackMachine};
+    use std::sync::{Arc, Mutex};
 
     #[test]
     pub fn test_add() {
@@ -106,6 +111,50 @@ pub mod tests {
         assert_eq!(Some(2), sm.pop());
     }
 
+    // Records the value on top of the stack and the PID of the machine
+    fn recorder(sm: &mut StackMachine) -> Arc<Mutex<Vec<(i32, u16)>>> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&log);
+        sm.register("record", 1, 0, move |ctx| {
+            let v = ctx.pop();
+            seen.lock().unwrap().push((v, ctx.pid));
+            Ok(())
+        });
+        log
+    }
+
+    #[test]
+    fn test_fork_inherits_functions() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let log = recorder(&mut sm);
+        let program = reader::parse(
+            "const 0\npushstr twice\nfunction\nconst 2\nmul\nendfunction\nfork\nchild\nif\nconst 21\nconst 0\npushstr twice\ncall\nconst 0\npushstr record\ncallext\nendif",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![(42, 1)], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn test_fork_policy() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let log = recorder(&mut sm);
+        sm.fork_policy = ForkPolicy {
+            stack: false,
+            functions: false,
+            ..ForkPolicy::default()
+        };
+        let program = reader::parse(
+            "const 0\npushstr twice\nfunction\nendfunction\nconst 5\nfork\nchild\nif\ndepth\nconst 0\npushstr record\ncallext\ntry\nconst 0\npushstr twice\ncall\ncatch\nconst 0\npushstr record\ncallext\nendtry\nendif",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![(0, 1), (-5, 1)], *log.lock().unwrap());
+        assert_eq!(vec![5], sm.stack);
+    }
+
     #[test]
     fn test_if_true() {
         let mut sm = StackMachine::new(2u32.pow(8));
@@ -187,6 +236,446 @@ pub mod tests {
         assert_eq!(Some(0i32), sm.pop());
     }
 
+    #[test]
+    fn test_compare() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![
+            (Op::Const, Some(2i32)),
+            (Op::Const, Some(3i32)),
+            (Op::GT, None),
+            (Op::Const, Some(3i32)),
+            (Op::Const, Some(2i32)),
+            (Op::GT, None),
+            (Op::Const, Some(3i32)),
+            (Op::Const, Some(3i32)),
+            (Op::LTE, None),
+        ]);
+
+        assert_eq!(vec![1, 0, 1], sm.stack);
+    }
+
+    #[test]
+    fn test_loop_break() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        // Pop values until one of them is not positive
+        sm.stack = vec![0, 0, 1, 1, 1];
+        sm.execute(vec![
+            (Op::Loop, None),
+            (Op::Not, None),
+            (Op::If, None),
+            (Op::Break, None),
+            (Op::EndIf, None),
+            (Op::EndLoop, None),
+        ]);
+
+        assert_eq!(vec![0], sm.stack);
+    }
+
+    #[test]
+    fn test_block_break() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![
+            (Op::Block, None),
+            (Op::Const, Some(1i32)),
+            (Op::Break, None),
+            (Op::Const, Some(2i32)),
+            (Op::EndBlock, None),
+            (Op::Const, Some(3i32)),
+        ]);
+
+        assert_eq!(vec![1, 3], sm.stack);
+    }
+
+    #[test]
+    fn test_return() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.function_table.insert(
+            "fn".to_string(),
+            vec![
+                (Op::Const, Some(1i32)),
+                (Op::If, None),
+                (Op::Const, Some(7i32)),
+                (Op::Return, None),
+                (Op::EndIf, None),
+                (Op::Const, Some(8i32)),
+            ],
+        );
+
+        sm.execute(vec![
+            (Op::Const, Some(0i32)), // Char codes for 'fn'
+            (Op::Const, Some(110i32)),
+            (Op::Const, Some(102i32)),
+            (Op::Call, None),
+            (Op::Const, Some(9i32)),
+        ]);
+
+        assert_eq!(vec![7, 9], sm.stack);
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![
+            (Op::Const, Some(1i32)),
+            (Op::Const, Some(2i32)),
+            (Op::Const, Some(3i32)),
+            (Op::Rot, None),  // 2 3 1
+            (Op::Over, None), // 2 3 1 3
+            (Op::Swap, None), // 2 3 3 1
+            (Op::Dup, None),  // 2 3 3 1 1
+        ]);
+
+        assert_eq!(vec![2, 3, 3, 1, 1], sm.stack);
+    }
+
+    #[test]
+    fn test_pick_roll() {
+        let mut sm = StackMachine::new(2u32.pow(16));
+        let code = vec![
+            (Op::Const, Some(1)),
+            (Op::Const, Some(2)),
+            (Op::Const, Some(3)),
+            (Op::Pick, Some(2)), // 1 2 3 1
+            (Op::Roll, Some(3)), // 2 3 1 1
+            (Op::Tuck, None),    // 2 3 1 1 1
+            (Op::Nip, None),     // 2 3 1 1
+            (Op::Depth, None),   // 2 3 1 1 4
+        ];
+        sm.execute(code);
+
+        assert_eq!(vec![2, 3, 1, 1, 4], sm.stack);
+
+        sm.execute(vec![(Op::Clear, None), (Op::Depth, None)]);
+
+        assert_eq!(vec![0], sm.stack);
+    }
+
+    #[test]
+    #[should_panic(expected = "Trap: stack underflow in `pick`.")]
+    fn test_pick_too_deep() {
+        let mut sm = StackMachine::new(2u32.pow(16));
+        sm.execute(vec![
+            (Op::Const, Some(1)),
+            (Op::Const, Some(2)),
+            (Op::Pick, Some(2)),
+        ]);
+    }
+
+    #[test]
+    fn test_negative_pick() {
+        let mut sm = StackMachine::new(2u32.pow(16));
+
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::Roll)),
+            sm.try_execute(vec![(Op::Const, Some(1)), (Op::Roll, Some(-1))])
+        );
+    }
+
+    #[test]
+    fn test_catch_unterminated_string() {
+        let mut sm = StackMachine::new(2u32.pow(16));
+        let program = reader::parse("try\nconst 104\nprintchars\ncatch\nendtry", "t.sm");
+        sm.execute_program(&program);
+
+        assert_eq!(vec![-1], sm.stack);
+    }
+
+    #[test]
+    fn test_huge_array() {
+        let mut sm = StackMachine::new(2u32.pow(16));
+        let program = reader::parse("try\nconst 2000000000\narray.new\ncatch\nendtry", "t.sm");
+        sm.execute_program(&program);
+
+        assert_eq!(vec![-4], sm.stack);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![
+            (Op::Const, Some(-2i32)),
+            (Op::Const, Some(8i32)),
+            (Op::Store, None),
+            (Op::Const, Some(8i32)),
+            (Op::Load, None),
+            (Op::Const, Some(12i32)),
+            (Op::Load, None),
+        ]);
+
+        assert_eq!(vec![-2, 0], sm.stack);
+        assert_eq!([0xfe, 0xff, 0xff, 0xff], sm.memory[8..12]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_memory_out_of_bounds() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![(Op::Const, Some(254i32)), (Op::Load, None)]);
+    }
+
+    #[test]
+    fn test_integer_ops() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 3\nconst -7\nmod\nneg\nabs\nconst 6\nand\nconst 1\nor\nconst 2\nxor\nconst 4\nmax\nconst 2\nmin\nconst 4\nswap\nshl\nconst 1\nswap\nsar\nconst 28\nconst -1\nshr",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![16, 15], sm.stack);
+
+        sm.stack.clear();
+        sm.execute(vec![
+            (Op::Const, Some(1)),
+            (Op::Const, Some(i32::MAX)),
+            (Op::Add, None),
+        ]);
+
+        assert_eq!(vec![i32::MIN], sm.stack);
+    }
+
+    #[test]
+    fn test_traps() {
+        let cases = [
+            ("const 0\nconst 1\ndiv", Trap::DivideByZero(Op::Div)),
+            ("const 0\nconst 1\nmod", Trap::DivideByZero(Op::Mod)),
+            (
+                "const 1\nconst 2147483647\nadd.checked",
+                Trap::Overflow(Op::AddChecked),
+            ),
+            (
+                "const -2147483648\nneg.checked",
+                Trap::Overflow(Op::NegChecked),
+            ),
+        ];
+        for (source, trap) in cases {
+            let mut sm = StackMachine::new(2u32.pow(8));
+            let program = reader::parse(source, "t.sm");
+
+            assert_eq!(Err(trap), sm.try_execute_program(&program), "{}", source);
+        }
+
+        // Traps unwind out of loops and function calls
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nloop\nconst 0\nconst 1\ndiv\nendloop\nendfunction\nconst 5\nconst 0\npushstr f\ncall\nconst 6",
+            "t.sm",
+        );
+
+        assert_eq!(
+            Err(Trap::DivideByZero(Op::Div)),
+            sm.try_execute_program(&program)
+        );
+        assert_eq!(vec![5], sm.stack);
+    }
+
+    #[test]
+    fn test_wide_values() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "f.const 2.5\nf.const -1e3\nf.div\nf.print\nf.const -400\nf.eq\ni64.const 3\ni64.const 9000000000\ni64.mul\ni64.print\nl2f\nf.sqrt\nf2l\ni64.const 164316\ni64.eq",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![1, 1], sm.stack);
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr greet\nfunction\nstr.const hello,\nstr.concat\nendfunction\nstr.const world\nconst 0\npushstr greet\ncall\ndup\nstr.len\nswap\nstr.const hello,world\nstr.eq",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![11, 1], sm.stack);
+        assert_eq!(Some("hello,world"), sm.heap.string(3));
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 3\narray.new\nconst 16\nstore\nalloc 2\nconst 1000\nloop\nconst 8\narray.new\npop\nconst -1\nadd\ndup\nnot\nif\nbreak\nendif\nendloop\npop",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        // The array in memory and the one on the stack survive
+        assert!(sm.heap.is_live(1));
+        assert_eq!(Some(&[0, 0][..]), sm.heap.array(2));
+        assert!(sm.heap.stats.collections > 0);
+        assert!(sm.heap.live() < 100);
+        assert!(sm.to_string().contains("Heap<live"));
+
+        sm.stack.clear();
+        sm.collect_garbage();
+
+        assert_eq!(1, sm.heap.live());
+    }
+
+    #[test]
+    fn test_function_references() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr inc\nfunction\nconst 1\nadd\nendfunction\nconst 0\npushstr dbl\nfunction\nconst 2\nmul\nendfunction\nconst 2\narray.new\nfnref inc\nconst 0\npick 2\narray.set\nfnref dbl\nconst 1\npick 2\narray.set\nconst 5\nconst 0\npick 2\narray.get\ncall.indirect\nconst 1\npick 2\narray.get\ncall.indirect\nnip",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![12], sm.stack);
+        assert_eq!(Some(2), sm.function_handle("dbl"));
+        assert_eq!(None, sm.function_handle("nope"));
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr add_n\nfunction\nenv.get 0\nadd\nendfunction\nconst 10\nfnref add_n\nclosure 1\nconst 5\nover\ncall.closure\nconst 3\narray.new\nfnref add_n\nclosure 1",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![1, 15, 3], sm.stack);
+        assert_eq!(Some(("add_n", &[10][..])), sm.heap.closure(1));
+
+        // The array is only reachable through the second closure
+        sm.collect_garbage();
+
+        assert!(sm.heap.is_live(2));
+    }
+
+    #[test]
+    fn test_env_outside_closure() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nenv.get 0\nendfunction\nconst 0\npushstr g\nfunction\nconst 0\npushstr f\ncall\nendfunction\nconst 1\nfnref g\nclosure 1\ncall.closure",
+            "t.sm",
+        );
+
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::EnvGet)),
+            sm.try_execute_program(&program)
+        );
+    }
+
+    #[test]
+    fn test_closure_out_of_range() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nendfunction\ntry\nfnref f\nclosure -1\ncatch\nendtry\ntry\nconst 1\nfnref f\nclosure 3\ncatch\nendtry",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![-4, -1], sm.stack);
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 7\ntry\nconst 0\nconst 1\ndiv\ncatch\nendtry\ntry\npop\npop\npop\ncatch\nendtry\ntry\nconst 0\npushstr nope\ncall\ncatch\nendtry\ntry\nthrow 42\nconst 1\ncatch\nconst 100\nadd\nendtry\ntry\nconst 9\ncatch\nendtry",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![7, -2, -1, -5, 142, 9], sm.stack);
+    }
+
+    #[test]
+    fn test_try_keeps_saved_handles() {
+        // The body drops the only handle to `keep` on the stack and allocates
+        // enough to be collected before it fails
+        let source = format!(
+            "str.const keep\ntry\npop\n{}throw 1\ncatch\npop\nprintstr\nendtry",
+            "str.const junk\npop\n".repeat(100)
+        );
+        let output = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_output(output.clone());
+        sm.execute_program(&reader::parse(&source, "t.sm"));
+
+        assert!(sm.heap.stats.collections > 0);
+        assert_eq!("keep\n", output.contents());
+    }
+
+    #[test]
+    fn test_throw_through_functions() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "const 0\npushstr inner\nfunction\nthrow 3\nendfunction\nconst 0\npushstr outer\nfunction\nconst 0\npushstr inner\ncall\nendfunction\nconst 0\npushstr outer\ncall",
+            "t.sm",
+        );
+        let trap = sm.try_execute_program(&program).unwrap_err();
+
+        assert_eq!(Trap::Thrown(3), trap);
+        assert_eq!(
+            "Trap: uncaught exception 3.\n    in function inner\n    in function outer",
+            sm.trap_report(&trap)
+        );
+    }
+
+    #[test]
+    fn test_bad_function_handle() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        assert_eq!(
+            Err(Trap::BadHandle(Op::CallIndirect)),
+            sm.try_execute(reader::parse("const 3\ncall.indirect", "t.sm").code)
+        );
+    }
+
+    #[test]
+    fn test_catch_bad_handles_and_addresses() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let program = reader::parse(
+            "try\nconst 7\nprintstr\ncatch\nendtry\ntry\nconst 7\ncall.closure\ncatch\nendtry\ntry\nconst 0\nconst 7\narray.get\ncatch\nendtry\ntry\nconst 7\nclosure 0\ncatch\nendtry\ntry\nconst 256\nload\ncatch\nendtry\ntry\nconst 0\nconst -1\nstore\ncatch\nendtry\ntry\nenv.get 0\ncatch\nendtry",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![-10, -10, -10, -10, -4, -4, -4], sm.stack);
+    }
+
+    #[test]
+    #[should_panic(expected = "Trap: undefined function `f`.")]
+    fn test_undefined_function_reference() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(reader::parse("fnref f", "t.sm").code);
+    }
+
+    #[test]
+    #[should_panic(expected = "Trap: stack underflow in `printchars`.")]
+    fn test_unterminated_string() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(reader::parse("pushstr hi\nprintchars", "t.sm").code);
+    }
+
+    #[test]
+    #[should_panic(expected = "Trap: division by zero in `div`.")]
+    fn test_trap_panics() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        sm.execute(vec![
+            (Op::Const, Some(0)),
+            (Op::Const, Some(1)),
+            (Op::Div, None),
+        ]);
+    }
+
     #[test]
     /*
      * Should perhaps write out to a file an example so not to rely
@@ -196,6 +685,14 @@ pub mod tests {
         let _ = reader::read(&String::from("examples/adder.sm"));
     }
 
+    #[test]
+    fn test_read_invalid_utf8() {
+        let path = std::env::temp_dir().join("stackmachine-invalid-utf8.sm");
+        std::fs::write(&path, b"const 1\nconst \xff\nprint\n").unwrap();
+
+        assert_eq!(None, reader::read_program(path.to_str().unwrap()));
+    }
+
     #[test]
     fn test_integration_builder() {
         let mut builder = Builder::new(2u32.pow(16));
@@ -305,6 +802,49 @@ pub mod tests {
         assert_eq!(sm.last(), Some(3 + 2 + 2));
     }
 
+    #[test]
+    fn test_register_host_function() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&log);
+        sm.register("sum_n", 2, 1, move |ctx| {
+            let (a, b) = (ctx.pop(), ctx.pop());
+            seen.lock().unwrap().push((a, b, ctx.pid));
+            ctx.store(8, a + b)?;
+            ctx.push(a + b);
+            Ok(())
+        });
+        sm.register("fail", 0, 0, |_| Err(Trap::Thrown(17)));
+
+        let program = reader::parse(
+            "const 2\nconst 3\nconst 0\npushstr sum_n\ncallext\ntry\nconst 0\npushstr fail\ncallext\ncatch\nendtry\ntry\nconst 0\npushstr missing\ncallext\ncatch\nendtry",
+            "t.sm",
+        );
+        sm.execute_program(&program);
+
+        assert_eq!(vec![5, 17, -5], sm.stack);
+        assert_eq!(vec![(3, 2, 0)], *log.lock().unwrap());
+        assert_eq!([5, 0, 0, 0], sm.memory[8..12]);
+
+        let analysis = check::check_with_externals(&program, &sm.host_signatures());
+        assert_eq!(Some(1), analysis.depths[10]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Host function bad declared ( 0 -- 1 ) but left the stack at 2 values"
+    )]
+    fn test_host_function_signature_mismatch() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.register("bad", 0, 1, |ctx| {
+            ctx.push(1);
+            ctx.push(2);
+            Ok(())
+        });
+
+        sm.execute(reader::parse("const 0\npushstr bad\ncallext", "t.sm").code);
+    }
+
     #[test]
     #[ignore]
     /*
@@ -325,6 +865,180 @@ pub mod tests {
      */
     fn test_cli() {}
 
+    /*
+     * Runs every example without `fork` or `include` and compares what it
+     * printed with `tests/golden/<name>.out`. Set `UPDATE_GOLDEN` to rewrite
+     * the expected output from the current interpreter.
+     */
+    #[test]
+    fn test_golden_output() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            if source.contains("fork") || source.contains("include") {
+                continue;
+            }
+            let program = reader::load(path.to_str().unwrap()).unwrap();
+            let output = Capture::new();
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.set_output(output.clone());
+            sm.execute_program(&program);
+
+            let golden = std::path::Path::new("tests/golden")
+                .join(path.file_stem().unwrap())
+                .with_extension("out");
+            if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                std::fs::create_dir_all("tests/golden").unwrap();
+                std::fs::write(&golden, output.contents()).unwrap();
+            }
+            let expected = std::fs::read_to_string(&golden).unwrap();
+
+            assert_eq!(expected, output.contents(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_scripted_input() {
+        let program = reader::parse(
+            "read.line\nprintchars\nconst 0\nloop\neof\nif\nbreak\nendif\nread.int\nadd\nendloop\nprint",
+            "t.sm",
+        );
+        let output = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_input("numbers:\n1 2\n3".as_bytes());
+        sm.set_output(output.clone());
+        sm.execute_program(&program);
+
+        assert_eq!("numbers:\n6\n", output.contents());
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let program = reader::parse("try\nread.int\ncatch\nendtry\nread.char", "t.sm");
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_input("x".as_bytes());
+        sm.execute_program(&program);
+
+        // The failed `read.int` leaves the `x` for `read.char`
+        assert_eq!(vec![-6, 120], sm.stack);
+    }
+
+    #[test]
+    fn test_file_copy() {
+        let dir = std::env::temp_dir().join("stackmachine-file-copy");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("in.txt"), "one\ntwo\n").unwrap();
+        let source = format!(
+            "const 0\nstr.const {0}/in.txt\nfile.open\nconst 1\nstr.const {0}/out.txt\nfile.open\nloop\npick 1\nfile.read\nif\npick 1\nfile.write\npop\nelse\npop\nbreak\nendif\nendloop\nfile.close\nswap\nfile.close",
+            dir.display()
+        );
+        let program = reader::parse(&source, "t.sm");
+
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.execute_program(&program);
+
+        // Nothing was granted, so neither file opens or closes
+        assert_eq!(vec![-9, -9], sm.stack);
+        assert!(!dir.join("out.txt").exists());
+
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.allow(&dir, Access::Read).unwrap();
+        sm.allow(&dir, Access::Write).unwrap();
+        sm.execute_program(&program);
+
+        assert_eq!(vec![0, 0], sm.stack);
+        assert_eq!(
+            "one\ntwo\n",
+            std::fs::read_to_string(dir.join("out.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_args_and_vars() {
+        let program = reader::parse(
+            "argc\nargv 1\nstr.len\nstr.const HOME\ngetenv\nswap\nstr.len\nstr.const NOPE\ngetenv",
+            "t.sm",
+        );
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.args = vec!["t.sm".to_string(), "four".to_string()];
+        sm.vars.insert("HOME".to_string(), "/home".to_string());
+        sm.execute_program(&program);
+
+        // The count, the argument's length, HOME being set and its length,
+        // then an empty string and NOPE not being set
+        assert_eq!(vec![2, 4, 1, 5], sm.stack[..4].to_vec());
+        assert_eq!(0, sm.stack[5]);
+
+        let program = reader::parse("argv 2", "t.sm");
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::Argv)),
+            sm.try_execute_program(&program)
+        );
+    }
+
+    #[test]
+    fn test_exit() {
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nloop\nexit 3\nendloop\nendfunction\nconst 1\ntry\nconst 0\npushstr f\ncall\ncatch\nendtry\nconst 2",
+            "t.sm",
+        );
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.execute_program(&program);
+
+        // `try` does not catch an `exit`, and nothing after it runs
+        assert_eq!(Some(3), sm.exit_status);
+        assert_eq!(vec![1], sm.stack);
+
+        sm.execute(reader::parse("const 4", "t.sm").code);
+        assert_eq!(None, sm.exit_status);
+    }
+
+    #[test]
+    fn test_exit_in_child() {
+        let program = reader::parse("fork\nchild\nif\nexit 5\nendif\nconst 7\nprint", "t.sm");
+        let output = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_output(output.clone());
+        sm.execute_program(&program);
+
+        // Only the parent gets past the `if`, and the child's status is not its
+        assert_eq!("7\n", output.contents());
+        assert_eq!(None, sm.exit_status);
+        assert_eq!(vec![7], sm.stack);
+    }
+
+    #[test]
+    fn test_trap_in_child() {
+        let program = reader::parse("fork\nchild\nif\nthrow 3\nendif\nconst 7", "t.sm");
+        let mut sm = StackMachine::new(2u32.pow(8));
+
+        // The child's trap goes to stderr only; the parent still succeeds
+        assert_eq!(Ok(()), sm.try_execute_program(&program));
+        assert_eq!(None, sm.exit_status);
+        assert_eq!(vec![7], sm.stack);
+    }
+
+    #[test]
+    fn test_trace_sink() {
+        let code = reader::parse("const 1\nconst 2\nadd", "t.sm").code;
+        let output = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_output(output.clone());
+        sm.execute(code.clone());
+
+        assert_eq!("", output.contents());
+
+        let trace = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_output(output.clone());
+        sm.set_trace(trace.clone());
+        sm.execute(code);
+
+        assert_eq!("", output.contents());
+        assert!(trace.contents().contains("Executing routine"));
+    }
+
     #[test]
     #[ignore]
     /*
@@ -1,33 +1,267 @@
-use stackmachine::stackmachine::{reader, StackMachine};
+use stackmachine::stackmachine::emit::EmitError;
+use stackmachine::stackmachine::files::Access;
+use stackmachine::stackmachine::{
+    check, disasm, emit_c, emit_wat, fast, formatter, optimize, reader, reader::Dialect, Program,
+    StackMachine, Trap,
+};
 use std::env;
+use std::fs;
 use std::path::Path;
+use std::process;
 
-// Prefer panics at this level. If we encounter an error at this level, we
-// want to self destruct. Lower than this, prefer to return results and
-// options so problems can be handled.
-fn main() -> Result<(), std::io::Error> {
-    let args: Vec<String> = env::args().collect();
+const USAGE: &str = "usage:
+    stackmachine [run] [-O] [--fast] [--trace] [--allow-read DIR] [--allow-write DIR] FILE... [-- ARG...]
+    stackmachine compile FILE [-o OUTPUT] [-O] [--strip]
+    stackmachine check FILE...
+    stackmachine disasm FILE
+    stackmachine emit-c FILE [-o OUTPUT]
+    stackmachine emit-wat FILE [-o OUTPUT]
+    stackmachine fmt [--check] FILE...
 
-    if args.len() == 1 {
-        panic!("Please pass filename to stackmachine.")
+Every command except fmt accepts `--dialect forth` to read Forth source, or
+`--dialect chars` to read source where `printstr` prints characters from the stack.";
+
+fn load(arg: &str, dialect: Dialect) -> Program {
+    let path = Path::new(arg);
+    if !path.exists() {
+        panic!("Could not find file {}.", arg);
+    }
+    match reader::load_dialect(arg, dialect) {
+        Some(program) => program,
+        None => panic!("Could not parse code."),
     }
+}
 
-    for arg in args.iter().skip(1) {
-        let path = Path::new(arg);
-        if path.exists() {
-            if let Some(p) = path.to_str() {
-                let mut sm = StackMachine::new(2u32.pow(16));
+// A trap nothing caught ends the whole run with an error, listing the
+// functions that were running, innermost first
+fn exit_on_trap(arg: &str, result: Result<(), Trap>, backtrace: &[String]) {
+    if let Err(trap) = result {
+        eprintln!("{}: trap: {}", arg, trap);
+        for name in backtrace.iter().rev() {
+            eprintln!("    in function {}", name);
+        }
+        process::exit(1);
+    }
+}
 
-                let code = reader::read(&String::from(p));
-                if let Some(c) = code {
-                    sm.execute(c);
-                } else {
-                    panic!("Could not parse code.");
+// With `--fast`, programs the checker can verify run on the fast engine;
+// anything else falls back to the regular interpreter. `--trace` sends the
+// interpreter's own tracing to stderr. `--allow-read` and `--allow-write`
+// grant a directory to the file opcodes; nothing else is reachable. Whatever
+// follows `--` is passed to each program as its arguments, and a program that
+// runs `exit` ends the whole run with that status.
+fn run(args: &[String], dialect: Dialect) -> Result<(), std::io::Error> {
+    let mut optimized = false;
+    let mut use_fast = false;
+    let mut trace = false;
+    let mut grants = Vec::new();
+    let mut files = Vec::new();
+    let mut program_args = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--" => {
+                program_args = iter.by_ref().cloned().collect();
+                break;
+            }
+            "-O" => optimized = true,
+            "--fast" => use_fast = true,
+            "--trace" => trace = true,
+            "--allow-read" => grants.push((iter.next(), Access::Read)),
+            "--allow-write" => grants.push((iter.next(), Access::Write)),
+            _ => files.push(arg),
+        }
+    }
+    for arg in files {
+        let mut program = load(arg, dialect);
+        if optimized {
+            program = optimize::optimize(&program);
+        }
+        if use_fast {
+            match fast::compile(&program) {
+                Ok(compiled) => {
+                    exit_on_trap(arg, fast::FastMachine::new().run(&compiled), &[]);
+                    continue;
                 }
+                Err(e) => eprintln!("{}: {}; using the interpreter", arg, e),
+            }
+        }
+        let mut sm = StackMachine::new(2u32.pow(16));
+        if trace {
+            sm.set_trace(std::io::stderr());
+        }
+        for (dir, access) in &grants {
+            let dir = dir.unwrap_or_else(|| panic!("{}", USAGE));
+            if let Err(e) = sm.allow(dir, *access) {
+                panic!("Could not allow access to {}: {}", dir, e);
+            }
+        }
+        sm.args = vec![arg.clone()];
+        sm.args.extend(program_args.iter().cloned());
+        sm.vars = env::vars().collect();
+        let result = sm.try_execute_program(&program);
+        exit_on_trap(arg, result, &sm.backtrace);
+        if let Some(status) = sm.exit_status {
+            process::exit(status);
+        }
+    }
+    Ok(())
+}
+
+// Writes the binary form of a source file, by default next to the source with
+// an `.smb` extension. `--strip` leaves out the debug section.
+fn compile(args: &[String], dialect: Dialect) -> Result<(), std::io::Error> {
+    let mut input = None;
+    let mut output = None;
+    let mut strip = false;
+    let mut optimized = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = iter.next().cloned(),
+            "-O" => optimized = true,
+            "--strip" => strip = true,
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => panic!("Unexpected argument {}.\n{}", arg, USAGE),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| panic!("{}", USAGE));
+    let output = output.unwrap_or_else(|| {
+        Path::new(&input)
+            .with_extension("smb")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let mut program = load(&input, dialect);
+    if optimized {
+        program = optimize::optimize(&program);
+    }
+    if strip {
+        program.debug = None;
+    }
+    fs::write(output, program.to_bytes())
+}
+
+// Reports stack underflows and unbalanced branches without running anything,
+// along with the inferred signature of each function.
+fn check(args: &[String], dialect: Dialect) -> Result<(), std::io::Error> {
+    let mut failed = false;
+    for arg in args {
+        let analysis = check::check(&load(arg, dialect));
+        for d in &analysis.diagnostics {
+            println!("{}", d);
+        }
+        for (name, sig) in &analysis.signatures {
+            match sig {
+                Some(sig) => println!("{}: {}", name, sig),
+                None => println!("{}: ( ? )", name),
             }
+        }
+        failed |= !analysis.is_ok();
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn disasm(args: &[String], dialect: Dialect) -> Result<(), std::io::Error> {
+    for arg in args {
+        print!("{}", disasm::disassemble(&load(arg, dialect)));
+    }
+    Ok(())
+}
+
+// Translates a program with one of the backends, printing the result to
+// stdout unless `-o` names a file
+fn emit(
+    args: &[String],
+    dialect: Dialect,
+    backend: fn(&Program) -> Result<String, EmitError>,
+) -> Result<(), std::io::Error> {
+    let mut input = None;
+    let mut output = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = iter.next().cloned(),
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => panic!("Unexpected argument {}.\n{}", arg, USAGE),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| panic!("{}", USAGE));
+    let source = match backend(&load(&input, dialect)) {
+        Ok(source) => source,
+        Err(e) => panic!("Could not translate {}: {}", input, e),
+    };
+    match output {
+        Some(output) => fs::write(output, source),
+        None => {
+            print!("{}", source);
+            Ok(())
+        }
+    }
+}
+
+// Rewrites each file in canonical form. With `--check` nothing is written;
+// files that are not formatted are listed and the exit status is non-zero.
+fn fmt(args: &[String]) -> Result<(), std::io::Error> {
+    let check = args.iter().any(|a| a == "--check");
+    let mut unformatted = false;
+    for arg in args.iter().filter(|a| *a != "--check") {
+        let source = fs::read_to_string(arg)?;
+        let formatted = formatter::format_source(&source);
+        if source == formatted {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", arg);
+            unformatted = true;
         } else {
-            panic!("Could not find file {}.", path.to_str().unwrap());
+            fs::write(arg, formatted)?;
         }
     }
+    if unformatted {
+        process::exit(1);
+    }
     Ok(())
 }
+
+// Prefer panics at this level. If we encounter an error at this level, we
+// want to self destruct. Lower than this, prefer to return results and
+// options so problems can be handled.
+fn main() -> Result<(), std::io::Error> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // `--dialect NAME` may appear anywhere on the command line before `--`
+    let mut dialect = Dialect::Sm;
+    if let Some(i) = args
+        .iter()
+        .take_while(|a| *a != "--")
+        .position(|a| a == "--dialect")
+    {
+        let name = args
+            .get(i + 1)
+            .unwrap_or_else(|| panic!("--dialect needs a name.\n{}", USAGE));
+        dialect = name.parse().unwrap_or_else(|e| panic!("{}.\n{}", e, USAGE));
+        args.drain(i..i + 2);
+    }
+
+    if args.is_empty() {
+        panic!("Please pass filename to stackmachine.\n{}", USAGE)
+    }
+
+    match args[0].as_str() {
+        "check" => check(&args[1..], dialect),
+        "compile" => compile(&args[1..], dialect),
+        "disasm" => disasm(&args[1..], dialect),
+        "emit-c" => emit(&args[1..], dialect, emit_c::emit_c),
+        "emit-wat" => emit(&args[1..], dialect, emit_wat::emit_wat),
+        "fmt" => fmt(&args[1..]),
+        "run" => run(&args[1..], dialect),
+        _ => run(&args, dialect),
+    }
+}
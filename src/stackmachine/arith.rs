@@ -0,0 +1,204 @@
+use std::fmt;
+
+use crate::stackmachine::function::Exec;
+use crate::stackmachine::Op;
+
+/*
+ * Integer semantics shared by the interpreter, the fast engine and the
+ * optimizer, so that every build and engine agrees on the result.
+ *
+ * The plain opcodes wrap around on overflow, as two's complement hardware
+ * does; `div` of the most negative value by -1 gives that value back. Their
+ * `.checked` variants trap instead. Division and remainder by zero trap in
+ * both forms. The remainder takes the sign of the dividend. Shift amounts are
+ * taken modulo 32; `shr` shifts in zeros and `sar` copies the sign bit.
+ */
+
+/*
+ * An error that stops the program unless a `try` block catches it. The
+ * handler gets the error as a number: whatever `throw` was given, or one of
+ * the negative codes below for errors raised by the machine itself.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    Overflow(Op),
+    DivideByZero(Op),
+    // An index outside of a string or array, or a negative array length
+    OutOfRange(Op),
+    StackUnderflow(Op),
+    UndefinedFunction(String),
+    // Input `read.int` could not read a number from
+    InvalidInput(Op),
+    // A value used as a handle that is not one to the right kind of object
+    BadHandle(Op),
+    Thrown(i32),
+}
+
+impl Trap {
+    pub fn code(&self) -> i32 {
+        match self {
+            Trap::StackUnderflow(_) => -1,
+            Trap::DivideByZero(_) => -2,
+            Trap::Overflow(_) => -3,
+            Trap::OutOfRange(_) => -4,
+            Trap::UndefinedFunction(_) => -5,
+            Trap::InvalidInput(_) => -6,
+            Trap::BadHandle(_) => -10,
+            Trap::Thrown(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Overflow(op) => write!(f, "integer overflow in `{}`", op),
+            Trap::DivideByZero(op) => write!(f, "division by zero in `{}`", op),
+            Trap::OutOfRange(op) => write!(f, "index out of range in `{}`", op),
+            Trap::StackUnderflow(op) => write!(f, "stack underflow in `{}`", op),
+            Trap::UndefinedFunction(name) => write!(f, "undefined function `{}`", name),
+            Trap::InvalidInput(op) => write!(f, "invalid input in `{}`", op),
+            Trap::BadHandle(op) => write!(f, "invalid handle in `{}`", op),
+            Trap::Thrown(code) => write!(f, "uncaught exception {}", code),
+        }
+    }
+}
+
+fn checked(op: Op, v: Option<i32>) -> Result<i32, Trap> {
+    v.ok_or(Trap::Overflow(op))
+}
+
+fn nonzero(op: Op, b: i32) -> Result<(), Trap> {
+    match b {
+        0 => Err(Trap::DivideByZero(op)),
+        _ => Ok(()),
+    }
+}
+
+// Pop a then b and compute `a op b`, or `None` if `op` is not binary arithmetic
+pub fn binary(op: Op, a: i32, b: i32) -> Option<Result<i32, Trap>> {
+    Some(match op {
+        Op::Add => Ok(a.wrapping_add(b)),
+        Op::Sub => Ok(a.wrapping_sub(b)),
+        Op::Mul => Ok(a.wrapping_mul(b)),
+        Op::Div => nonzero(op, b).map(|_| a.wrapping_div(b)),
+        Op::Mod => nonzero(op, b).map(|_| a.wrapping_rem(b)),
+        Op::Min => Ok(a.min(b)),
+        Op::Max => Ok(a.max(b)),
+        Op::And => Ok(a & b),
+        Op::Or => Ok(a | b),
+        Op::Xor => Ok(a ^ b),
+        Op::Shl => Ok(a.wrapping_shl(b as u32)),
+        Op::Shr => Ok((a as u32).wrapping_shr(b as u32) as i32),
+        Op::Sar => Ok(a.wrapping_shr(b as u32)),
+        Op::AddChecked => checked(op, a.checked_add(b)),
+        Op::SubChecked => checked(op, a.checked_sub(b)),
+        Op::MulChecked => checked(op, a.checked_mul(b)),
+        Op::DivChecked => nonzero(op, b).and_then(|_| checked(op, a.checked_div(b))),
+        Op::ModChecked => nonzero(op, b).and_then(|_| checked(op, a.checked_rem(b))),
+        _ => return None,
+    })
+}
+
+// Pop a and compute `op a`, or `None` if `op` is not unary arithmetic
+pub fn unary(op: Op, a: i32) -> Option<Result<i32, Trap>> {
+    Some(match op {
+        Op::Neg => Ok(a.wrapping_neg()),
+        Op::Abs => Ok(a.wrapping_abs()),
+        Op::NegChecked => checked(op, a.checked_neg()),
+        Op::AbsChecked => checked(op, a.checked_abs()),
+        _ => return None,
+    })
+}
+
+// Pop a then b and compare them, or `None` if `op` is not a comparison
+pub fn compare(op: Op, a: i32, b: i32) -> Option<bool> {
+    Some(match op {
+        Op::r#Eq => a == b,
+        Op::GT => a > b,
+        Op::LT => a < b,
+        Op::GTE => a >= b,
+        Op::LTE => a <= b,
+        _ => return None,
+    })
+}
+
+// Runs an arithmetic, comparison or `not` opcode on the stack, or returns
+// `None` if `op` is not one
+pub fn execute(op: Op, stack: &mut Vec<i32>) -> Option<Result<(), Trap>> {
+    if op.info().exec != Exec::Arith {
+        return None;
+    }
+    let a = stack.pop().unwrap();
+    let v = match op {
+        Op::Not => Ok((a <= 0) as i32),
+        _ => match unary(op, a) {
+            Some(v) => v,
+            None => {
+                let b = stack.pop().unwrap();
+                binary(op, a, b)
+                    .or_else(|| compare(op, a, b).map(|c| Ok(c as i32)))
+                    .unwrap()
+            }
+        },
+    };
+    Some(v.map(|v| stack.push(v)))
+}
+
+#[cfg(test)]
+mod arith_test {
+
+    use super::*;
+
+    #[test]
+    fn test_wrapping() {
+        assert_eq!(Some(Ok(i32::MIN)), binary(Op::Add, i32::MAX, 1));
+        assert_eq!(Some(Ok(i32::MAX)), binary(Op::Sub, i32::MIN, 1));
+        assert_eq!(Some(Ok(-2)), binary(Op::Mul, i32::MAX, 2));
+        assert_eq!(Some(Ok(i32::MIN)), binary(Op::Div, i32::MIN, -1));
+        assert_eq!(Some(Ok(0)), binary(Op::Mod, i32::MIN, -1));
+        assert_eq!(Some(Ok(i32::MIN)), unary(Op::Neg, i32::MIN));
+        assert_eq!(Some(Ok(i32::MIN)), unary(Op::Abs, i32::MIN));
+    }
+
+    #[test]
+    fn test_checked() {
+        let cases = [
+            (Op::AddChecked, i32::MAX, 1),
+            (Op::SubChecked, i32::MIN, 1),
+            (Op::MulChecked, i32::MAX, 2),
+            (Op::DivChecked, i32::MIN, -1),
+            (Op::ModChecked, i32::MIN, -1),
+        ];
+        for (op, a, b) in cases {
+            assert_eq!(Some(Err(Trap::Overflow(op))), binary(op, a, b), "{}", op);
+        }
+        assert_eq!(Some(Ok(7)), binary(Op::AddChecked, 3, 4));
+        assert_eq!(
+            Some(Err(Trap::Overflow(Op::NegChecked))),
+            unary(Op::NegChecked, i32::MIN)
+        );
+        assert_eq!(Some(Ok(5)), unary(Op::AbsChecked, -5));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        for op in [Op::Div, Op::Mod, Op::DivChecked, Op::ModChecked] {
+            assert_eq!(Some(Err(Trap::DivideByZero(op))), binary(op, 1, 0));
+        }
+        assert_eq!(Some(Ok(-1)), binary(Op::Mod, -7, 3));
+    }
+
+    #[test]
+    fn test_bitwise() {
+        assert_eq!(Some(Ok(0b1000)), binary(Op::And, 0b1100, 0b1010));
+        assert_eq!(Some(Ok(0b1110)), binary(Op::Or, 0b1100, 0b1010));
+        assert_eq!(Some(Ok(0b0110)), binary(Op::Xor, 0b1100, 0b1010));
+        assert_eq!(Some(Ok(8)), binary(Op::Shl, 1, 35));
+        assert_eq!(Some(Ok(0x7fff_ffff)), binary(Op::Shr, -1, 1));
+        assert_eq!(Some(Ok(-1)), binary(Op::Sar, -1, 1));
+        assert_eq!(Some(Ok(2)), binary(Op::Min, 2, 3));
+        assert_eq!(Some(Ok(3)), binary(Op::Max, 2, 3));
+        assert_eq!(None, binary(Op::r#Eq, 1, 1));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::stackmachine::function::for_each_op;
+use crate::stackmachine::wide;
 use crate::stackmachine::Op;
 use crate::stackmachine::StackMachine;
 
@@ -8,117 +10,87 @@ pub struct Builder {
 
 impl Builder {
     pub fn new(memsize: u32) -> Builder {
-        return Builder {
+        Builder {
             sm: StackMachine::new(memsize),
             code: Vec::new(),
-        };
+        }
     }
 
-    fn push(&mut self, line: (Op, Option<i32>)) {
+    fn emit(&mut self, line: (Op, Option<i32>)) {
         self.code.push(line);
     }
 
-    pub fn print(&mut self) -> &mut Builder {
-        self.push((Op::Print, None));
-        return self;
+    // Same expansion as `pushstr` in source: last character first
+    pub fn pushstr(&mut self, s: &str) -> &mut Builder {
+        for c in s.chars().rev() {
+            self.emit((Op::Const, Some(c as i32)));
+        }
+        self
     }
 
-    pub fn r#const(&mut self, arg: i32) -> &mut Builder {
-        self.push((Op::Const, Some(arg)));
-        return self;
+    // Same expansion as `i64.const` and `f.const` in source: two cells
+    pub fn i64_const(&mut self, v: i64) -> &mut Builder {
+        for c in wide::i64_cells(v) {
+            self.emit((Op::Const, Some(c)));
+        }
+        self
     }
 
-    pub fn add(&mut self) -> &mut Builder {
-        self.push((Op::Add, None));
-        return self;
+    pub fn f_const(&mut self, v: f64) -> &mut Builder {
+        for c in wide::f64_cells(v) {
+            self.emit((Op::Const, Some(c)));
+        }
+        self
     }
 
-    pub fn sub(&mut self) -> &mut Builder {
-        self.push((Op::Sub, None));
-        return self;
+    // Same expansion as `str.const` in source: the characters and `str.new`
+    pub fn str_const(&mut self, s: &str) -> &mut Builder {
+        self.r#const(0).pushstr(s).str_new()
     }
 
-    pub fn mul(&mut self) -> &mut Builder {
-        self.push((Op::Mul, None));
-        return self;
-    }
-
-    pub fn div(&mut self) -> &mut Builder {
-        self.push((Op::Div, None));
-        return self;
-    }
-
-    pub fn call(&mut self) -> &mut Builder {
-        self.push((Op::Call, None));
-        return self;
-    }
-
-    pub fn call_ext(&mut self) -> &mut Builder {
-        self.push((Op::CallExt, None));
-        return self;
-    }
-
-    pub fn fork(&mut self) -> &mut Builder {
-        self.push((Op::Fork, None));
-        return self;
-    }
-
-    pub fn child(&mut self) -> &mut Builder {
-        self.push((Op::Child, None));
-        return self;
-    }
-
-    pub fn r#if(&mut self) -> &mut Builder {
-        self.push((Op::If, None));
-        return self;
-    }
-
-    pub fn eq(&mut self) -> &mut Builder {
-        self.push((Op::r#Eq, None));
-        return self;
-    }
-
-    pub fn not(&mut self) -> &mut Builder {
-        self.push((Op::Not, None));
-        return self;
-    }
-
-    pub fn gt(&mut self) -> &mut Builder {
-        self.push((Op::GT, None));
-        return self;
-    }
-
-    pub fn lt(&mut self) -> &mut Builder {
-        self.push((Op::LT, None));
-        return self;
-    }
-
-    pub fn gte(&mut self) -> &mut Builder {
-        self.push((Op::GTE, None));
-        return self;
-    }
-
-    pub fn lte(&mut self) -> &mut Builder {
-        self.push((Op::LTE, None));
-        return self;
-    }
-
-    pub fn end_if(&mut self) -> &mut Builder {
-        self.push((Op::EndIf, None));
-        return self;
-    }
-
-    pub fn get_pid(&mut self) -> &mut Builder {
-        self.push((Op::GetPid, None));
-        return self;
+    // Same expansion as `fnref` in source: the name, then `fnref` to pop it
+    pub fn fn_ref(&mut self, name: &str) -> &mut Builder {
+        self.r#const(0).pushstr(name);
+        self.emit((Op::FnRef, None));
+        self
     }
 
     pub fn execute(&mut self) -> &StackMachine {
         self.sm.execute(self.code.clone());
-        return &mut self.sm;
+        &self.sm
     }
 }
 
+// One chaining method per opcode, named in the opcode table
+macro_rules! builder_method {
+    ($op:ident, Int, $method:ident, $desc:literal) => {
+        #[doc = $desc]
+        pub fn $method(&mut self, arg: i32) -> &mut Builder {
+            self.emit((Op::$op, Some(arg)));
+            self
+        }
+    };
+    ($op:ident, None, $method:ident, $desc:literal) => {
+        #[doc = $desc]
+        pub fn $method(&mut self) -> &mut Builder {
+            self.emit((Op::$op, None));
+            self
+        }
+    };
+    // Text operands are source-only; see `Builder::pushstr` and `Builder::f_const`
+    ($op:ident, Text, $method:ident, $desc:literal) => {};
+}
+
+macro_rules! builder_methods {
+    ($($op:ident, $mnemonic:literal, $operand:ident, $effect:tt, $exec:ident, $method:ident, $desc:literal;)*) => {
+        impl Builder {
+            $(builder_method!($op, $operand, $method, $desc);)*
+        }
+    };
+}
+
+for_each_op!(builder_methods);
+
 #[cfg(test)]
 mod builder_test {
 
@@ -177,6 +149,81 @@ mod builder_test {
         assert_eq!(Some(0), builder.sm.last());
     }
 
+    #[test]
+    fn test_builder_stack_words() {
+        let mut builder = Builder::new(2u32.pow(16));
+
+        builder
+            .r#const(1)
+            .r#const(2)
+            .r#const(3)
+            .pick(2)
+            .roll(3)
+            .tuck()
+            .nip()
+            .depth()
+            .execute();
+
+        assert_eq!(vec![2, 3, 1, 1, 4], builder.sm.stack);
+
+        builder.clear().execute();
+
+        assert_eq!(Vec::<i32>::new(), builder.sm.stack);
+    }
+
+    #[test]
+    fn test_builder_wide() {
+        let mut builder = Builder::new(2u32.pow(16));
+
+        builder
+            .f_const(0.5)
+            .r#const(3)
+            .i2f()
+            .f_mul()
+            .f2i()
+            .i64_const(1 << 33)
+            .i64_const(2)
+            .i64_mul()
+            .execute();
+
+        assert_eq!(1, builder.sm.stack[0]);
+        assert_eq!(1 << 34, super::wide::pop_i64(&mut builder.sm.stack));
+    }
+
+    #[test]
+    fn test_builder_strings() {
+        let mut builder = Builder::new(2u32.pow(16));
+
+        builder
+            .r#const(7)
+            .int_to_str()
+            .str_const("n = ")
+            .str_concat()
+            .execute();
+
+        let handle = builder.sm.last().unwrap();
+        assert_eq!(Some("n = 7"), builder.sm.heap.string(handle));
+    }
+
+    #[test]
+    fn test_builder_fn_ref() {
+        let mut builder = Builder::new(2u32.pow(16));
+
+        builder
+            .r#const(0)
+            .pushstr("square")
+            .function()
+            .dup()
+            .mul()
+            .end_function()
+            .r#const(7)
+            .fn_ref("square")
+            .call_indirect()
+            .execute();
+
+        assert_eq!(vec![49], builder.sm.stack);
+    }
+
     #[test]
     fn test_builder_fork() {
         let mut builder = Builder::new(2u32.pow(16));
@@ -0,0 +1,883 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::stackmachine::function::Effect;
+use crate::stackmachine::program::Program;
+use crate::stackmachine::Op;
+
+/*
+ * Static stack-effect analysis. Walks a program the way the VM would, but
+ * over an abstract stack whose entries are either a known constant or
+ * unknown. Known constants are what let the checker read the null-terminated
+ * names passed to `call` and `function`.
+ *
+ * Anything whose effect cannot be known ahead of time (external functions
+ * with no declared signature, indirect calls, strings built from computed
+ * values, recursion) makes the rest of that block opaque: depths stop being
+ * tracked there and no further diagnostics are reported for it.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub index: usize,
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(loc) => write!(f, "{}: {}", loc, self.message),
+            None => write!(f, "instruction {}: {}", self.index, self.message),
+        }
+    }
+}
+
+// Number of values a function consumes and leaves behind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "( {} -- {} )", self.inputs, self.outputs)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    // Inferred signature of every function the program defines, `None` when
+    // the body's effect depends on something only known at run time
+    pub signatures: BTreeMap<String, Option<Signature>>,
+    // Stack depth before each top-level instruction, where it is known
+    pub depths: Vec<Option<usize>>,
+    // Whether every part of the program had a statically known effect
+    pub complete: bool,
+}
+
+impl Analysis {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    // No diagnostics and nothing opaque: the program provably never
+    // underflows the stack
+    pub fn is_verified(&self) -> bool {
+        self.is_ok() && self.complete && self.signatures.values().all(Option::is_some)
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    stack: Vec<Option<i32>>,
+    // Values taken from below the block's starting stack; these become a
+    // function's inputs
+    borrowed: usize,
+    opaque: bool,
+    // Set after `break` or `return`: the rest of the block is unreachable
+    terminated: bool,
+    in_function: bool,
+}
+
+impl State {
+    fn new(in_function: bool) -> State {
+        State {
+            stack: Vec::new(),
+            borrowed: 0,
+            opaque: false,
+            terminated: false,
+            in_function,
+        }
+    }
+
+    fn depth(&self) -> Option<usize> {
+        if self.opaque || self.borrowed > 0 {
+            None
+        } else {
+            Some(self.stack.len())
+        }
+    }
+
+    // Net values left relative to where the block started
+    fn height(&self) -> isize {
+        self.stack.len() as isize - self.borrowed as isize
+    }
+
+    // Combine the states at the end of two branches, forgetting any
+    // constants the branches disagree on
+    fn merge(&mut self, other: &State) {
+        if self.terminated {
+            *self = other.clone();
+            return;
+        }
+        if other.terminated {
+            return;
+        }
+        self.opaque |= other.opaque;
+        self.borrowed = self.borrowed.max(other.borrowed);
+        for (a, b) in self.stack.iter_mut().zip(other.stack.iter()) {
+            if a != b {
+                *a = None;
+            }
+        }
+    }
+}
+
+struct Checker<'a> {
+    program: &'a Program,
+    analysis: Analysis,
+    // States at each `break` of the enclosing blocks, innermost last
+    breaks: Vec<Vec<(usize, State)>>,
+    // States at each `return` of the function being analysed
+    returns: Vec<(usize, State)>,
+    // Declared signatures of the host functions `callext` can call
+    externals: &'a BTreeMap<String, Signature>,
+    // Calls from function bodies to functions not defined yet; the body may
+    // only run once they are, so these are checked at the end
+    pending: Vec<(usize, String)>,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, index: usize, message: String) {
+        let location = self.program.debug.as_ref().and_then(|d| d.location(index));
+        self.analysis.diagnostics.push(Diagnostic {
+            index,
+            location,
+            message,
+        });
+    }
+
+    fn resolve_pending(&mut self) {
+        for (index, name) in std::mem::take(&mut self.pending) {
+            if !self.analysis.signatures.contains_key(&name) {
+                self.error(index, format!("call to undefined function `{}`", name));
+            }
+        }
+    }
+
+    fn take(&mut self, state: &mut State, index: usize, n: usize) -> Vec<Option<i32>> {
+        let have = state.stack.len();
+        if have < n {
+            if !state.in_function && !state.opaque {
+                let op = self.program.code[index].0;
+                self.error(
+                    index,
+                    format!(
+                        "stack underflow: `{}` needs {} value(s) but the stack has {}",
+                        op, n, have
+                    ),
+                );
+            }
+            state.borrowed += n - have;
+            let mut values = vec![None; n - have];
+            values.append(&mut state.stack);
+            return values;
+        }
+        state.stack.split_off(have - n)
+    }
+
+    // Pops a null-terminated string made of known characters
+    fn string(&mut self, state: &mut State, index: usize) -> Option<String> {
+        let mut s = String::new();
+        loop {
+            if state.opaque {
+                return None;
+            }
+            match state.stack.pop() {
+                Some(Some(0)) => return Some(s),
+                Some(Some(c)) => s.push(c as u8 as char),
+                Some(None) => {
+                    state.opaque = true;
+                    return None;
+                }
+                None => {
+                    if !state.in_function {
+                        self.error(
+                            index,
+                            format!(
+                                "string {:?} is not null-terminated; `{}` would wait forever",
+                                s, self.program.code[index].0
+                            ),
+                        );
+                    }
+                    state.opaque = true;
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Index of the `else` or `catch` (if any) and end matching the block at
+    // `start`
+    fn bounds(&self, start: usize, end: usize, close: Op) -> Option<(Option<usize>, usize)> {
+        let open = self.program.code[start].0;
+        let mut nest = 0;
+        let mut else_idx = None;
+        for i in start + 1..end {
+            match self.program.code[i].0 {
+                op if op == open => nest += 1,
+                Op::Else if nest == 0 && open == Op::If => else_idx = Some(i),
+                Op::Catch if nest == 0 && open == Op::Try => else_idx = Some(i),
+                op if op == close && nest == 0 => return Some((else_idx, i)),
+                op if op == close => nest -= 1,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /*
+     * Combines the states that leave a block by different paths. They must
+     * all agree on the stack height; `None` means no path leaves at all.
+     */
+    fn join(&mut self, index: usize, what: &str, exits: &[(usize, State)]) -> Option<State> {
+        let known: Vec<&(usize, State)> = exits.iter().filter(|(_, s)| !s.opaque).collect();
+        if let Some((_, first)) = known.first() {
+            if let Some((at, other)) = known.iter().find(|(_, s)| s.height() != first.height()) {
+                self.error(
+                    index,
+                    format!(
+                        "{} leaves the stack at different heights \
+                         ({:+} and {:+} at instruction {})",
+                        what,
+                        first.height(),
+                        other.height(),
+                        at
+                    ),
+                );
+                let mut state = first.clone();
+                state.opaque = true;
+                return Some(state);
+            }
+        }
+
+        let mut iter = exits.iter().map(|(_, s)| s);
+        let mut state = iter.next()?.clone();
+        for other in iter {
+            state.merge(other);
+        }
+        state.terminated = false;
+        Some(state)
+    }
+
+    // Analyses a function body and infers its signature from every way out
+    fn function(&mut self, start: usize, end: usize) -> Option<Signature> {
+        let saved = std::mem::take(&mut self.returns);
+        let mut body = State::new(true);
+        self.block(start, end, &mut body);
+
+        let mut exits = std::mem::replace(&mut self.returns, saved);
+        if !body.terminated {
+            exits.push((end, body));
+        }
+        let state = self.join(start.saturating_sub(1), "function", &exits)?;
+        if state.opaque {
+            return None;
+        }
+        let inputs = exits.iter().map(|(_, s)| s.borrowed).max().unwrap_or(0);
+        Some(Signature {
+            inputs,
+            outputs: (state.height() + inputs as isize) as usize,
+        })
+    }
+
+    fn block(&mut self, start: usize, end: usize, state: &mut State) {
+        let code = &self.program.code;
+        let mut index = start;
+        while index < end {
+            if state.terminated {
+                return;
+            }
+            if !state.in_function {
+                self.analysis.depths[index] = state.depth();
+            }
+            let (op, arg) = code[index];
+
+            match op {
+                Op::Const | Op::Push => {
+                    if arg.is_none() {
+                        self.error(index, format!("`{}` is missing its operand", op));
+                    }
+                    state.stack.push(arg);
+                }
+                Op::If => {
+                    self.take(state, index, 1);
+                    let (else_idx, endif) = match self.bounds(index, end, Op::EndIf) {
+                        Some(bounds) => bounds,
+                        None => {
+                            self.error(index, "`if` has no matching `endif`".to_string());
+                            return;
+                        }
+                    };
+                    let base = state.height();
+                    let mut other = state.clone();
+                    self.block(index + 1, else_idx.unwrap_or(endif), state);
+                    if let Some(e) = else_idx {
+                        self.block(e + 1, endif, &mut other);
+                    }
+                    let reachable = !state.terminated && !other.terminated;
+                    let known = !state.opaque && !other.opaque;
+                    if reachable && known && state.height() != other.height() {
+                        let message = match else_idx {
+                            Some(_) => format!(
+                                "branches leave different stack heights: `if` {:+}, `else` {:+}",
+                                state.height() - base,
+                                other.height() - base
+                            ),
+                            None => format!(
+                                "`if` without `else` changes the stack height by {:+}",
+                                state.height() - other.height()
+                            ),
+                        };
+                        self.error(index, message);
+                        state.opaque = true;
+                    }
+                    state.merge(&other);
+                    index = endif;
+                }
+                // The handler starts from the stack as it was at `try`, plus
+                // the error code
+                Op::Try => {
+                    let (catch, endtry) = match self.bounds(index, end, Op::EndTry) {
+                        Some((Some(catch), endtry)) => (catch, endtry),
+                        _ => {
+                            self.error(
+                                index,
+                                "`try` has no matching `catch` and `endtry`".to_string(),
+                            );
+                            return;
+                        }
+                    };
+                    let base = state.height();
+                    let mut handler = state.clone();
+                    handler.stack.push(None);
+                    self.block(index + 1, catch, state);
+                    self.block(catch + 1, endtry, &mut handler);
+                    let reachable = !state.terminated && !handler.terminated;
+                    let known = !state.opaque && !handler.opaque;
+                    if reachable && known && state.height() != handler.height() {
+                        self.error(
+                            index,
+                            format!(
+                                "branches leave different stack heights: `try` {:+}, `catch` {:+}",
+                                state.height() - base,
+                                handler.height() - base
+                            ),
+                        );
+                        state.opaque = true;
+                    }
+                    state.merge(&handler);
+                    index = endtry;
+                }
+                Op::Throw | Op::Exit => {
+                    self.take(state, index, 1);
+                    state.terminated = true;
+                }
+                Op::Block | Op::Loop => {
+                    let close = if op == Op::Loop {
+                        Op::EndLoop
+                    } else {
+                        Op::EndBlock
+                    };
+                    let block_end = match self.bounds(index, end, close) {
+                        Some((_, e)) => e,
+                        None => {
+                            self.error(index, format!("`{}` has no matching `{}`", op, close));
+                            return;
+                        }
+                    };
+
+                    let base = state.height();
+                    self.breaks.push(Vec::new());
+                    self.block(index + 1, block_end, state);
+                    let mut exits = self.breaks.pop().unwrap();
+
+                    if op == Op::Loop {
+                        // Every iteration has to start from the same height. A
+                        // body whose height is unknown may not, so whatever
+                        // leaves the loop is unknown too.
+                        if !state.terminated && (state.opaque || state.height() != base) {
+                            if !state.opaque {
+                                self.error(
+                                    index,
+                                    format!(
+                                        "loop body changes the stack height by {:+} each iteration",
+                                        state.height() - base
+                                    ),
+                                );
+                            }
+                            state.opaque = true;
+                            exits.push((block_end, state.clone()));
+                        }
+                    } else if !state.terminated {
+                        exits.push((block_end, state.clone()));
+                    }
+
+                    match self.join(index, &format!("`{}`", op), &exits) {
+                        Some(after) => *state = after,
+                        // Nothing leaves the block, so nothing after it runs
+                        None => state.terminated = true,
+                    }
+                    index = block_end;
+                }
+                Op::Break => match self.breaks.last_mut() {
+                    Some(breaks) => {
+                        breaks.push((index, state.clone()));
+                        state.terminated = true;
+                    }
+                    None => {
+                        self.error(index, "`break` outside of a `block` or `loop`".to_string());
+                        state.terminated = true;
+                    }
+                },
+                Op::Return => {
+                    if state.in_function {
+                        self.returns.push((index, state.clone()));
+                    }
+                    state.terminated = true;
+                }
+                Op::Else | Op::EndIf | Op::EndBlock | Op::EndLoop | Op::Catch | Op::EndTry => {
+                    self.error(index, format!("`{}` without a matching start of block", op));
+                }
+                Op::Function => {
+                    let name = self.string(state, index);
+                    let body_end = (index + 1..code.len())
+                        .find(|i| code[*i].0 == Op::EndFunction)
+                        .unwrap_or(code.len());
+
+                    // The body may call the function itself, whose signature
+                    // is not known until the body has been analysed
+                    if let Some(name) = &name {
+                        self.analysis.signatures.insert(name.clone(), None);
+                    }
+                    // Loops around a definition do not extend into its body
+                    let outer = std::mem::take(&mut self.breaks);
+                    let signature = self.function(index + 1, body_end);
+                    self.breaks = outer;
+
+                    if let Some(name) = name {
+                        self.analysis.signatures.insert(name, signature);
+                    }
+                    for i in index + 1..body_end.min(end) {
+                        self.analysis.depths[i] = None;
+                    }
+                    index = body_end;
+                }
+                Op::EndFunction => {
+                    self.error(
+                        index,
+                        "`endfunction` without a matching `function`".to_string(),
+                    );
+                }
+                Op::Call => match self.string(state, index) {
+                    Some(name) => match self.analysis.signatures.get(&name).copied() {
+                        Some(Some(sig)) => {
+                            self.take(state, index, sig.inputs);
+                            state.stack.extend(vec![None; sig.outputs]);
+                        }
+                        Some(None) => state.opaque = true,
+                        None if state.in_function => {
+                            self.pending.push((index, name));
+                            state.opaque = true;
+                        }
+                        None => {
+                            self.error(index, format!("call to undefined function `{}`", name));
+                            state.opaque = true;
+                        }
+                    },
+                    None => state.opaque = true,
+                },
+                Op::FnRef => {
+                    if let Some(name) = self.string(state, index) {
+                        if !self.analysis.signatures.contains_key(&name) {
+                            self.error(
+                                index,
+                                format!("reference to undefined function `{}`", name),
+                            );
+                        }
+                    }
+                    state.stack.push(None);
+                }
+                Op::Closure => match arg {
+                    Some(n) if n >= 0 => {
+                        self.take(state, index, n as usize + 1);
+                        state.stack.push(None);
+                    }
+                    _ => {
+                        self.error(index, format!("`{}` needs a non-negative operand", op));
+                        state.opaque = true;
+                    }
+                },
+                // Which function runs is only known at run time
+                Op::CallIndirect | Op::CallClosure => {
+                    self.take(state, index, 1);
+                    state.opaque = true;
+                }
+                Op::CallExt => match self.string(state, index) {
+                    Some(name) => match self.externals.get(&name).copied() {
+                        Some(sig) => {
+                            self.take(state, index, sig.inputs);
+                            state.stack.extend(vec![None; sig.outputs]);
+                        }
+                        None => state.opaque = true,
+                    },
+                    None => state.opaque = true,
+                },
+                Op::PrintChars => {
+                    self.string(state, index);
+                }
+                // How long the line is is only known at run time
+                Op::ReadLine => state.opaque = true,
+                Op::StrNew => {
+                    self.string(state, index);
+                    state.stack.push(None);
+                }
+                Op::Print => {
+                    let v = self.take(state, index, 1);
+                    state.stack.extend(v);
+                }
+                Op::Pick | Op::Roll => match arg {
+                    Some(n) if n >= 0 => {
+                        let mut values = self.take(state, index, n as usize + 1);
+                        let v = match op {
+                            Op::Pick => values[0],
+                            _ => values.remove(0),
+                        };
+                        state.stack.extend(values);
+                        state.stack.push(v);
+                    }
+                    _ => {
+                        self.error(index, format!("`{}` needs a non-negative operand", op));
+                        state.opaque = true;
+                    }
+                },
+                // Inside a function this also clears whatever the caller left
+                Op::Clear if state.in_function => state.opaque = true,
+                Op::Clear => state.stack.clear(),
+                _ => match op.info().effect {
+                    Effect::Fixed(pops, pushes) => {
+                        self.take(state, index, pops);
+                        state.stack.extend(vec![None; pushes]);
+                    }
+                    Effect::Dynamic => {
+                        self.error(
+                            index,
+                            format!("`{}` only exists in source and cannot run", op),
+                        );
+                        state.opaque = true;
+                    }
+                },
+            }
+            index += 1;
+        }
+    }
+}
+
+pub fn check(program: &Program) -> Analysis {
+    check_with_externals(program, &BTreeMap::new())
+}
+
+// Checks a program that can call the host functions in `externals`, usually
+// `StackMachine::host_signatures`; other external functions stay opaque
+pub fn check_with_externals(
+    program: &Program,
+    externals: &BTreeMap<String, Signature>,
+) -> Analysis {
+    let mut checker = Checker {
+        program,
+        analysis: Analysis {
+            depths: vec![None; program.code.len()],
+            ..Analysis::default()
+        },
+        breaks: Vec::new(),
+        returns: Vec::new(),
+        externals,
+        pending: Vec::new(),
+    };
+
+    // Functions loaded ahead of time are analysed on their own, in function
+    // mode, before the top-level code that may call them. They may call each
+    // other, so all of them are known before any is analysed.
+    for name in program.functions.keys() {
+        checker.analysis.signatures.insert(name.clone(), None);
+    }
+    for (name, body) in &program.functions {
+        let sub = Program::new(body.clone());
+        let mut inner = Checker {
+            program: &sub,
+            analysis: Analysis {
+                depths: vec![None; body.len()],
+                signatures: checker.analysis.signatures.clone(),
+                ..Analysis::default()
+            },
+            breaks: Vec::new(),
+            returns: Vec::new(),
+            externals,
+            pending: Vec::new(),
+        };
+        let signature = inner.function(0, body.len());
+        inner.resolve_pending();
+
+        for mut d in inner.analysis.diagnostics {
+            d.message = format!("in function `{}`: {}", name, d.message);
+            checker.analysis.diagnostics.push(d);
+        }
+        checker.analysis.signatures.insert(name.clone(), signature);
+    }
+
+    let mut state = State::new(false);
+    checker.block(0, program.code.len(), &mut state);
+    checker.resolve_pending();
+    checker.analysis.complete = !state.opaque;
+    checker.analysis
+}
+
+#[cfg(test)]
+mod check_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+
+    fn analyse(source: &str) -> Analysis {
+        check(&reader::parse(source, "t.sm"))
+    }
+
+    #[test]
+    fn test_clean_program() {
+        let analysis = analyse("const 1\nconst 2\nadd\nprint\npop");
+
+        assert!(analysis.is_ok());
+        assert_eq!(
+            vec![Some(0), Some(1), Some(2), Some(1), Some(1)],
+            analysis.depths
+        );
+    }
+
+    #[test]
+    fn test_verified() {
+        assert!(analyse("const 1\nconst 2\nadd").is_verified());
+        assert!(!analyse("const 0\npushstr f\ncallext").is_verified());
+    }
+
+    #[test]
+    fn test_external_signatures() {
+        let program = reader::parse(
+            "const 1\nconst 2\nconst 0\npushstr sum\ncallext\npop",
+            "t.sm",
+        );
+        let mut externals = BTreeMap::new();
+        externals.insert(
+            "sum".to_string(),
+            Signature {
+                inputs: 2,
+                outputs: 1,
+            },
+        );
+
+        assert!(check_with_externals(&program, &externals).is_verified());
+
+        externals.get_mut("sum").unwrap().inputs = 3;
+        let analysis = check_with_externals(&program, &externals);
+
+        assert!(analysis.diagnostics[0].message.contains("stack underflow"));
+    }
+
+    #[test]
+    fn test_underflow() {
+        let analysis = analyse("const 1\n\nadd");
+
+        assert_eq!(1, analysis.diagnostics.len());
+        assert_eq!(
+            Some("t.sm:3:1".to_string()),
+            analysis.diagnostics[0].location
+        );
+    }
+
+    #[test]
+    fn test_pick_roll() {
+        let analysis = analyse("const 1\nconst 2\npick 1\nroll 2\nclear\ndepth\npick 1");
+
+        assert_eq!(
+            vec![
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(3),
+                Some(0),
+                Some(1)
+            ],
+            analysis.depths
+        );
+        assert_eq!(1, analysis.diagnostics.len());
+        assert!(analysis.diagnostics[0].message.contains("underflow"));
+    }
+
+    #[test]
+    fn test_branch_mismatch() {
+        let analysis = analyse("const 1\nif\nconst 2\nconst 3\nelse\nconst 4\nendif");
+
+        assert_eq!(1, analysis.diagnostics.len());
+        assert!(analysis.diagnostics[0].message.contains("branches"));
+    }
+
+    #[test]
+    fn test_branches_balanced() {
+        let analysis = analyse("const 1\nif\nconst 2\nelse\nconst 4\nendif\npop");
+
+        assert!(analysis.is_ok());
+    }
+
+    #[test]
+    fn test_function_signature() {
+        let analysis = analyse(
+            "const 0\npushstr f\nfunction\nadd\nconst 1\nendfunction\nconst 1\nconst 2\nconst 0\n\
+                pushstr f\ncall\nadd",
+        );
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        assert_eq!(
+            Some(&Some(Signature {
+                inputs: 2,
+                outputs: 2
+            })),
+            analysis.signatures.get("f")
+        );
+    }
+
+    #[test]
+    fn test_loop() {
+        let analysis =
+            analyse("const 5\nloop\nconst 1\nsub\nconst 1\nif\nbreak\nendif\nendloop\nprint");
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        assert_eq!(Some(1), analysis.depths[9]);
+    }
+
+    #[test]
+    fn test_unbalanced_loop() {
+        let analysis = analyse("loop\nconst 1\nendloop");
+
+        assert!(analysis.diagnostics[0].message.contains("each iteration"));
+    }
+
+    #[test]
+    fn test_loop_with_unknown_height() {
+        let analysis = analyse(
+            "const 7\nconst 1\nloop\nif\nconst 5\ndepth\nprintchars\nconst 0\nelse\nbreak\nendif\n\
+                endloop\npop",
+        );
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        assert!(!analysis.is_verified());
+    }
+
+    #[test]
+    fn test_early_return() {
+        let analysis = analyse(
+            "const 0\npushstr f\nfunction\nif\nconst 1\nreturn\nendif\nconst 2\nendfunction",
+        );
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        assert_eq!(
+            Some(&Some(Signature {
+                inputs: 1,
+                outputs: 1
+            })),
+            analysis.signatures.get("f")
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let analysis = analyse("pushstr hello\nprintchars");
+
+        assert_eq!(1, analysis.diagnostics.len());
+        assert!(analysis.diagnostics[0].message.contains("null-terminated"));
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let analysis = analyse("const 0\npushstr nope\ncall");
+
+        assert!(analysis.diagnostics[0].message.contains("undefined"));
+    }
+
+    #[test]
+    fn test_recursive_functions() {
+        let analysis = analyse(
+            "const 0\npushstr f\nfunction\nconst 0\npushstr f\ncall\nendfunction\nconst 0\n\
+                pushstr f\ncall",
+        );
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        assert!(!analysis.is_verified());
+
+        let analysis = analyse(
+            "const 0\npushstr f\nfunction\nconst 0\npushstr g\ncall\nendfunction\nconst 0\n\
+                pushstr g\nfunction\nconst 0\npushstr f\ncall\nendfunction",
+        );
+
+        assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+
+        let analysis =
+            analyse("const 0\npushstr f\nfunction\nconst 0\npushstr g\ncall\nendfunction");
+
+        assert!(analysis.diagnostics[0]
+            .message
+            .contains("undefined function `g`"));
+    }
+
+    #[test]
+    fn test_function_references() {
+        let analysis = analyse("fnref nope");
+
+        assert!(analysis.diagnostics[0]
+            .message
+            .contains("undefined function `nope`"));
+
+        let analysis = analyse("const 0\npushstr f\nfunction\nendfunction\nfnref f\ncall.indirect");
+
+        assert!(analysis.is_ok());
+        assert!(!analysis.complete);
+
+        let analysis = analyse(
+            "const 0\npushstr f\nfunction\nenv.get 0\nendfunction\nconst 1\nfnref f\nclosure 1\n\
+                closure 1",
+        );
+
+        assert_eq!(Some(1), analysis.depths[10]);
+        assert!(analysis.diagnostics[0].message.contains("needs 2 value(s)"));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let analysis = analyse("try\nconst 1\nconst 2\ndiv\ncatch\nendtry\npop");
+
+        assert!(analysis.is_verified());
+
+        let analysis = analyse("try\nconst 1\ncatch\nconst 2\nendtry");
+
+        assert!(analysis.diagnostics[0]
+            .message
+            .contains("`try` +1, `catch` +2"));
+
+        let analysis = analyse("try\nthrow 1\ncatch\nendtry\npop");
+
+        assert!(analysis.is_verified());
+    }
+
+    #[test]
+    fn test_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let program = reader::read_program(path.to_str().unwrap()).unwrap();
+            let analysis = check(&program);
+
+            assert!(analysis.is_ok(), "{:?}", analysis.diagnostics);
+        }
+    }
+}
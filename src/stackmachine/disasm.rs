@@ -0,0 +1,315 @@
+use std::fmt::Write;
+
+use crate::stackmachine::program::{DebugInfo, Program};
+use crate::stackmachine::Op;
+
+/*
+ * Finds runs of `Const` instructions that look like they were produced by
+ * `pushstr`, returning (start, length, text) for each. A run is only treated
+ * as a string if it is printable, would parse back to the same instructions,
+ * and sits somewhere a string is expected: right after a null terminator or
+ * right before an opcode that consumes a string.
+ */
+pub fn string_runs(code: &[(Op, Option<i32>)]) -> Vec<(usize, usize, String)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        let len = code[i..]
+            .iter()
+            .take_while(|(op, arg)| *op == Op::Const && matches!(arg, Some(32..=126)))
+            .count();
+        if len < 2 {
+            i += 1;
+            continue;
+        }
+
+        // `pushstr` pushes characters last-to-first
+        let text: String = code[i..i + len]
+            .iter()
+            .rev()
+            .map(|(_, arg)| arg.unwrap() as u8 as char)
+            .collect();
+
+        let after_terminator = i > 0 && code[i - 1] == (Op::Const, Some(0));
+        let before_consumer = matches!(
+            code.get(i + len),
+            Some((Op::Call, None))
+                | Some((Op::CallExt, None))
+                | Some((Op::PrintChars, None))
+                | Some((Op::StrNew, None))
+                | Some((Op::FnRef, None))
+                | Some((Op::Function, None))
+        );
+        let first_word = text.split(' ').next().unwrap_or("");
+        let reparses = !text.starts_with(' ')
+            && !text.ends_with(' ')
+            && !text.contains("  ")
+            && first_word.parse::<i32>().is_err();
+
+        if reparses && (after_terminator || before_consumer) {
+            runs.push((i, len, text));
+        }
+        i += len;
+    }
+    runs
+}
+
+// Source text for a single instruction
+pub fn format_instruction(op: Op, arg: Option<i32>) -> String {
+    match arg {
+        Some(v) => format!("{} {}", op, v),
+        None => op.to_string(),
+    }
+}
+
+// Opcodes that start an indented block
+pub fn opens_block(op: Op) -> bool {
+    matches!(
+        op,
+        Op::If | Op::Else | Op::Function | Op::Block | Op::Loop | Op::Try | Op::Catch
+    )
+}
+
+// Opcodes that end an indented block
+pub fn closes_block(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Else
+            | Op::EndIf
+            | Op::EndFunction
+            | Op::EndBlock
+            | Op::EndLoop
+            | Op::Catch
+            | Op::EndTry
+    )
+}
+
+/*
+ * Finds the instruction each control flow opcode transfers control to: the
+ * instruction after the matching `else` or `endif` for an `if` whose
+ * condition fails, after the `endif` for an `else`, after the `endfunction`
+ * for a `function`, after the end of the block for a `block`, `loop` or
+ * `break`, and back to the top of the loop for an `endloop`.
+ */
+pub fn jump_targets(code: &[(Op, Option<i32>)]) -> Vec<Option<usize>> {
+    let mut targets = vec![None; code.len()];
+    let mut open = Vec::<usize>::new();
+    let mut loops = Vec::<(usize, Vec<usize>)>::new();
+    for (i, (op, _)) in code.iter().enumerate() {
+        match op {
+            Op::If | Op::Function => open.push(i),
+            Op::Block | Op::Loop => loops.push((i, Vec::new())),
+            Op::Break => {
+                if let Some((_, breaks)) = loops.last_mut() {
+                    breaks.push(i);
+                }
+            }
+            Op::EndBlock | Op::EndLoop => {
+                if let Some((start, breaks)) = loops.pop() {
+                    targets[start] = Some(i + 1);
+                    for b in breaks {
+                        targets[b] = Some(i + 1);
+                    }
+                    if *op == Op::EndLoop {
+                        targets[i] = Some(start + 1);
+                    }
+                }
+            }
+            Op::Else => {
+                if let Some(&start) = open.last() {
+                    targets[start] = Some(i + 1);
+                }
+                open.push(i);
+            }
+            Op::EndIf => {
+                while let Some(start) = open.pop() {
+                    if targets[start].is_none() {
+                        targets[start] = Some(i + 1);
+                    }
+                    if code[start].0 != Op::Else {
+                        break;
+                    }
+                }
+            }
+            Op::EndFunction => {
+                if let Some(start) = open.pop() {
+                    targets[start] = Some(i + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+    targets
+}
+
+fn disassemble_stream(
+    out: &mut String,
+    code: &[(Op, Option<i32>)],
+    debug: Option<&DebugInfo>,
+) -> std::fmt::Result {
+    let runs = string_runs(code);
+    let targets = jump_targets(code);
+    let mut depth = 0usize;
+    let mut i = 0;
+
+    // The name of the most recent string literal, used to label functions
+    let mut last_string = None;
+
+    while i < code.len() {
+        let (op, arg) = code[i];
+        let mut text;
+        let mut notes = Vec::<String>::new();
+        let mut width = 1;
+
+        if let Some((_, len, s)) = runs.iter().find(|(start, _, _)| *start == i) {
+            text = format!("pushstr {}", s);
+            notes.push(format!("{} x const", len));
+            width = *len;
+            last_string = Some(s.clone());
+        } else {
+            if closes_block(op) {
+                depth = depth.saturating_sub(1);
+            }
+            text = format_instruction(op, arg);
+            match (op, targets[i]) {
+                (Op::If, Some(t)) => notes.push(format!("false -> {:04}", t)),
+                (Op::Else, Some(t)) | (Op::Break, Some(t)) | (Op::EndLoop, Some(t)) => {
+                    notes.push(format!("-> {:04}", t))
+                }
+                (Op::Block, Some(t)) | (Op::Loop, Some(t)) => {
+                    notes.push(format!("exit -> {:04}", t))
+                }
+                (Op::Function, Some(t)) => {
+                    if let Some(name) = last_string.take() {
+                        text = format!("{} {}", text, name);
+                    }
+                    notes.push(format!("-> {:04}", t));
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(loc) = debug.and_then(|d| d.location(i)) {
+            notes.push(loc);
+        }
+
+        let line = format!("{:04}  {}{}", i, "  ".repeat(depth), text);
+        if notes.is_empty() {
+            writeln!(out, "{}", line)?;
+        } else {
+            writeln!(out, "{:<40} ; {}", line, notes.join(", "))?;
+        }
+
+        if opens_block(op) && width == 1 {
+            depth += 1;
+        }
+        i += width;
+    }
+    Ok(())
+}
+
+/*
+ * Writes code back out as source the reader accepts, one instruction per
+ * line, indented by block depth and with string literals re-collapsed into
+ * `pushstr`.
+ */
+pub fn write_source<W: Write>(out: &mut W, code: &[(Op, Option<i32>)]) -> std::fmt::Result {
+    let runs = string_runs(code);
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < code.len() {
+        if let Some((_, len, s)) = runs.iter().find(|(start, _, _)| *start == i) {
+            writeln!(out, "{}pushstr {}", "  ".repeat(depth), s)?;
+            i += len;
+            continue;
+        }
+
+        let (op, arg) = code[i];
+        if closes_block(op) {
+            depth = depth.saturating_sub(1);
+        }
+        writeln!(out, "{}{}", "  ".repeat(depth), format_instruction(op, arg))?;
+        if opens_block(op) {
+            depth += 1;
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// Human readable listing of a program with offsets and control flow targets
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    for (name, body) in &program.functions {
+        writeln!(out, "function {}:", name).unwrap();
+        disassemble_stream(&mut out, body, None).unwrap();
+        writeln!(out).unwrap();
+    }
+    if !program.functions.is_empty() {
+        writeln!(out, "main:").unwrap();
+    }
+    disassemble_stream(&mut out, &program.code, program.debug.as_ref()).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod disasm_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+
+    #[test]
+    fn test_string_runs() {
+        let program = reader::parse("const 0\npushstr hello world\nprintchars", "t.sm");
+        let runs = string_runs(&program.code);
+
+        assert_eq!(vec![(1, 11, "hello world".to_string())], runs);
+    }
+
+    #[test]
+    fn test_numbers_not_collapsed() {
+        let program = reader::parse("const 100\nconst 101\nadd", "t.sm");
+
+        assert!(string_runs(&program.code).is_empty());
+    }
+
+    #[test]
+    fn test_jump_targets() {
+        let program = reader::parse("const 1\nif\nconst 2\nelse\nconst 3\nendif", "t.sm");
+        let targets = jump_targets(&program.code);
+
+        assert_eq!(Some(4), targets[1]);
+        assert_eq!(Some(6), targets[3]);
+
+        let program = reader::parse("loop\nconst 1\nif\nbreak\nendif\nendloop", "t.sm");
+        let targets = jump_targets(&program.code);
+
+        assert_eq!(Some(6), targets[0]);
+        assert_eq!(Some(6), targets[3]);
+        assert_eq!(Some(1), targets[5]);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = reader::read_program("examples/fn_call.sm").unwrap();
+        let listing = disassemble(&program);
+
+        assert!(listing.contains("0007  function callme"));
+        assert!(listing.contains("0008    add"));
+        assert!(listing.contains("pushstr Expecting value of `5`"));
+    }
+
+    #[test]
+    fn test_display_reparses() {
+        for example in &[
+            "examples/cond.sm",
+            "examples/fn_call.sm",
+            "examples/print_string.sm",
+        ] {
+            let program = reader::read_program(example).unwrap();
+            let source = program.to_string();
+
+            assert_eq!(program.code, reader::parse(&source, example).code);
+        }
+    }
+}
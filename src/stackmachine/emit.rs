@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::stackmachine::program::Program;
+use crate::stackmachine::Op;
+
+/*
+ * The groundwork shared by the backends that translate programs into other
+ * languages. Those languages need every function known up front, so
+ * definitions are hoisted out of the code that runs them and the string
+ * literals naming functions are resolved at compile time. Programs that build
+ * names at run time cannot be translated.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    // Index of the instruction in its stream, for error messages
+    Op(usize, Op, Option<i32>),
+    Call(usize, String),
+    CallExt(usize, String),
+    // `fnref` of a literal name, which pushes `Unit::handle` of the function
+    FnRef(usize, String),
+}
+
+#[derive(Debug, Default)]
+pub struct Unit {
+    pub functions: BTreeMap<String, Vec<Item>>,
+    pub main: Vec<Item>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EmitError {
+    Unsupported(usize, Op),
+    // A `call`, `callext`, `fnref` or `function` whose name is not a string
+    // literal
+    DynamicName(usize),
+    UndefinedFunction(usize, String),
+    Redefined(usize, String),
+    // A block closed by the wrong keyword, or never closed
+    Unbalanced(usize),
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::Unsupported(i, op) => {
+                write!(f, "instruction {}: `{}` cannot be translated", i, op)
+            }
+            EmitError::DynamicName(i) => {
+                write!(f, "instruction {}: name is not a string literal", i)
+            }
+            EmitError::UndefinedFunction(i, name) => {
+                write!(f, "instruction {}: function `{}` is not defined", i, name)
+            }
+            EmitError::Redefined(i, name) => {
+                write!(f, "instruction {}: function `{}` is defined twice", i, name)
+            }
+            EmitError::Unbalanced(i) => write!(f, "instruction {}: unbalanced block", i),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+// Takes back the `const` instructions of a string literal just pushed to `out`
+fn literal(out: &mut Vec<Item>, index: usize) -> Result<String, EmitError> {
+    let mut name = String::new();
+    while let Some(Item::Op(_, Op::Const, Some(c))) | Some(Item::Op(_, Op::Push, Some(c))) =
+        out.last().cloned()
+    {
+        out.pop();
+        if c == 0 {
+            return Ok(name);
+        }
+        name.push(c as u8 as char);
+    }
+    Err(EmitError::DynamicName(index))
+}
+
+// `code` starts at instruction `base` of its stream
+fn lower(
+    code: &[(Op, Option<i32>)],
+    base: usize,
+    functions: &mut BTreeMap<String, Vec<Item>>,
+) -> Result<Vec<Item>, EmitError> {
+    let mut out = Vec::new();
+    let mut open = Vec::<(usize, Op)>::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let (op, arg) = code[offset];
+        let index = base + offset;
+        match op {
+            Op::Function => {
+                let name = literal(&mut out, index)?;
+                let end = (offset + 1..code.len())
+                    .find(|i| code[*i].0 == Op::EndFunction)
+                    .ok_or(EmitError::Unbalanced(index))?;
+                let body = lower(&code[offset + 1..end], index + 1, functions)?;
+                if functions.insert(name.clone(), body).is_some() {
+                    return Err(EmitError::Redefined(index, name));
+                }
+                offset = end;
+            }
+            Op::Call => {
+                let name = literal(&mut out, index)?;
+                out.push(Item::Call(index, name));
+            }
+            Op::CallExt => {
+                let name = literal(&mut out, index)?;
+                out.push(Item::CallExt(index, name));
+            }
+            Op::FnRef => {
+                let name = literal(&mut out, index)?;
+                out.push(Item::FnRef(index, name));
+            }
+            Op::If | Op::Block | Op::Loop => {
+                open.push((index, op));
+                out.push(Item::Op(index, op, arg));
+            }
+            Op::Else | Op::EndIf | Op::EndBlock | Op::EndLoop => {
+                let opener = match op {
+                    Op::Else | Op::EndIf => Op::If,
+                    Op::EndBlock => Op::Block,
+                    _ => Op::Loop,
+                };
+                match open.last() {
+                    Some((_, o)) if *o == opener => (),
+                    _ => return Err(EmitError::Unbalanced(index)),
+                }
+                if op != Op::Else {
+                    open.pop();
+                }
+                out.push(Item::Op(index, op, arg));
+            }
+            Op::EndFunction | Op::Include | Op::PushStr => {
+                return Err(EmitError::Unsupported(index, op))
+            }
+            _ => out.push(Item::Op(index, op, arg)),
+        }
+        offset += 1;
+    }
+    match open.pop() {
+        Some((i, _)) => Err(EmitError::Unbalanced(i)),
+        None => Ok(out),
+    }
+}
+
+fn check_calls(code: &[Item], unit: &Unit) -> Result<(), EmitError> {
+    for item in code {
+        if let Item::Call(i, name) | Item::FnRef(i, name) = item {
+            if !unit.functions.contains_key(name) {
+                return Err(EmitError::UndefinedFunction(*i, name.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn prepare(program: &Program) -> Result<Unit, EmitError> {
+    let mut unit = Unit::default();
+    for (name, body) in &program.functions {
+        let body = lower(body, 0, &mut unit.functions)?;
+        unit.functions.insert(name.clone(), body);
+    }
+    unit.main = lower(&program.code, 0, &mut unit.functions)?;
+
+    check_calls(&unit.main, &unit)?;
+    for body in unit.functions.values() {
+        check_calls(body, &unit)?;
+    }
+    Ok(unit)
+}
+
+impl Unit {
+    /*
+     * The value `fnref` pushes for a function: its position in `functions`,
+     * counting from 1. Handles are opaque to programs, so this need not match
+     * the numbering the interpreter uses.
+     */
+    pub fn handle(&self, name: &str) -> Option<i32> {
+        self.functions
+            .keys()
+            .position(|n| n == name)
+            .map(|i| i as i32 + 1)
+    }
+
+    // Whether `op` appears anywhere in the program
+    pub fn uses(&self, op: Op) -> bool {
+        let used = |code: &[Item]| {
+            code.iter()
+                .any(|i| matches!(i, Item::Op(_, o, _) if *o == op))
+        };
+        used(&self.main) || self.functions.values().any(|code| used(code))
+    }
+}
+
+// Turns a function name into an identifier: letters and digits are kept and
+// everything else is written as `_xx` in hex
+pub fn mangle(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("_{:02x}", c as u32));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod emit_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+
+    #[test]
+    fn test_hoists_functions() {
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nconst 1\nendfunction\nconst 0\npushstr f\ncall",
+            "t.sm",
+        );
+        let unit = prepare(&program).unwrap();
+
+        assert_eq!(vec![Item::Call(7, "f".to_string())], unit.main);
+        assert_eq!(vec![Item::Op(3, Op::Const, Some(1))], unit.functions["f"]);
+    }
+
+    #[test]
+    fn test_function_handles() {
+        let program = reader::parse(
+            "const 0\npushstr g\nfunction\nendfunction\nconst 0\npushstr f\nfunction\nendfunction\nfnref g",
+            "t.sm",
+        );
+        let unit = prepare(&program).unwrap();
+
+        assert_eq!(vec![Item::FnRef(10, "g".to_string())], unit.main);
+        assert_eq!(Some(1), unit.handle("f"));
+        assert_eq!(Some(2), unit.handle("g"));
+        assert_eq!(None, unit.handle("h"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("const 1\ncall", EmitError::DynamicName(1)),
+            (
+                "const 0\npushstr g\ncall",
+                EmitError::UndefinedFunction(2, "g".to_string()),
+            ),
+            ("fnref g", EmitError::UndefinedFunction(2, "g".to_string())),
+            ("const 1\nif\nendloop", EmitError::Unbalanced(2)),
+            ("loop\nconst 1", EmitError::Unbalanced(0)),
+        ];
+        for (source, expected) in cases.iter() {
+            let program = reader::parse(source, "t.sm");
+            assert_eq!(
+                Err(expected),
+                prepare(&program).as_ref().map(|_| ()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_mangle() {
+        assert_eq!("add_5fone", mangle("add_one"));
+        assert_eq!("f_2dx", mangle("f-x"));
+    }
+}
@@ -0,0 +1,797 @@
+use std::fmt::Write;
+
+use crate::stackmachine::emit::{self, EmitError, Item, Unit};
+use crate::stackmachine::program::Program;
+use crate::stackmachine::Op;
+
+/*
+ * Translates a program into a standalone C file. The operand stack and the
+ * machine's memory become static arrays, functions become C functions and the
+ * structured control flow maps onto `if`, `for (;;)` and `do { } while (0)`.
+ *
+ * Arithmetic wraps instead of being undefined on overflow, and stack
+ * underflow, division by zero, overflow in the `.checked` opcodes and out of
+ * bounds memory accesses stop the program with an error. 64-bit and float
+ * values take two stack cells as in the interpreter, strings live on a heap
+ * of code point arrays that is never collected, and programs using `f.sqrt`
+ * need linking with `-lm`. Function handles index a table of function
+ * pointers. Arrays, closures and `free` are not translated. Defining
+ * `SM_DUMP_STACK` when compiling prints the final stack to stderr.
+ */
+
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef STACK_SIZE
+#define STACK_SIZE 65536
+#endif
+#ifndef MEMORY_SIZE
+#define MEMORY_SIZE 65536
+#endif
+
+static int32_t stack[STACK_SIZE];
+static size_t sp;
+static uint8_t memory[MEMORY_SIZE];
+
+static void fail(const char *message) {
+    fprintf(stderr, "error: %s\n", message);
+    exit(1);
+}
+
+static void push(int32_t v) {
+    if (sp == STACK_SIZE) fail("stack overflow");
+    stack[sp++] = v;
+}
+
+static int32_t pop(void) {
+    if (sp == 0) fail("stack underflow");
+    return stack[--sp];
+}
+
+static inline void op_add(void) { uint32_t a = pop(); uint32_t b = pop(); push((int32_t)(a + b)); }
+static inline void op_sub(void) { uint32_t a = pop(); uint32_t b = pop(); push((int32_t)(a - b)); }
+static inline void op_mul(void) { uint32_t a = pop(); uint32_t b = pop(); push((int32_t)(a * b)); }
+
+static inline void op_div(void) {
+    int32_t a = pop();
+    int32_t b = pop();
+    if (b == 0) fail("division by zero");
+    push(b == -1 ? (int32_t)(0u - (uint32_t)a) : a / b);
+}
+
+static inline void op_mod(void) {
+    int32_t a = pop();
+    int32_t b = pop();
+    if (b == 0) fail("division by zero");
+    push(b == -1 ? 0 : a % b);
+}
+
+static inline void op_neg(void) { push((int32_t)(0u - (uint32_t)pop())); }
+static inline void op_abs(void) { int32_t a = pop(); push(a < 0 ? (int32_t)(0u - (uint32_t)a) : a); }
+static inline void op_min(void) { int32_t a = pop(); int32_t b = pop(); push(a < b ? a : b); }
+static inline void op_max(void) { int32_t a = pop(); int32_t b = pop(); push(a > b ? a : b); }
+static inline void op_and(void) { int32_t a = pop(); int32_t b = pop(); push(a & b); }
+static inline void op_or(void) { int32_t a = pop(); int32_t b = pop(); push(a | b); }
+static inline void op_xor(void) { int32_t a = pop(); int32_t b = pop(); push(a ^ b); }
+static inline void op_shl(void) { uint32_t a = pop(); int32_t b = pop(); push((int32_t)(a << (b & 31))); }
+static inline void op_shr(void) { uint32_t a = pop(); int32_t b = pop(); push((int32_t)(a >> (b & 31))); }
+
+/* Right shifts of negative values are implementation defined in C */
+static inline void op_sar(void) {
+    int32_t a = pop();
+    int32_t b = pop() & 31;
+    push(a < 0 ? ~(~a >> b) : a >> b);
+}
+
+static inline int32_t checked(int64_t v) {
+    if (v < INT32_MIN || v > INT32_MAX) fail("integer overflow");
+    return (int32_t)v;
+}
+
+static inline void op_add_checked(void) { int64_t a = pop(); int64_t b = pop(); push(checked(a + b)); }
+static inline void op_sub_checked(void) { int64_t a = pop(); int64_t b = pop(); push(checked(a - b)); }
+static inline void op_mul_checked(void) { int64_t a = pop(); int64_t b = pop(); push(checked(a * b)); }
+static inline void op_neg_checked(void) { int64_t a = pop(); push(checked(-a)); }
+static inline void op_abs_checked(void) { int64_t a = pop(); push(checked(a < 0 ? -a : a)); }
+
+static inline void op_div_checked(void) {
+    int64_t a = pop();
+    int64_t b = pop();
+    if (b == 0) fail("division by zero");
+    push(checked(a / b));
+}
+
+static inline void op_mod_checked(void) {
+    int32_t a = pop();
+    int32_t b = pop();
+    if (b == 0) fail("division by zero");
+    if (a == INT32_MIN && b == -1) fail("integer overflow");
+    push(a % b);
+}
+
+static inline void op_eq(void) { int32_t a = pop(); int32_t b = pop(); push(a == b); }
+static inline void op_gt(void) { int32_t a = pop(); int32_t b = pop(); push(a > b); }
+static inline void op_lt(void) { int32_t a = pop(); int32_t b = pop(); push(a < b); }
+static inline void op_gte(void) { int32_t a = pop(); int32_t b = pop(); push(a >= b); }
+static inline void op_lte(void) { int32_t a = pop(); int32_t b = pop(); push(a <= b); }
+static inline void op_not(void) { push(pop() <= 0); }
+
+static inline void op_dup(void) { int32_t a = pop(); push(a); push(a); }
+static inline void op_swap(void) { int32_t a = pop(); int32_t b = pop(); push(a); push(b); }
+static inline void op_over(void) { int32_t a = pop(); int32_t b = pop(); push(b); push(a); push(b); }
+
+static inline void op_rot(void) {
+    int32_t a = pop();
+    int32_t b = pop();
+    int32_t c = pop();
+    push(b);
+    push(a);
+    push(c);
+}
+
+static inline void op_nip(void) { int32_t a = pop(); pop(); push(a); }
+static inline void op_tuck(void) { int32_t a = pop(); int32_t b = pop(); push(a); push(b); push(a); }
+static inline void op_depth(void) { push((int32_t)sp); }
+static inline void op_clear(void) { sp = 0; }
+
+static inline size_t below_top(int32_t n) {
+    if (n < 0 || (size_t)n >= sp) fail("stack underflow");
+    return sp - 1 - (size_t)n;
+}
+
+static inline void op_pick(int32_t n) { push(stack[below_top(n)]); }
+
+static inline void op_roll(int32_t n) {
+    size_t i = below_top(n);
+    int32_t v = stack[i];
+    memmove(&stack[i], &stack[i + 1], (sp - i - 1) * sizeof stack[0]);
+    stack[sp - 1] = v;
+}
+
+static inline void push64(uint64_t v) {
+    push((int32_t)(uint32_t)v);
+    push((int32_t)(uint32_t)(v >> 32));
+}
+
+static inline uint64_t pop64(void) {
+    uint64_t hi = (uint32_t)pop();
+    uint64_t lo = (uint32_t)pop();
+    return (hi << 32) | lo;
+}
+
+static inline void pushf(double d) { uint64_t v; memcpy(&v, &d, sizeof v); push64(v); }
+static inline double popf(void) { uint64_t v = pop64(); double d; memcpy(&d, &v, sizeof d); return d; }
+
+static inline void op_i64_add(void) { uint64_t a = pop64(); uint64_t b = pop64(); push64(a + b); }
+static inline void op_i64_sub(void) { uint64_t a = pop64(); uint64_t b = pop64(); push64(a - b); }
+static inline void op_i64_mul(void) { uint64_t a = pop64(); uint64_t b = pop64(); push64(a * b); }
+
+static inline void op_i64_div(void) {
+    int64_t a = (int64_t)pop64();
+    int64_t b = (int64_t)pop64();
+    if (b == 0) fail("division by zero");
+    push64(b == -1 ? 0u - (uint64_t)a : (uint64_t)(a / b));
+}
+
+static inline void op_i64_mod(void) {
+    int64_t a = (int64_t)pop64();
+    int64_t b = (int64_t)pop64();
+    if (b == 0) fail("division by zero");
+    push64(b == -1 ? 0 : (uint64_t)(a % b));
+}
+
+static inline void op_i64_eq(void) { int64_t a = (int64_t)pop64(); int64_t b = (int64_t)pop64(); push(a == b); }
+static inline void op_i64_lt(void) { int64_t a = (int64_t)pop64(); int64_t b = (int64_t)pop64(); push(a < b); }
+static inline void op_i64_gt(void) { int64_t a = (int64_t)pop64(); int64_t b = (int64_t)pop64(); push(a > b); }
+
+static inline void op_f_add(void) { double a = popf(); double b = popf(); pushf(a + b); }
+static inline void op_f_sub(void) { double a = popf(); double b = popf(); pushf(a - b); }
+static inline void op_f_mul(void) { double a = popf(); double b = popf(); pushf(a * b); }
+static inline void op_f_div(void) { double a = popf(); double b = popf(); pushf(a / b); }
+static inline void op_f_neg(void) { pushf(-popf()); }
+static inline void op_f_sqrt(void) { pushf(sqrt(popf())); }
+static inline void op_f_eq(void) { double a = popf(); double b = popf(); push(a == b); }
+static inline void op_f_lt(void) { double a = popf(); double b = popf(); push(a < b); }
+static inline void op_f_gt(void) { double a = popf(); double b = popf(); push(a > b); }
+
+/* Conversions from double truncate, saturate and map NaN to 0 */
+static inline void op_f2i(void) {
+    double d = popf();
+    push(d != d ? 0 : d >= 2147483647.0 ? INT32_MAX : d <= -2147483648.0 ? INT32_MIN : (int32_t)d);
+}
+
+static inline void op_f2l(void) {
+    double d = popf();
+    push64((uint64_t)(d != d ? 0 : d >= 9223372036854775807.0 ? INT64_MAX
+                      : d <= -9223372036854775808.0 ? INT64_MIN : (int64_t)d));
+}
+
+static inline void op_i2f(void) { pushf((double)pop()); }
+static inline void op_i2l(void) { push64((uint64_t)(int64_t)pop()); }
+static inline void op_l2i(void) { push((int32_t)(uint32_t)pop64()); }
+static inline void op_l2f(void) { pushf((double)(int64_t)pop64()); }
+
+static inline void op_i64_print(void) {
+    uint64_t v = pop64();
+    printf("%" PRId64 "\n", (int64_t)v);
+    push64(v);
+}
+
+/* Same form as the interpreter: 0.25, 1.0, 1e16, 1.5e-7, inf, NaN */
+static inline void op_f_print(void) {
+    char text[40];
+    int precision, exponent, decimals;
+    double d = popf();
+    pushf(d);
+    if (d != d) {
+        printf("NaN\n");
+        return;
+    }
+    if (d == HUGE_VAL || d == -HUGE_VAL) {
+        printf(d > 0 ? "inf\n" : "-inf\n");
+        return;
+    }
+    /* The fewest significant digits that read back as the same double */
+    for (precision = 1; precision < 17; precision++) {
+        snprintf(text, sizeof text, "%.*e", precision - 1, d);
+        if (strtod(text, NULL) == d) break;
+    }
+    snprintf(text, sizeof text, "%.*e", precision - 1, d);
+    exponent = atoi(strchr(text, 'e') + 1);
+    if (d != 0 && (exponent < -4 || exponent >= 16)) {
+        *strchr(text, 'e') = '\0';
+        printf("%se%d\n", text, exponent);
+    } else {
+        decimals = precision - 1 - exponent;
+        printf("%.*f\n", decimals > 0 ? decimals : 1, d);
+    }
+}
+
+static inline void op_print(void) {
+    if (sp == 0) fail("stack underflow");
+    printf("%d\n", stack[sp - 1]);
+}
+
+static inline void put_utf8(int32_t c) {
+    if (c < 0x80) {
+        putchar(c);
+    } else if (c < 0x800) {
+        putchar(0xc0 | (c >> 6));
+        putchar(0x80 | (c & 0x3f));
+    } else if (c < 0x10000) {
+        putchar(0xe0 | (c >> 12));
+        putchar(0x80 | ((c >> 6) & 0x3f));
+        putchar(0x80 | (c & 0x3f));
+    } else {
+        putchar(0xf0 | (c >> 18));
+        putchar(0x80 | ((c >> 12) & 0x3f));
+        putchar(0x80 | ((c >> 6) & 0x3f));
+        putchar(0x80 | (c & 0x3f));
+    }
+}
+
+/* Characters are single bytes, printed as UTF-8 like the interpreter does */
+static inline void op_printchars(void) {
+    int32_t c;
+    while ((c = pop()) != 0) put_utf8((uint8_t)c);
+    putchar('\n');
+}
+
+/* Strings are arrays of code points on a heap that is never freed */
+typedef struct {
+    int32_t *chars;
+    size_t len;
+} string;
+
+static string *strings;
+static size_t nstrings;
+static size_t string_capacity;
+
+/* Returns the handle of a new string of `len` characters; handles start at 1 */
+static inline int32_t str_alloc(size_t len) {
+    if (nstrings == string_capacity) {
+        string_capacity = string_capacity ? 2 * string_capacity : 16;
+        strings = realloc(strings, string_capacity * sizeof *strings);
+        if (!strings) fail("out of memory");
+    }
+    strings[nstrings].chars = malloc(len ? len * sizeof(int32_t) : 1);
+    if (!strings[nstrings].chars) fail("out of memory");
+    strings[nstrings].len = len;
+    return (int32_t)++nstrings;
+}
+
+static inline string *str_get(int32_t h) {
+    if (h < 1 || (size_t)h > nstrings) fail("not a string handle");
+    return &strings[h - 1];
+}
+
+/* Invalid character codes become U+FFFD, as in the interpreter */
+static inline int32_t str_char(int32_t c) {
+    return c < 0 || c > 0x10ffff || (c >= 0xd800 && c < 0xe000) ? 0xfffd : c;
+}
+
+static inline void op_str_new(void) {
+    size_t end = sp, len, i;
+    int32_t h;
+    while (end > 0 && stack[end - 1] != 0) end--;
+    if (end == 0) fail("string is not null-terminated");
+    len = sp - end;
+    h = str_alloc(len);
+    for (i = 0; i < len; i++) strings[h - 1].chars[i] = str_char(stack[sp - 1 - i]);
+    sp = end - 1;
+    push(h);
+}
+
+static inline void op_str_len(void) { push((int32_t)str_get(pop())->len); }
+
+static inline void op_str_concat(void) {
+    int32_t a = pop();
+    int32_t b = pop();
+    size_t la = str_get(a)->len;
+    size_t lb = str_get(b)->len;
+    int32_t h = str_alloc(la + lb);
+    memcpy(strings[h - 1].chars, strings[a - 1].chars, la * sizeof(int32_t));
+    memcpy(strings[h - 1].chars + la, strings[b - 1].chars, lb * sizeof(int32_t));
+    push(h);
+}
+
+static inline void op_str_eq(void) {
+    string *a = str_get(pop());
+    string *b = str_get(pop());
+    push(a->len == b->len && !memcmp(a->chars, b->chars, a->len * sizeof(int32_t)));
+}
+
+static inline void op_str_at(void) {
+    string *s = str_get(pop());
+    int32_t i = pop();
+    if (i < 0 || (size_t)i >= s->len) fail("index out of range");
+    push(s->chars[i]);
+}
+
+static inline void op_str_slice(void) {
+    int32_t h = pop();
+    int32_t start = pop();
+    int32_t end = pop();
+    int32_t r;
+    if (start < 0 || start > end || (size_t)end > str_get(h)->len) fail("index out of range");
+    r = str_alloc((size_t)(end - start));
+    memcpy(strings[r - 1].chars, strings[h - 1].chars + start, (size_t)(end - start) * sizeof(int32_t));
+    push(r);
+}
+
+static inline void op_int_to_str(void) {
+    char text[12];
+    int n = snprintf(text, sizeof text, "%d", pop());
+    int32_t h = str_alloc((size_t)n);
+    int i;
+    for (i = 0; i < n; i++) strings[h - 1].chars[i] = text[i];
+    push(h);
+}
+
+static inline void op_printstr(void) {
+    string *s = str_get(pop());
+    size_t i;
+    for (i = 0; i < s->len; i++) put_utf8(s->chars[i]);
+    putchar('\n');
+}
+
+static inline void op_dbg(void) {
+    size_t i;
+    printf("DEBUG::StackMachine<0, [");
+    for (i = 0; i < sp; i++) printf(i ? ", %d" : "%d", stack[i]);
+    printf("]>\n");
+}
+
+static inline size_t address(int32_t a) {
+    if (a < 0 || (size_t)a + 4 > MEMORY_SIZE) fail("address out of bounds");
+    return (size_t)a;
+}
+
+static inline void op_load(void) {
+    size_t a = address(pop());
+    push((int32_t)((uint32_t)memory[a] | ((uint32_t)memory[a + 1] << 8) |
+                   ((uint32_t)memory[a + 2] << 16) | ((uint32_t)memory[a + 3] << 24)));
+}
+
+static inline void op_store(void) {
+    size_t a = address(pop());
+    uint32_t v = (uint32_t)pop();
+    memory[a] = v & 0xff;
+    memory[a + 1] = (v >> 8) & 0xff;
+    memory[a + 2] = (v >> 16) & 0xff;
+    memory[a + 3] = v >> 24;
+}
+"#;
+
+const MAIN: &str = r#"
+int main(void) {
+    run();
+#ifdef SM_DUMP_STACK
+    {
+        size_t i;
+        fprintf(stderr, "[");
+        for (i = 0; i < sp; i++) fprintf(stderr, i ? ", %d" : "%d", stack[i]);
+        fprintf(stderr, "]\n");
+    }
+#endif
+    return 0;
+}
+"#;
+
+// The helper in the prelude implementing a simple opcode
+fn helper(op: Op) -> Option<&'static str> {
+    Some(match op {
+        Op::Add => "op_add",
+        Op::Sub => "op_sub",
+        Op::Mul => "op_mul",
+        Op::Div => "op_div",
+        Op::Mod => "op_mod",
+        Op::Neg => "op_neg",
+        Op::Abs => "op_abs",
+        Op::Min => "op_min",
+        Op::Max => "op_max",
+        Op::And => "op_and",
+        Op::Or => "op_or",
+        Op::Xor => "op_xor",
+        Op::Shl => "op_shl",
+        Op::Shr => "op_shr",
+        Op::Sar => "op_sar",
+        Op::AddChecked => "op_add_checked",
+        Op::SubChecked => "op_sub_checked",
+        Op::MulChecked => "op_mul_checked",
+        Op::DivChecked => "op_div_checked",
+        Op::ModChecked => "op_mod_checked",
+        Op::NegChecked => "op_neg_checked",
+        Op::AbsChecked => "op_abs_checked",
+        Op::I64Add => "op_i64_add",
+        Op::I64Sub => "op_i64_sub",
+        Op::I64Mul => "op_i64_mul",
+        Op::I64Div => "op_i64_div",
+        Op::I64Mod => "op_i64_mod",
+        Op::I64Eq => "op_i64_eq",
+        Op::I64Lt => "op_i64_lt",
+        Op::I64Gt => "op_i64_gt",
+        Op::I64Print => "op_i64_print",
+        Op::FAdd => "op_f_add",
+        Op::FSub => "op_f_sub",
+        Op::FMul => "op_f_mul",
+        Op::FDiv => "op_f_div",
+        Op::FNeg => "op_f_neg",
+        Op::FSqrt => "op_f_sqrt",
+        Op::FEq => "op_f_eq",
+        Op::FLt => "op_f_lt",
+        Op::FGt => "op_f_gt",
+        Op::FPrint => "op_f_print",
+        Op::I2F => "op_i2f",
+        Op::F2I => "op_f2i",
+        Op::I2L => "op_i2l",
+        Op::L2I => "op_l2i",
+        Op::L2F => "op_l2f",
+        Op::F2L => "op_f2l",
+        Op::r#Eq => "op_eq",
+        Op::GT => "op_gt",
+        Op::LT => "op_lt",
+        Op::GTE => "op_gte",
+        Op::LTE => "op_lte",
+        Op::Not => "op_not",
+        Op::Dup => "op_dup",
+        Op::Swap => "op_swap",
+        Op::Over => "op_over",
+        Op::Rot => "op_rot",
+        Op::Nip => "op_nip",
+        Op::Tuck => "op_tuck",
+        Op::Depth => "op_depth",
+        Op::Clear => "op_clear",
+        Op::Print => "op_print",
+        Op::PrintStr => "op_printstr",
+        Op::PrintChars => "op_printchars",
+        Op::StrNew => "op_str_new",
+        Op::StrLen => "op_str_len",
+        Op::StrConcat => "op_str_concat",
+        Op::StrEq => "op_str_eq",
+        Op::StrAt => "op_str_at",
+        Op::StrSlice => "op_str_slice",
+        Op::IntToStr => "op_int_to_str",
+        Op::Debug => "op_dbg",
+        Op::Load => "op_load",
+        Op::Store => "op_store",
+        _ => return None,
+    })
+}
+
+fn body(out: &mut String, code: &[Item], unit: &Unit) -> Result<(), EmitError> {
+    // Open blocks, innermost last; `true` for those `break` leaves
+    let mut open = Vec::<bool>::new();
+    let line = |out: &mut String, depth: usize, text: &str| {
+        writeln!(out, "{}{}", "    ".repeat(depth + 1), text).unwrap();
+    };
+
+    for item in code {
+        let (index, op, arg) = match item {
+            Item::Op(i, op, arg) => (*i, *op, *arg),
+            Item::Call(_, name) => {
+                line(out, open.len(), &format!("fn_{}();", emit::mangle(name)));
+                continue;
+            }
+            Item::CallExt(i, _) => return Err(EmitError::Unsupported(*i, Op::CallExt)),
+            Item::FnRef(_, name) => {
+                let handle = unit.handle(name).unwrap();
+                line(out, open.len(), &format!("push({});", handle));
+                continue;
+            }
+        };
+        let depth = open.len();
+        match op {
+            Op::Const | Op::Push => line(out, depth, &format!("push({});", arg.unwrap_or(0))),
+            Op::Pop => line(out, depth, "pop();"),
+            Op::Pick => line(out, depth, &format!("op_pick({});", arg.unwrap_or(0))),
+            Op::Roll => line(out, depth, &format!("op_roll({});", arg.unwrap_or(0))),
+            Op::GetPid | Op::Child => line(out, depth, "push(0);"),
+            Op::CallIndirect => line(out, depth, "call_indirect();"),
+            Op::Noop => (),
+            Op::If => {
+                line(out, depth, "if (pop() > 0) {");
+                open.push(false);
+            }
+            Op::Else => line(out, depth - 1, "} else {"),
+            Op::Block => {
+                line(out, depth, "do {");
+                open.push(true);
+            }
+            Op::Loop => {
+                line(out, depth, "for (;;) {");
+                open.push(true);
+            }
+            Op::EndIf | Op::EndLoop => {
+                open.pop();
+                line(out, depth - 1, "}");
+            }
+            Op::EndBlock => {
+                open.pop();
+                line(out, depth - 1, "} while (0);");
+            }
+            // Outside of any loop a `break` ends the routine, like `return`
+            Op::Break if open.contains(&true) => line(out, depth, "break;"),
+            Op::Break | Op::Return => line(out, depth, "return;"),
+            _ => match helper(op) {
+                Some(name) => line(out, depth, &format!("{}();", name)),
+                None => return Err(EmitError::Unsupported(index, op)),
+            },
+        }
+    }
+    Ok(())
+}
+
+pub fn emit_c(program: &Program) -> Result<String, EmitError> {
+    let unit = emit::prepare(program)?;
+    let mut out = String::from(PRELUDE);
+
+    out.push('\n');
+    for name in unit.functions.keys() {
+        writeln!(out, "void fn_{}(void);", emit::mangle(name)).unwrap();
+    }
+    writeln!(out, "static void run(void);").unwrap();
+
+    // Indexed by function handle; the null entry keeps the table from being
+    // empty
+    if unit.uses(Op::CallIndirect) {
+        out.push_str("\nstatic void (*const functions[])(void) = {\n");
+        for name in unit.functions.keys() {
+            writeln!(out, "    fn_{},", emit::mangle(name)).unwrap();
+        }
+        out.push_str("    NULL,\n};\n");
+        writeln!(
+        out,
+        "\nstatic void call_indirect(void) {{\n    int32_t h = pop();\n    if (h < 1 || h > {}) fail(\"not a function handle\");\n    functions[h - 1]();\n}}",
+            unit.functions.len()
+        )
+        .unwrap();
+    }
+
+    for (name, code) in &unit.functions {
+        writeln!(out, "\n/* {} */", name.replace("*/", "* /")).unwrap();
+        writeln!(out, "void fn_{}(void) {{", emit::mangle(name)).unwrap();
+        body(&mut out, code, &unit)?;
+        out.push_str("}\n");
+    }
+
+    out.push_str("\nstatic void run(void) {\n");
+    body(&mut out, &unit.main, &unit)?;
+    out.push_str("}\n");
+    out.push_str(MAIN);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod emit_c_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+    use crate::stackmachine::sink::Capture;
+    use crate::stackmachine::StackMachine;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    // Builds the C translation of `program` in a scratch directory, or returns
+    // `None` if there is no C compiler to build it with
+    fn build(program: &Program, name: &str) -> Option<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("sm-emit-c-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join(format!("{}.c", name));
+        let binary = dir.join(name);
+        std::fs::write(&source, emit_c(program).unwrap()).unwrap();
+
+        let status = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror", "-DSM_DUMP_STACK", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .arg("-lm")
+            .status()
+            .ok()?;
+        assert!(status.success(), "cc failed on {}", source.display());
+        Some(binary)
+    }
+
+    fn run(binary: &Path) -> (String, String) {
+        let output = Command::new(binary).output().unwrap();
+        assert!(output.status.success());
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_output() {
+        let program = reader::parse(
+            "const 0\npushstr hi\nprintchars\nconst 7\nconst 3\nsub\nprint\ndbg",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "output") {
+            let (stdout, stderr) = run(&binary);
+
+            assert_eq!("hi\n-4\nDEBUG::StackMachine<0, [-4]>\n", stdout);
+            assert_eq!("[-4]\n", stderr);
+        }
+    }
+
+    #[test]
+    fn test_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let program = reader::load(path.to_str().unwrap()).unwrap();
+            if emit_c(&program).is_err() {
+                continue;
+            }
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            if let Some(binary) = build(&program, name) {
+                let output = Capture::new();
+                let mut sm = StackMachine::new(2u32.pow(16));
+                sm.set_output(output.clone());
+                sm.execute_program(&program);
+                let (stdout, stderr) = run(&binary);
+
+                assert_eq!(output.contents(), stdout, "{}", path.display());
+                assert_eq!(format!("{:?}\n", sm.stack), stderr, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let program = reader::parse(
+            "const 1\nconst 2\nconst 3\nconst 4\npick 3\nroll 4\ntuck\nnip\ndepth\nrot\nover",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "stack_words") {
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.execute_program(&program);
+            let (_, stderr) = run(&binary);
+
+            assert_eq!(format!("{:?}\n", sm.stack), stderr);
+        }
+    }
+
+    #[test]
+    fn test_integer_ops() {
+        let program = reader::parse(
+            "const 1\nconst 2147483647\nadd\nconst -1\nswap\ndiv\nconst -1\nconst -2147483648\nmod\nconst 3\nconst -7\nmod\nneg\nabs\nconst 12\nconst 10\nxor\nconst 6\nor\nconst 3\nand\nconst 2\nmax\nconst 9\nmin\nconst 33\nconst -8\nsar\nconst 28\nconst -1\nshr\nconst 36\nconst 1\nshl\nconst 3\nconst -4\nmul.checked\nconst -2147483647\nabs.checked",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "integer_ops") {
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.execute_program(&program);
+            let (_, stderr) = run(&binary);
+
+            assert_eq!(format!("{:?}\n", sm.stack), stderr);
+        }
+    }
+
+    #[test]
+    fn test_wide_values() {
+        let program = reader::parse(
+            "f.const 2.5\nf.const -1e3\nf.div\nf.print\ni64.const 3\ni64.const 9000000000\ni64.mul\ni64.print\nl2f\nf.sqrt\nf2i\ni2l\ni64.const 7\ni64.gt\nf.const 1e30\nf2l\nf.const 0.1\nf.const 0.2\nf.add\nf.print\nf.const 1e16\nf.print\nf.const -1.5e-7\nf.print\nf.const 0\nf.print\nf.const 0\nf.div\nf.print\nf.const 1e300\nf.const 1e300\nf.mul\nf.print",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "wide_values") {
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.execute_program(&program);
+            let (stdout, stderr) = run(&binary);
+
+            assert_eq!(
+                "-400.0\n27000000000\n0.30000000000000004\n1e16\n-1.5e-7\n0.0\nNaN\ninf\n",
+                stdout
+            );
+            assert_eq!(format!("{:?}\n", sm.stack), stderr);
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let program = reader::parse(
+            "const 3\nconst 1\nstr.const héllo world\nstr.slice\nstr.const x=\nstr.concat\ndup\nprintstr\nstr.len\nconst -5\nint->str\ndup\nprintstr\nstr.len\nconst 1\nstr.const ab\nstr.at\nstr.const ab\nstr.const ab\nstr.eq",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "strings") {
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.execute_program(&program);
+            let (stdout, stderr) = run(&binary);
+
+            assert_eq!("x=él\n-5\n", stdout);
+            assert_eq!(format!("{:?}\n", sm.stack), stderr);
+        }
+    }
+
+    #[test]
+    fn test_indirect_calls() {
+        let program = reader::parse(
+            "const 0\npushstr inc\nfunction\nconst 1\nadd\nendfunction\nconst 0\npushstr dbl\nfunction\nconst 2\nmul\nendfunction\nconst 5\nfnref inc\ncall.indirect\nfnref dbl\ncall.indirect\nprint",
+            "t.sm",
+        );
+        if let Some(binary) = build(&program, "indirect") {
+            let mut sm = StackMachine::new(2u32.pow(16));
+            sm.execute_program(&program);
+            let (stdout, stderr) = run(&binary);
+
+            assert_eq!("12\n", stdout);
+            assert_eq!(format!("{:?}\n", sm.stack), stderr);
+        }
+    }
+
+    #[test]
+    fn test_traps() {
+        let cases = [
+            ("const 0\nconst 1\nmod", "division by zero"),
+            ("const 1\nconst 2147483647\nadd.checked", "integer overflow"),
+            (
+                "const -1\nconst -2147483648\ndiv.checked",
+                "integer overflow",
+            ),
+            ("const 2\nstr.const ab\nstr.at", "index out of range"),
+            ("const 1\ncall.indirect", "not a function handle"),
+        ];
+        for (i, (source, message)) in cases.iter().enumerate() {
+            let program = reader::parse(source, "t.sm");
+            if let Some(binary) = build(&program, &format!("trap{}", i)) {
+                let output = Command::new(binary).output().unwrap();
+
+                assert!(!output.status.success());
+                assert_eq!(
+                    format!("error: {}\n", message),
+                    String::from_utf8(output.stderr).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let program = reader::parse("fork", "t.sm");
+
+        assert_eq!(Err(EmitError::Unsupported(0, Op::Fork)), emit_c(&program));
+    }
+}
@@ -0,0 +1,914 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::stackmachine::emit::{self, EmitError, Item, Unit};
+use crate::stackmachine::program::Program;
+use crate::stackmachine::Op;
+
+/*
+ * Translates a program into a WebAssembly text module. Linear memory starts
+ * with the machine's memory, so `load` and `store` use the same addresses as
+ * `StackMachine::memory`, and the operand stack lives right after it as
+ * 32-bit words, `$sp` pointing past the top. Keeping the stack in memory
+ * rather than on the WebAssembly operand stack lets functions take and leave
+ * any number of values, as they do in the interpreter. Where the interpreter
+ * would trap, the module traps too. There is no heap, so `printstr`, the
+ * `str.*` and `array.*` opcodes, `alloc`, `free` and closures cannot be
+ * translated;
+ * `printchars` can. `call.indirect` goes through a table of the functions,
+ * indexed by function handle.
+ *
+ * The host provides, under `env`:
+ *
+ *   print (value)                  prints a number on its own line
+ *   printstr (address, length)     prints that many bytes, one per character,
+ *                                  for `printchars`
+ *   dbg (address, count)           shows the stack, bottom first
+ *   print_i64 (value)              prints a 64-bit integer, if `i64.print` is used
+ *   print_f64 (value)              prints a float, if `f.print` is used
+ *
+ * and every external function called with `callext` under `ext`, taking and
+ * returning `$sp` so it can use the stack in the exported memory. The module
+ * exports `run`, `memory` and `sp`.
+ */
+
+const MEMORY_SIZE: u32 = 1 << 16;
+const STACK_BASE: u32 = MEMORY_SIZE;
+const STACK_LIMIT: u32 = STACK_BASE + 4 * (1 << 16);
+// The stack, plus a page that `printchars` uses as scratch space above it
+const PAGES: u32 = STACK_LIMIT / (1 << 16) + 1;
+
+fn runtime() -> String {
+    format!(
+        r#"  (memory (export "memory") {pages})
+  (global $sp (export "sp") (mut i32) (i32.const {base}))
+
+  (func $push (param $v i32)
+    (if (i32.ge_u (global.get $sp) (i32.const {limit})) (then unreachable))
+    (i32.store (global.get $sp) (local.get $v))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 4))))
+
+  (func $pop (result i32)
+    (if (i32.le_u (global.get $sp) (i32.const {base})) (then unreachable))
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 4)))
+    (i32.load (global.get $sp)))
+
+  (func $cond (result i32)
+    (i32.gt_s (call $pop) (i32.const 0)))
+
+  (func $op_add (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.add (local.get $a) (local.get $b))))
+
+  (func $op_sub (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.sub (local.get $a) (local.get $b))))
+
+  (func $op_mul (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.mul (local.get $a) (local.get $b))))
+
+  ;; `i32.div_s` traps on overflow, where `div` wraps
+  (func $op_div (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (if (i32.eq (local.get $b) (i32.const -1))
+      (then (call $push (i32.sub (i32.const 0) (local.get $a))))
+      (else (call $push (i32.div_s (local.get $a) (local.get $b))))))
+
+  (func $op_mod (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.rem_s (local.get $a) (local.get $b))))
+
+  (func $op_neg
+    (call $push (i32.sub (i32.const 0) (call $pop))))
+
+  (func $op_abs (local $a i32)
+    (local.set $a (call $pop))
+    (call $push
+      (select (i32.sub (i32.const 0) (local.get $a)) (local.get $a)
+              (i32.lt_s (local.get $a) (i32.const 0)))))
+
+  (func $op_min (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push
+      (select (local.get $a) (local.get $b) (i32.lt_s (local.get $a) (local.get $b)))))
+
+  (func $op_max (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push
+      (select (local.get $a) (local.get $b) (i32.gt_s (local.get $a) (local.get $b)))))
+
+  (func $op_and (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.and (local.get $a) (local.get $b))))
+
+  (func $op_or (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.or (local.get $a) (local.get $b))))
+
+  (func $op_xor (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.xor (local.get $a) (local.get $b))))
+
+  (func $op_shl (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.shl (local.get $a) (local.get $b))))
+
+  (func $op_shr (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.shr_u (local.get $a) (local.get $b))))
+
+  (func $op_sar (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.shr_s (local.get $a) (local.get $b))))
+
+  ;; The `.checked` opcodes compute in 64 bits and trap if the result does
+  ;; not fit back into 32
+  (func $checked (param $v i64) (result i32)
+    (if (i64.ne (local.get $v) (i64.extend_i32_s (i32.wrap_i64 (local.get $v))))
+      (then unreachable))
+    (i32.wrap_i64 (local.get $v)))
+
+  (func $op_add.checked (local $a i64) (local $b i64)
+    (local.set $a (i64.extend_i32_s (call $pop)))
+    (local.set $b (i64.extend_i32_s (call $pop)))
+    (call $push (call $checked (i64.add (local.get $a) (local.get $b)))))
+
+  (func $op_sub.checked (local $a i64) (local $b i64)
+    (local.set $a (i64.extend_i32_s (call $pop)))
+    (local.set $b (i64.extend_i32_s (call $pop)))
+    (call $push (call $checked (i64.sub (local.get $a) (local.get $b)))))
+
+  (func $op_mul.checked (local $a i64) (local $b i64)
+    (local.set $a (i64.extend_i32_s (call $pop)))
+    (local.set $b (i64.extend_i32_s (call $pop)))
+    (call $push (call $checked (i64.mul (local.get $a) (local.get $b)))))
+
+  (func $op_div.checked (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.div_s (local.get $a) (local.get $b))))
+
+  (func $op_mod.checked (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (if (i32.and (i32.eq (local.get $a) (i32.const 0x80000000))
+                 (i32.eq (local.get $b) (i32.const -1)))
+      (then unreachable))
+    (call $push (i32.rem_s (local.get $a) (local.get $b))))
+
+  (func $op_neg.checked
+    (call $push (call $checked (i64.sub (i64.const 0) (i64.extend_i32_s (call $pop))))))
+
+  (func $op_abs.checked (local $a i64)
+    (local.set $a (i64.extend_i32_s (call $pop)))
+    (call $push
+      (call $checked
+        (select (i64.sub (i64.const 0) (local.get $a)) (local.get $a)
+                (i64.lt_s (local.get $a) (i64.const 0))))))
+
+  (func $op_eq (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.eq (local.get $a) (local.get $b))))
+
+  (func $op_gt (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.gt_s (local.get $a) (local.get $b))))
+
+  (func $op_lt (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.lt_s (local.get $a) (local.get $b))))
+
+  (func $op_gte (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.ge_s (local.get $a) (local.get $b))))
+
+  (func $op_lte (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (i32.le_s (local.get $a) (local.get $b))))
+
+  (func $op_not
+    (call $push (i32.le_s (call $pop) (i32.const 0))))
+
+  (func $op_pop
+    (drop (call $pop)))
+
+  (func $op_dup (local $a i32)
+    (local.set $a (call $pop))
+    (call $push (local.get $a))
+    (call $push (local.get $a)))
+
+  (func $op_swap (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (local.get $a))
+    (call $push (local.get $b)))
+
+  (func $op_over (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (local.get $b))
+    (call $push (local.get $a))
+    (call $push (local.get $b)))
+
+  (func $op_rot (local $a i32) (local $b i32) (local $c i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (local.set $c (call $pop))
+    (call $push (local.get $b))
+    (call $push (local.get $a))
+    (call $push (local.get $c)))
+
+  (func $op_nip (local $a i32)
+    (local.set $a (call $pop))
+    (drop (call $pop))
+    (call $push (local.get $a)))
+
+  (func $op_tuck (local $a i32) (local $b i32)
+    (local.set $a (call $pop))
+    (local.set $b (call $pop))
+    (call $push (local.get $a))
+    (call $push (local.get $b))
+    (call $push (local.get $a)))
+
+  (func $op_depth
+    (call $push
+      (i32.shr_u (i32.sub (global.get $sp) (i32.const {base})) (i32.const 2))))
+
+  (func $op_clear
+    (global.set $sp (i32.const {base})))
+
+  ;; Address of the value $n below the top of the stack
+  (func $below_top (param $n i32) (result i32) (local $a i32)
+    (local.set $a
+      (i32.sub (global.get $sp) (i32.shl (i32.add (local.get $n) (i32.const 1)) (i32.const 2))))
+    (if (i32.or (i32.lt_s (local.get $n) (i32.const 0))
+                (i32.lt_s (local.get $a) (i32.const {base})))
+      (then unreachable))
+    (local.get $a))
+
+  (func $op_pick (param $n i32)
+    (call $push (i32.load (call $below_top (local.get $n)))))
+
+  (func $op_roll (param $n i32) (local $a i32) (local $v i32)
+    (local.set $a (call $below_top (local.get $n)))
+    (local.set $v (i32.load (local.get $a)))
+    (block $done
+      (loop $next
+        (local.set $a (i32.add (local.get $a) (i32.const 4)))
+        (br_if $done (i32.ge_u (local.get $a) (global.get $sp)))
+        (i32.store (i32.sub (local.get $a) (i32.const 4)) (i32.load (local.get $a)))
+        (br $next)))
+    (i32.store (i32.sub (global.get $sp) (i32.const 4)) (local.get $v)))
+
+  ;; 64-bit and float values are two cells, low word first, which is how
+  ;; `i64.load` reads them
+  (func $push64 (param $v i64)
+    (if (i32.gt_u (global.get $sp) (i32.const {limit64})) (then unreachable))
+    (i64.store (global.get $sp) (local.get $v))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 8))))
+
+  (func $pop64 (result i64)
+    (if (i32.lt_u (global.get $sp) (i32.const {base64})) (then unreachable))
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 8)))
+    (i64.load (global.get $sp)))
+
+  (func $pushf (param $v f64)
+    (call $push64 (i64.reinterpret_f64 (local.get $v))))
+
+  (func $popf (result f64)
+    (f64.reinterpret_i64 (call $pop64)))
+
+  (func $op_i64.add (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push64 (i64.add (local.get $a) (local.get $b))))
+
+  (func $op_i64.sub (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push64 (i64.sub (local.get $a) (local.get $b))))
+
+  (func $op_i64.mul (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push64 (i64.mul (local.get $a) (local.get $b))))
+
+  (func $op_i64.div (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (if (i64.eq (local.get $b) (i64.const -1))
+      (then (call $push64 (i64.sub (i64.const 0) (local.get $a))))
+      (else (call $push64 (i64.div_s (local.get $a) (local.get $b))))))
+
+  (func $op_i64.mod (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push64 (i64.rem_s (local.get $a) (local.get $b))))
+
+  (func $op_i64.eq (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push (i64.eq (local.get $a) (local.get $b))))
+
+  (func $op_i64.lt (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push (i64.lt_s (local.get $a) (local.get $b))))
+
+  (func $op_i64.gt (local $a i64) (local $b i64)
+    (local.set $a (call $pop64))
+    (local.set $b (call $pop64))
+    (call $push (i64.gt_s (local.get $a) (local.get $b))))
+
+  (func $op_f.add (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $pushf (f64.add (local.get $a) (local.get $b))))
+
+  (func $op_f.sub (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $pushf (f64.sub (local.get $a) (local.get $b))))
+
+  (func $op_f.mul (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $pushf (f64.mul (local.get $a) (local.get $b))))
+
+  (func $op_f.div (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $pushf (f64.div (local.get $a) (local.get $b))))
+
+  (func $op_f.neg
+    (call $pushf (f64.neg (call $popf))))
+
+  (func $op_f.sqrt
+    (call $pushf (f64.sqrt (call $popf))))
+
+  (func $op_f.eq (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $push (f64.eq (local.get $a) (local.get $b))))
+
+  (func $op_f.lt (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $push (f64.lt (local.get $a) (local.get $b))))
+
+  (func $op_f.gt (local $a f64) (local $b f64)
+    (local.set $a (call $popf))
+    (local.set $b (call $popf))
+    (call $push (f64.gt (local.get $a) (local.get $b))))
+
+  (func $op_i2f
+    (call $pushf (f64.convert_i32_s (call $pop))))
+
+  (func $op_f2i
+    (call $push (i32.trunc_sat_f64_s (call $popf))))
+
+  (func $op_i2l
+    (call $push64 (i64.extend_i32_s (call $pop))))
+
+  (func $op_l2i
+    (call $push (i32.wrap_i64 (call $pop64))))
+
+  (func $op_l2f
+    (call $pushf (f64.convert_i64_s (call $pop64))))
+
+  (func $op_f2l
+    (call $push64 (i64.trunc_sat_f64_s (call $popf))))
+
+  (func $op_print
+    (if (i32.le_u (global.get $sp) (i32.const {base})) (then unreachable))
+    (call $print (i32.load (i32.sub (global.get $sp) (i32.const 4)))))
+
+  (func $op_printchars (local $top i32) (local $c i32) (local $n i32)
+    (local.set $top (global.get $sp))
+    (block $done
+      (loop $next
+        (local.set $c (call $pop))
+        (br_if $done (i32.eqz (local.get $c)))
+        (i32.store8 (i32.add (local.get $top) (local.get $n)) (local.get $c))
+        (local.set $n (i32.add (local.get $n) (i32.const 1)))
+        (br $next)))
+    (call $printstr (local.get $top) (local.get $n)))
+
+  (func $op_dbg
+    (call $dbg
+      (i32.const {base})
+      (i32.shr_u (i32.sub (global.get $sp) (i32.const {base})) (i32.const 2))))
+
+  (func $address (param $a i32) (result i32)
+    (if (i32.gt_u (local.get $a) (i32.const {last_word})) (then unreachable))
+    (local.get $a))
+
+  (func $op_load
+    (call $push (i32.load (call $address (call $pop)))))
+
+  (func $op_store (local $a i32)
+    (local.set $a (call $address (call $pop)))
+    (i32.store (local.get $a) (call $pop)))
+"#,
+        pages = PAGES,
+        base = STACK_BASE,
+        limit = STACK_LIMIT,
+        limit64 = STACK_LIMIT - 8,
+        base64 = STACK_BASE + 8,
+        last_word = MEMORY_SIZE - 4,
+    )
+}
+
+// Opcodes implemented by a `$op_<mnemonic>` function in the runtime
+fn has_helper(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::r#Eq
+            | Op::GT
+            | Op::LT
+            | Op::GTE
+            | Op::LTE
+            | Op::Not
+            | Op::Mod
+            | Op::Neg
+            | Op::Abs
+            | Op::Min
+            | Op::Max
+            | Op::And
+            | Op::Or
+            | Op::Xor
+            | Op::Shl
+            | Op::Shr
+            | Op::Sar
+            | Op::AddChecked
+            | Op::SubChecked
+            | Op::MulChecked
+            | Op::DivChecked
+            | Op::ModChecked
+            | Op::NegChecked
+            | Op::AbsChecked
+            | Op::I64Add
+            | Op::I64Sub
+            | Op::I64Mul
+            | Op::I64Div
+            | Op::I64Mod
+            | Op::I64Eq
+            | Op::I64Lt
+            | Op::I64Gt
+            | Op::I64Print
+            | Op::FAdd
+            | Op::FSub
+            | Op::FMul
+            | Op::FDiv
+            | Op::FNeg
+            | Op::FSqrt
+            | Op::FEq
+            | Op::FLt
+            | Op::FGt
+            | Op::FPrint
+            | Op::I2F
+            | Op::F2I
+            | Op::I2L
+            | Op::L2I
+            | Op::L2F
+            | Op::F2L
+            | Op::Pop
+            | Op::Dup
+            | Op::Swap
+            | Op::Over
+            | Op::Rot
+            | Op::Nip
+            | Op::Tuck
+            | Op::Depth
+            | Op::Clear
+            | Op::Print
+            | Op::PrintChars
+            | Op::Debug
+            | Op::Load
+            | Op::Store
+    )
+}
+
+// A string for use inside a quoted name
+fn quote(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if (b.is_ascii_graphic() && b != b'"' && b != b'\\') || b == b' ' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{:02x}", b));
+        }
+    }
+    out
+}
+
+enum Open {
+    If,
+    Block(usize),
+    Loop(usize),
+}
+
+fn body(out: &mut String, code: &[Item], unit: &Unit) -> Result<(), EmitError> {
+    let mut open = Vec::<Open>::new();
+    let mut labels = 0;
+    let line = |out: &mut String, depth: usize, text: &str| {
+        writeln!(out, "{}{}", "  ".repeat(depth + 2), text).unwrap();
+    };
+
+    for item in code {
+        let depth = open.len();
+        let (index, op, arg) = match item {
+            Item::Op(i, op, arg) => (*i, *op, *arg),
+            Item::Call(_, name) => {
+                line(out, depth, &format!("call $fn_{}", emit::mangle(name)));
+                continue;
+            }
+            Item::CallExt(_, name) => {
+                let call = format!("call $ext_{}", emit::mangle(name));
+                line(
+                    out,
+                    depth,
+                    &format!("(global.set $sp ({} (global.get $sp)))", call),
+                );
+                continue;
+            }
+            Item::FnRef(_, name) => {
+                let handle = unit.handle(name).unwrap();
+                line(out, depth, &format!("(call $push (i32.const {}))", handle));
+                continue;
+            }
+        };
+        match op {
+            Op::Const | Op::Push => {
+                let value = arg.unwrap_or(0);
+                line(out, depth, &format!("(call $push (i32.const {}))", value));
+            }
+            Op::Pick | Op::Roll => {
+                let n = arg.unwrap_or(0);
+                line(out, depth, &format!("(call $op_{} (i32.const {}))", op, n));
+            }
+            Op::GetPid | Op::Child => line(out, depth, "(call $push (i32.const 0))"),
+            Op::CallIndirect => {
+                line(out, depth, "(call_indirect (type $fn) (call $pop))");
+            }
+            Op::Noop => (),
+            Op::If => {
+                line(out, depth, "call $cond");
+                line(out, depth, "if");
+                open.push(Open::If);
+            }
+            Op::Else => line(out, depth - 1, "else"),
+            Op::Block => {
+                labels += 1;
+                line(out, depth, &format!("block $b{}", labels));
+                open.push(Open::Block(labels));
+            }
+            Op::Loop => {
+                labels += 1;
+                line(out, depth, &format!("block $b{}", labels));
+                line(out, depth, &format!("loop $l{}", labels));
+                open.push(Open::Loop(labels));
+            }
+            Op::EndIf | Op::EndBlock => {
+                open.pop();
+                line(out, depth - 1, "end");
+            }
+            Op::EndLoop => {
+                if let Some(Open::Loop(n)) = open.pop() {
+                    line(out, depth, &format!("br $l{}", n));
+                }
+                line(out, depth - 1, "end");
+                line(out, depth - 1, "end");
+            }
+            Op::Break => {
+                let target = open.iter().rev().find_map(|o| match o {
+                    Open::Block(n) | Open::Loop(n) => Some(*n),
+                    Open::If => None,
+                });
+                match target {
+                    Some(n) => line(out, depth, &format!("br $b{}", n)),
+                    // Outside of any loop a `break` ends the routine
+                    None => line(out, depth, "return"),
+                }
+            }
+            Op::Return => line(out, depth, "return"),
+            _ if has_helper(op) => line(out, depth, &format!("call $op_{}", op)),
+            _ => return Err(EmitError::Unsupported(index, op)),
+        }
+    }
+    Ok(())
+}
+
+fn external_functions<'a>(code: &'a [Item], names: &mut BTreeSet<&'a str>) {
+    for item in code {
+        if let Item::CallExt(_, name) = item {
+            names.insert(name);
+        }
+    }
+}
+
+pub fn emit_wat(program: &Program) -> Result<String, EmitError> {
+    let unit = emit::prepare(program)?;
+    let mut out = String::from("(module\n");
+
+    out.push_str("  (import \"env\" \"print\" (func $print (param i32)))\n");
+    out.push_str("  (import \"env\" \"printstr\" (func $printstr (param i32 i32)))\n");
+    out.push_str("  (import \"env\" \"dbg\" (func $dbg (param i32 i32)))\n");
+    // Only programs that print wide values need the host to print them
+    let mut wide_prints = String::new();
+    for (op, ty) in [(Op::I64Print, "i64"), (Op::FPrint, "f64")] {
+        if !unit.uses(op) {
+            continue;
+        }
+        writeln!(
+            out,
+            "  (import \"env\" \"print_{ty}\" (func $print_{ty} (param {ty})))"
+        )
+        .unwrap();
+        let (pop, push) = match ty {
+            "i64" => ("$pop64", "$push64"),
+            _ => ("$popf", "$pushf"),
+        };
+        write!(
+            wide_prints,
+            "\n  (func $op_{op} (local $v {ty})\n    (local.set $v (call {pop}))\n    (call $print_{ty} (local.get $v))\n    (call {push} (local.get $v)))\n"
+        )
+        .unwrap();
+    }
+    let mut externals = BTreeSet::new();
+    external_functions(&unit.main, &mut externals);
+    for code in unit.functions.values() {
+        external_functions(code, &mut externals);
+    }
+    for name in externals {
+        writeln!(
+            out,
+            "  (import \"ext\" \"{}\" (func $ext_{} (param i32) (result i32)))",
+            quote(name),
+            emit::mangle(name)
+        )
+        .unwrap();
+    }
+    out.push('\n');
+    out.push_str(&runtime());
+    out.push_str(&wide_prints);
+
+    // Function handles index the table; entry 0 is left empty so that
+    // `call.indirect` of 0 traps
+    if unit.uses(Op::CallIndirect) {
+        out.push_str("\n  (type $fn (func))\n");
+        writeln!(out, "  (table {} funcref)", unit.functions.len() + 1).unwrap();
+        out.push_str("  (elem (i32.const 1) func");
+        for name in unit.functions.keys() {
+            write!(out, " $fn_{}", emit::mangle(name)).unwrap();
+        }
+        out.push_str(")\n");
+    }
+
+    for (name, code) in &unit.functions {
+        writeln!(out, "\n  ;; {}", quote(name)).unwrap();
+        writeln!(out, "  (func $fn_{}", emit::mangle(name)).unwrap();
+        body(&mut out, code, &unit)?;
+        out.push_str("  )\n");
+    }
+
+    out.push_str("\n  (func $run (export \"run\")\n");
+    body(&mut out, &unit.main, &unit)?;
+    out.push_str("  )\n)\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod emit_wat_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+    use crate::stackmachine::StackMachine;
+    use std::collections::{HashMap, HashSet};
+
+    // Every parenthesis is matched and every `$name` referenced is defined
+    // somewhere in the module
+    fn validate(wat: &str) {
+        let mut depth = 0i32;
+        let mut quoted = false;
+        let mut defined = HashSet::new();
+        let mut used = Vec::new();
+        for line in wat.lines() {
+            let code = line.split(";;").next().unwrap();
+            for c in code.chars() {
+                match c {
+                    '"' => quoted = !quoted,
+                    '(' if !quoted => depth += 1,
+                    ')' if !quoted => depth -= 1,
+                    _ => (),
+                }
+                assert!(depth >= 0, "unbalanced: {}", line);
+            }
+            let words: Vec<&str> = code
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .filter(|w| !w.is_empty())
+                .collect();
+            for pair in words.windows(2) {
+                if pair[1].starts_with('$') {
+                    match pair[0] {
+                        "func" | "global" | "local" | "param" | "block" | "loop" | "type" => {
+                            defined.insert(pair[1]);
+                        }
+                        _ => used.push(pair[1]),
+                    }
+                }
+            }
+        }
+        assert_eq!(0, depth);
+        for name in used {
+            assert!(defined.contains(name), "{} is not defined", name);
+        }
+    }
+
+    /*
+     * Reads the code of the emitted functions back into opcodes, which is
+     * enough to run the translation on the interpreter. Functions keep their
+     * mangled names.
+     */
+    fn read_back(wat: &str) -> Program {
+        let mut functions = HashMap::new();
+        let mut current: Option<(String, Vec<_>)> = None;
+        let mut open = Vec::new();
+        let mut lines = wat.lines().map(str::trim).peekable();
+
+        while let Some(line) = lines.next() {
+            if let Some(name) = line.strip_prefix("(func $fn_") {
+                current = Some((name.to_string(), Vec::new()));
+                continue;
+            }
+            if line.starts_with("(func $run") {
+                current = Some(("".to_string(), Vec::new()));
+                continue;
+            }
+            let code = match current.as_mut() {
+                Some((_, code)) => code,
+                None => continue,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [")"] => {
+                    let (name, code) = current.take().unwrap();
+                    functions.insert(name, code);
+                }
+                ["(call", f, "(i32.const", n] => {
+                    let op = match f.strip_prefix("$op_") {
+                        Some(mnemonic) => mnemonic.parse().unwrap(),
+                        None => Op::Const,
+                    };
+                    code.push((op, Some(n.trim_end_matches(')').parse().unwrap())))
+                }
+                ["call", "$cond"] => (),
+                ["if"] => {
+                    code.push((Op::If, None));
+                    open.push(Op::EndIf);
+                }
+                ["else"] => code.push((Op::Else, None)),
+                ["block", _] if lines.peek().is_some_and(|l| l.starts_with("loop")) => {
+                    lines.next();
+                    code.push((Op::Loop, None));
+                    open.push(Op::EndLoop);
+                }
+                ["block", _] => {
+                    code.push((Op::Block, None));
+                    open.push(Op::EndBlock);
+                }
+                ["br", label] if label.starts_with("$l") => {
+                    // The jump back at the end of a loop, and the end of the
+                    // outer block that follows it
+                    lines.next();
+                    lines.next();
+                    open.pop();
+                    code.push((Op::EndLoop, None));
+                }
+                ["br", _] => code.push((Op::Break, None)),
+                ["end"] => code.push((open.pop().unwrap(), None)),
+                ["return"] => code.push((Op::Return, None)),
+                ["call", f] if f.starts_with("$fn_") => {
+                    code.push((Op::Const, Some(0)));
+                    for c in f["$fn_".len()..].chars().rev() {
+                        code.push((Op::Const, Some(c as i32)));
+                    }
+                    code.push((Op::Call, None));
+                }
+                ["call", f] => code.push((f["$op_".len()..].parse().unwrap(), None)),
+                _ => panic!("unexpected line {}", line),
+            }
+        }
+
+        let mut program = Program::new(functions.remove("").unwrap());
+        program.functions = functions.into_iter().collect();
+        program
+    }
+
+    const CASES: &[&str] = &[
+        "const 2\nconst 3\nadd\nconst 4\nmul\nconst 1\nsub\nconst 2\ndiv",
+        "const 5\nconst 4\neq\nif\nconst 1\nelse\nconst 0\nendif\nnot",
+        "const 1\nconst 2\nconst 3\nloop\nconst 1\nif\nbreak\nendif\nendloop\nblock\nbreak\nendblock",
+        "const 42\nconst 8\nstore\nconst 8\nload",
+        "const 1\nconst 2\nconst 3\nrot\nover\nswap\ndup",
+        "const 1\nconst 2\nconst 3\npick 2\nroll 3\ntuck\nnip\ndepth\nclear\nconst 4\ndepth",
+        "f.const 2.5\nf.const -1e3\nf.div\nf.const 4\nf.sqrt\nf.mul\nf2l\ni64.const -3\ni64.mul\ni64.const 7\ni64.lt\nf.const 1e30\nf2i\nconst 9\ni2f\nf.neg\nf2i\ni2l\nl2f\nf2l\nl2i",
+        "const 1\nconst 2147483647\nadd\nconst -1\nswap\ndiv\nconst 3\nconst -7\nmod\nneg\nabs\nconst 12\nconst 10\nxor\nconst 33\nconst -8\nsar\nconst 2\nmax\nmul.checked",
+        "const 0\npushstr f_x\nfunction\nconst 1\nif\nconst 3\nmul\nreturn\nendif\nendfunction\nconst 2\nconst 0\npushstr f_x\ncall",
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for case in CASES {
+            let program = reader::parse(case, "t.sm");
+            let wat = emit_wat(&program).unwrap();
+            validate(&wat);
+
+            let mut expected = StackMachine::new(2u32.pow(16));
+            expected.execute_program(&program);
+            let mut actual = StackMachine::new(2u32.pow(16));
+            actual.execute_program(&read_back(&wat));
+
+            assert_eq!(expected.stack, actual.stack, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let program = reader::load(path.to_str().unwrap()).unwrap();
+            if let Ok(wat) = emit_wat(&program) {
+                validate(&wat);
+                read_back(&wat);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_imports() {
+        let wat = emit_wat(&reader::parse("f.const 1.5\nf.print", "t.sm")).unwrap();
+
+        assert!(wat.contains(r#"(import "env" "print_f64" (func $print_f64 (param f64)))"#));
+        assert!(!wat.contains("print_i64"));
+        validate(&wat);
+    }
+
+    #[test]
+    fn test_imports() {
+        let program = reader::parse("const 0\npushstr my ext\ncallext", "t.sm");
+        let wat = emit_wat(&program).unwrap();
+
+        assert!(wat
+            .contains(r#"(import "ext" "my ext" (func $ext_my_20ext (param i32) (result i32)))"#));
+        assert!(wat.contains("(global.set $sp (call $ext_my_20ext (global.get $sp)))"));
+        validate(&wat);
+    }
+
+    #[test]
+    fn test_function_table() {
+        let program = reader::parse(
+            "const 0\npushstr f\nfunction\nendfunction\nfnref f\ncall.indirect",
+            "t.sm",
+        );
+        let wat = emit_wat(&program).unwrap();
+
+        assert!(wat.contains("(table 2 funcref)\n  (elem (i32.const 1) func $fn_f)"));
+        assert!(wat.contains("(call $push (i32.const 1))"));
+        assert!(wat.contains("(call_indirect (type $fn) (call $pop))"));
+        validate(&wat);
+    }
+
+    #[test]
+    fn test_no_string_heap() {
+        let program = reader::parse("str.const a\nprintstr", "t.sm");
+
+        assert_eq!(
+            Err(EmitError::Unsupported(2, Op::StrNew)),
+            emit_wat(&program)
+        );
+    }
+}
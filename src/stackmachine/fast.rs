@@ -0,0 +1,785 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+use crate::stackmachine::check::{self, Diagnostic};
+use crate::stackmachine::program::Program;
+use crate::stackmachine::sink::{self, Sink};
+use crate::stackmachine::{arith, heap, wide, Heap, Op, Trap};
+
+/*
+ * A faster execution engine for programs the checker can fully verify.
+ *
+ * Structured control flow is compiled down to a flat stream of fixed-size
+ * instructions with resolved jump targets, function names are resolved to
+ * addresses ahead of time instead of being popped off the stack as strings on
+ * every call, and a few common sequences are fused into single
+ * superinstructions.
+ *
+ * Programs that fork, call external functions, call through function
+ * references or build function names at run time are not supported;
+ * `StackMachine::execute` still runs those.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Insn {
+    Const(i32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    GT,
+    LT,
+    GTE,
+    LTE,
+    Not,
+    Pop,
+    Dup,
+    Swap,
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    Pick(u32),
+    Roll(u32),
+    Depth,
+    Clear,
+    // Any other arithmetic, with the shared semantics in `arith`
+    Binary(Op),
+    Unary(Op),
+    // A 64-bit or float opcode, run by `wide`, and their print opcodes
+    Wide(Op),
+    WidePrint(Op),
+    // A string or array opcode, run by the machine's `Heap`
+    Heap(Op),
+    Alloc(i32),
+    Print,
+    PrintStr,
+    PrintChars,
+    Debug,
+    Jump(u32),
+    // Pop a condition and jump unless it is positive
+    JumpIfNot(u32),
+    Call(u32),
+    Ret,
+
+    // Superinstructions
+    ConstAdd(i32),
+    ConstSub(i32),
+    ConstMul(i32),
+    // `eq` followed by `if`: pop two values and jump unless they are equal
+    EqJumpIfNot(u32),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    // The checker could not verify the program
+    Unverified(Vec<Diagnostic>),
+    Unsupported(usize, Op),
+    // A `call` or `function` whose name is not a string literal
+    DynamicName(usize),
+    UndefinedFunction(usize, String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Unverified(diagnostics) if diagnostics.is_empty() => {
+                write!(f, "program's stack effect is not statically known")
+            }
+            CompileError::Unverified(diagnostics) => {
+                write!(f, "program failed verification: {}", diagnostics[0])
+            }
+            CompileError::Unsupported(i, op) => {
+                write!(
+                    f,
+                    "instruction {}: `{}` is not supported by the fast engine",
+                    i, op
+                )
+            }
+            CompileError::DynamicName(i) => {
+                write!(
+                    f,
+                    "instruction {}: function name is not a string literal",
+                    i
+                )
+            }
+            CompileError::UndefinedFunction(i, name) => {
+                write!(f, "instruction {}: function `{}` is not defined", i, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// Compiled program: main code starts at 0 and ends in `Ret`, functions follow
+#[derive(Clone, Debug)]
+pub struct FastProgram {
+    pub code: Vec<Insn>,
+}
+
+enum Frame {
+    If {
+        jump: usize,
+        skip_else: Option<usize>,
+    },
+    Loop {
+        start: usize,
+        breaks: Vec<usize>,
+    },
+    Block {
+        breaks: Vec<usize>,
+    },
+}
+
+struct Compiler {
+    out: Vec<Insn>,
+    // Position of the most recent jump target; instructions may not be fused
+    // across it
+    label: usize,
+    functions: HashMap<String, u32>,
+    // Function bodies waiting to be appended after the main code
+    pending: Vec<Vec<(Op, Option<i32>)>>,
+}
+
+impl Compiler {
+    fn bind(&mut self) -> usize {
+        self.label = self.out.len();
+        self.label
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        let target = target as u32;
+        self.out[at] = match self.out[at] {
+            Insn::Jump(_) => Insn::Jump(target),
+            Insn::JumpIfNot(_) => Insn::JumpIfNot(target),
+            Insn::EqJumpIfNot(_) => Insn::EqJumpIfNot(target),
+            other => other,
+        };
+    }
+
+    // The instruction just emitted, if it can be fused with the next one
+    fn fusable(&self) -> Option<Insn> {
+        if self.out.len() > self.label {
+            self.out.last().copied()
+        } else {
+            None
+        }
+    }
+
+    fn emit(&mut self, insn: Insn) {
+        let fused = match (self.fusable(), insn) {
+            (Some(Insn::Const(k)), Insn::Add) => Some(Insn::ConstAdd(k)),
+            (Some(Insn::Const(k)), Insn::Sub) => Some(Insn::ConstSub(k)),
+            (Some(Insn::Const(k)), Insn::Mul) => Some(Insn::ConstMul(k)),
+            (Some(Insn::Eq), Insn::JumpIfNot(t)) => Some(Insn::EqJumpIfNot(t)),
+            _ => None,
+        };
+        match fused {
+            Some(f) => *self.out.last_mut().unwrap() = f,
+            None => self.out.push(insn),
+        }
+    }
+
+    // Takes back the `const` instructions of a just-emitted string literal
+    fn literal(&mut self, index: usize) -> Result<String, CompileError> {
+        let mut name = String::new();
+        while self.out.len() > self.label {
+            match self.out.pop() {
+                Some(Insn::Const(0)) => return Ok(name),
+                Some(Insn::Const(c)) => name.push(c as u8 as char),
+                _ => break,
+            }
+        }
+        Err(CompileError::DynamicName(index))
+    }
+
+    fn compile(&mut self, code: &[(Op, Option<i32>)]) -> Result<(), CompileError> {
+        let mut frames = Vec::<Frame>::new();
+        let mut index = 0;
+        while index < code.len() {
+            let (op, arg) = code[index];
+            match op {
+                Op::Const | Op::Push => self.emit(Insn::Const(arg.unwrap_or(0))),
+                Op::Add => self.emit(Insn::Add),
+                Op::Sub => self.emit(Insn::Sub),
+                Op::Mul => self.emit(Insn::Mul),
+                Op::Div => self.emit(Insn::Div),
+                Op::r#Eq => self.emit(Insn::Eq),
+                Op::GT => self.emit(Insn::GT),
+                Op::LT => self.emit(Insn::LT),
+                Op::GTE => self.emit(Insn::GTE),
+                Op::LTE => self.emit(Insn::LTE),
+                Op::Not => self.emit(Insn::Not),
+                Op::Pop => self.emit(Insn::Pop),
+                Op::Dup => self.emit(Insn::Dup),
+                Op::Swap => self.emit(Insn::Swap),
+                Op::Over => self.emit(Insn::Over),
+                Op::Rot => self.emit(Insn::Rot),
+                Op::Nip => self.emit(Insn::Nip),
+                Op::Tuck => self.emit(Insn::Tuck),
+                // The checker has rejected negative operands
+                Op::Pick => self.emit(Insn::Pick(arg.unwrap() as u32)),
+                Op::Roll => self.emit(Insn::Roll(arg.unwrap() as u32)),
+                Op::Depth => self.emit(Insn::Depth),
+                Op::Clear => self.emit(Insn::Clear),
+                _ if arith::binary(op, 0, 1).is_some() => self.emit(Insn::Binary(op)),
+                _ if arith::unary(op, 0).is_some() => self.emit(Insn::Unary(op)),
+                Op::I64Print | Op::FPrint => self.emit(Insn::WidePrint(op)),
+                _ if wide::is_wide(op) => self.emit(Insn::Wide(op)),
+                Op::Print => self.emit(Insn::Print),
+                _ if heap::is_heap(op) => self.emit(Insn::Heap(op)),
+                Op::Alloc => self.emit(Insn::Alloc(arg.unwrap())),
+                Op::PrintStr => self.emit(Insn::PrintStr),
+                Op::PrintChars => self.emit(Insn::PrintChars),
+                Op::Debug => self.emit(Insn::Debug),
+                Op::Noop => (),
+                Op::If => {
+                    self.emit(Insn::JumpIfNot(0));
+                    frames.push(Frame::If {
+                        jump: self.out.len() - 1,
+                        skip_else: None,
+                    });
+                }
+                Op::Else => {
+                    self.emit(Insn::Jump(0));
+                    let at = self.out.len() - 1;
+                    let target = self.bind();
+                    if let Some(Frame::If { jump, skip_else }) = frames.last_mut() {
+                        let jump = *jump;
+                        *skip_else = Some(at);
+                        self.patch(jump, target);
+                    }
+                }
+                Op::EndIf => {
+                    let target = self.bind();
+                    if let Some(Frame::If { jump, skip_else }) = frames.pop() {
+                        match skip_else {
+                            Some(at) => self.patch(at, target),
+                            None => self.patch(jump, target),
+                        }
+                    }
+                }
+                Op::Loop => {
+                    let start = self.bind();
+                    frames.push(Frame::Loop {
+                        start,
+                        breaks: Vec::new(),
+                    });
+                }
+                Op::Block => frames.push(Frame::Block { breaks: Vec::new() }),
+                Op::Break => {
+                    let innermost = frames.iter_mut().rev().find_map(|f| match f {
+                        Frame::Loop { breaks, .. } | Frame::Block { breaks } => Some(breaks),
+                        Frame::If { .. } => None,
+                    });
+                    match innermost {
+                        Some(breaks) => {
+                            breaks.push(self.out.len());
+                            self.out.push(Insn::Jump(0));
+                        }
+                        // Outside any loop a `break` ends the routine
+                        None => self.out.push(Insn::Ret),
+                    }
+                }
+                Op::EndLoop | Op::EndBlock => {
+                    let breaks = match frames.pop() {
+                        Some(Frame::Loop { start, breaks }) => {
+                            self.out.push(Insn::Jump(start as u32));
+                            breaks
+                        }
+                        Some(Frame::Block { breaks }) => breaks,
+                        _ => Vec::new(),
+                    };
+                    let target = self.bind();
+                    for at in breaks {
+                        self.patch(at, target);
+                    }
+                }
+                Op::Return => self.out.push(Insn::Ret),
+                Op::Function => {
+                    if !frames.is_empty() {
+                        return Err(CompileError::Unsupported(index, op));
+                    }
+                    let name = self.literal(index)?;
+                    let end = (index + 1..code.len())
+                        .find(|i| code[*i].0 == Op::EndFunction)
+                        .unwrap_or(code.len());
+                    if self.functions.contains_key(&name) {
+                        return Err(CompileError::Unsupported(index, op));
+                    }
+                    let id = self.pending.len() as u32;
+                    self.functions.insert(name, id);
+                    self.pending.push(code[index + 1..end].to_vec());
+                    index = end;
+                }
+                Op::Call => {
+                    let name = self.literal(index)?;
+                    match self.functions.get(&name).copied() {
+                        // Patched to the function's real address once it is laid out
+                        Some(id) => self.out.push(Insn::Call(id)),
+                        None => return Err(CompileError::UndefinedFunction(index, name)),
+                    }
+                }
+                _ => return Err(CompileError::Unsupported(index, op)),
+            }
+            index += 1;
+        }
+        self.out.push(Insn::Ret);
+        Ok(())
+    }
+}
+
+pub fn compile(program: &Program) -> Result<FastProgram, CompileError> {
+    let analysis = check::check(program);
+    if !analysis.is_verified() {
+        return Err(CompileError::Unverified(analysis.diagnostics));
+    }
+
+    let mut compiler = Compiler {
+        out: Vec::new(),
+        label: 0,
+        functions: HashMap::new(),
+        pending: Vec::new(),
+    };
+    for (name, body) in &program.functions {
+        let id = compiler.pending.len() as u32;
+        compiler.functions.insert(name.clone(), id);
+        compiler.pending.push(body.clone());
+    }
+    compiler.compile(&program.code)?;
+
+    // Lay out function bodies after the main code. Bodies can define and
+    // call functions of their own, so keep going until none are left.
+    let mut addresses = Vec::new();
+    while addresses.len() < compiler.pending.len() {
+        let body = compiler.pending[addresses.len()].clone();
+        addresses.push(compiler.out.len() as u32);
+        compiler.bind();
+        compiler.compile(&body)?;
+    }
+    for insn in compiler.out.iter_mut() {
+        if let Insn::Call(id) = insn {
+            *insn = Insn::Call(addresses[*id as usize]);
+        }
+    }
+    Ok(FastProgram { code: compiler.out })
+}
+
+pub struct FastMachine {
+    pub stack: Vec<i32>,
+    pub heap: Heap,
+    pub output: Sink,
+}
+
+impl Default for FastMachine {
+    fn default() -> Self {
+        FastMachine::new()
+    }
+}
+
+impl FastMachine {
+    pub fn new() -> FastMachine {
+        FastMachine {
+            stack: Vec::with_capacity(1024),
+            heap: Heap::new(),
+            output: sink::stdout(),
+        }
+    }
+
+    pub fn set_output<W: Write + Send + 'static>(&mut self, output: W) {
+        self.output = sink::sink(output);
+    }
+
+    // The checker has already ruled out underflow in the code it verifies,
+    // but opcodes like `depth` and `printchars` depend on values it cannot
+    // know, so the stack is still checked
+    #[inline(always)]
+    fn pop(&mut self, op: Op) -> Result<i32, Trap> {
+        self.stack.pop().ok_or(Trap::StackUnderflow(op))
+    }
+
+    #[inline(always)]
+    fn top(&self, op: Op) -> Result<i32, Trap> {
+        self.stack.last().copied().ok_or(Trap::StackUnderflow(op))
+    }
+
+    // The fast engine has no memory, so the stack is the only root
+    fn collect_if_needed(&mut self) {
+        if self.heap.wants_collection() {
+            self.heap.collect(self.stack.iter().copied());
+        }
+    }
+
+    // Stops at the first trap, like `StackMachine::try_execute`
+    pub fn run(&mut self, program: &FastProgram) -> Result<(), Trap> {
+        let code = &program.code[..];
+        let mut returns = Vec::<usize>::new();
+        let mut pc = 0;
+        loop {
+            let insn = code[pc];
+            pc += 1;
+            match insn {
+                Insn::Const(v) => self.stack.push(v),
+                Insn::Add => {
+                    let a = self.pop(Op::Add)?;
+                    let b = self.pop(Op::Add)?;
+                    self.stack.push(a.wrapping_add(b));
+                }
+                Insn::Sub => {
+                    let a = self.pop(Op::Sub)?;
+                    let b = self.pop(Op::Sub)?;
+                    self.stack.push(a.wrapping_sub(b));
+                }
+                Insn::Mul => {
+                    let a = self.pop(Op::Mul)?;
+                    let b = self.pop(Op::Mul)?;
+                    self.stack.push(a.wrapping_mul(b));
+                }
+                Insn::Div => {
+                    let a = self.pop(Op::Div)?;
+                    let b = self.pop(Op::Div)?;
+                    if b == 0 {
+                        return Err(Trap::DivideByZero(Op::Div));
+                    }
+                    self.stack.push(a.wrapping_div(b));
+                }
+                Insn::Binary(op) => {
+                    let a = self.pop(op)?;
+                    let b = self.pop(op)?;
+                    self.stack.push(arith::binary(op, a, b).unwrap()?);
+                }
+                Insn::Unary(op) => {
+                    let a = self.pop(op)?;
+                    self.stack.push(arith::unary(op, a).unwrap()?);
+                }
+                Insn::Wide(op) => wide::execute(op, &mut self.stack).unwrap()?,
+                Insn::WidePrint(op) => sink::write_line(
+                    &self.output,
+                    format_args!("{}", wide::format_top(op, &self.stack)),
+                ),
+                Insn::Heap(op) => {
+                    self.heap.execute(op, &mut self.stack).unwrap()?;
+                    self.collect_if_needed();
+                }
+                Insn::Alloc(n) => {
+                    let handle = self.heap.new_array(Op::Alloc, n)?;
+                    self.stack.push(handle);
+                    self.collect_if_needed();
+                }
+                Insn::Eq | Insn::GT | Insn::LT | Insn::GTE | Insn::LTE => {
+                    let op = match insn {
+                        Insn::Eq => Op::r#Eq,
+                        Insn::GT => Op::GT,
+                        Insn::LT => Op::LT,
+                        Insn::GTE => Op::GTE,
+                        _ => Op::LTE,
+                    };
+                    let a = self.pop(op)?;
+                    let b = self.pop(op)?;
+                    let result = arith::compare(op, a, b).unwrap();
+                    self.stack.push(result as i32);
+                }
+                Insn::Not => {
+                    let a = self.pop(Op::Not)?;
+                    self.stack.push((a <= 0) as i32);
+                }
+                Insn::Pop => {
+                    self.pop(Op::Pop)?;
+                }
+                Insn::Dup => self.stack.push(self.top(Op::Dup)?),
+                Insn::Swap => {
+                    let a = self.pop(Op::Swap)?;
+                    let b = self.pop(Op::Swap)?;
+                    self.stack.push(a);
+                    self.stack.push(b);
+                }
+                Insn::Over => {
+                    let a = self.pop(Op::Over)?;
+                    let b = self.top(Op::Over)?;
+                    self.stack.push(a);
+                    self.stack.push(b);
+                }
+                Insn::Rot => {
+                    let a = self.pop(Op::Rot)?;
+                    let b = self.pop(Op::Rot)?;
+                    let c = self.pop(Op::Rot)?;
+                    self.stack.push(b);
+                    self.stack.push(a);
+                    self.stack.push(c);
+                }
+                Insn::Nip => {
+                    let a = self.pop(Op::Nip)?;
+                    self.pop(Op::Nip)?;
+                    self.stack.push(a);
+                }
+                Insn::Tuck => {
+                    let a = self.pop(Op::Tuck)?;
+                    let b = self.pop(Op::Tuck)?;
+                    self.stack.push(a);
+                    self.stack.push(b);
+                    self.stack.push(a);
+                }
+                Insn::Pick(n) => {
+                    let v = self.stack[self.stack.len() - 1 - n as usize];
+                    self.stack.push(v);
+                }
+                Insn::Roll(n) => {
+                    let v = self.stack.remove(self.stack.len() - 1 - n as usize);
+                    self.stack.push(v);
+                }
+                Insn::Depth => self.stack.push(self.stack.len() as i32),
+                Insn::Clear => self.stack.clear(),
+                Insn::Print => {
+                    sink::write_line(&self.output, format_args!("{}", self.top(Op::Print)?))
+                }
+                Insn::PrintStr => {
+                    let handle = self.pop(Op::PrintStr)?;
+                    let s = self
+                        .heap
+                        .string(handle)
+                        .ok_or(Trap::BadHandle(Op::PrintStr))?;
+                    sink::write_line(&self.output, format_args!("{}", s));
+                }
+                Insn::PrintChars => {
+                    let mut s = String::new();
+                    loop {
+                        match self.pop(Op::PrintChars)? {
+                            0 => break,
+                            c => s.push(c as u8 as char),
+                        }
+                    }
+                    sink::write_line(&self.output, format_args!("{}", s));
+                }
+                Insn::Debug if self.heap.stats.allocated > 0 => sink::write_line(
+                    &self.output,
+                    format_args!("DEBUG::StackMachine<0, {:?}> {}", self.stack, self.heap),
+                ),
+                Insn::Debug => sink::write_line(
+                    &self.output,
+                    format_args!("DEBUG::StackMachine<0, {:?}>", self.stack),
+                ),
+                Insn::Jump(t) => pc = t as usize,
+                Insn::JumpIfNot(t) => {
+                    if self.pop(Op::If)? <= 0 {
+                        pc = t as usize;
+                    }
+                }
+                Insn::Call(t) => {
+                    returns.push(pc);
+                    pc = t as usize;
+                }
+                Insn::Ret => match returns.pop() {
+                    Some(r) => pc = r,
+                    None => return Ok(()),
+                },
+                Insn::ConstAdd(k) => {
+                    let b = self.pop(Op::Add)?;
+                    self.stack.push(k.wrapping_add(b));
+                }
+                Insn::ConstSub(k) => {
+                    let b = self.pop(Op::Sub)?;
+                    self.stack.push(k.wrapping_sub(b));
+                }
+                Insn::ConstMul(k) => {
+                    let b = self.pop(Op::Mul)?;
+                    self.stack.push(k.wrapping_mul(b));
+                }
+                Insn::EqJumpIfNot(t) => {
+                    let a = self.pop(Op::r#Eq)?;
+                    let b = self.pop(Op::r#Eq)?;
+                    if a != b {
+                        pc = t as usize;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod fast_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+    use crate::stackmachine::sink::Capture;
+    use crate::stackmachine::StackMachine;
+
+    // The stack and output of the interpreter and of the fast engine
+    fn both(src: &str) -> ((Vec<i32>, String), (Vec<i32>, String)) {
+        let program = reader::parse(src, "t.sm");
+
+        let mut sm = StackMachine::new(2u32.pow(8));
+        let output = Capture::new();
+        sm.set_output(output.clone());
+        sm.execute_program(&program);
+
+        let mut fast = FastMachine::new();
+        let fast_output = Capture::new();
+        fast.set_output(fast_output.clone());
+        fast.run(&compile(&program).unwrap()).unwrap();
+
+        (
+            (sm.stack, output.contents()),
+            (fast.stack, fast_output.contents()),
+        )
+    }
+
+    const CASES: &[&str] = &[
+        "const 2\nconst 3\nadd\nconst 4\nmul\nconst 1\nsub\nconst 2\ndiv",
+        "const 5\nconst 4\neq\nif\nconst 1\nelse\nconst 0\nendif",
+        "const 5\nconst 5\neq\nif\nconst 1\nelse\nconst 0\nendif",
+        "const 1\nif\nconst 0\nif\nconst 7\nelse\nconst 6\nendif\nelse\nconst 8\nendif\nconst 3\n\
+            const 4\nlte",
+        "block\nconst 1\nconst 1\nif\nbreak\nendif\npop\nconst 2\nendblock\nconst 3",
+        "const 3\nloop\nconst -1\nadd\nconst 1\nif\nbreak\nendif\nendloop",
+        "const 0\npushstr f\nfunction\nconst 1\nif\nconst 3\nmul\nreturn\nendif\nendfunction\n\
+            const 2\nconst 0\npushstr f\ncall\nconst 0\npushstr f\ncall",
+        "const 0\nnot\nconst 3\nnot\nconst 9\npop",
+        "const 1\nconst 2\nconst 3\nrot\nover\nswap\ndup",
+        "const 1\nconst 2\nconst 3\npick 2\nroll 3\ntuck\nnip\ndepth\nclear\nconst 4\ndepth",
+        "f.const 2.5\nf.const -1e3\nf.div\nf.print\ni64.const 3\ni64.const 9000000000\ni64.mul\n\
+            i64.print\nl2f\nf.sqrt\nf2i\ni2l\ni64.const 7\ni64.gt",
+        "const 1\nconst 2147483647\nadd\nconst 3\nconst -7\nmod\nneg\nconst 12\nconst 10\nxor\n\
+            const 1\nswap\nsar\nabs.checked",
+        "const 3\nconst 1\nstr.const hello world\nstr.slice\nstr.const x=\nstr.concat\ndup\n\
+            printstr\nstr.len\nconst -5\nint->str\nstr.len\nconst 0\npushstr hi\nprintchars",
+        "const 4\narray.new\nconst 9\nconst 2\npick 2\narray.set\nconst 2\nover\narray.get\nover\n\
+            array.len\nrot\nfree\nalloc 2\narray.len\ndbg",
+    ];
+
+    #[test]
+    fn test_matches_interpreter() {
+        for case in CASES {
+            let (expected, actual) = both(case);
+            assert_eq!(expected, actual, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_traps() {
+        let cases = [
+            ("const 0\nconst 1\ndiv", Trap::DivideByZero(Op::Div)),
+            (
+                "const 2\nconst 2147483647\nmul.checked",
+                Trap::Overflow(Op::MulChecked),
+            ),
+            (
+                "const 5\nstr.const abc\nstr.at",
+                Trap::OutOfRange(Op::StrAt),
+            ),
+        ];
+        for (source, trap) in cases {
+            let program = compile(&reader::parse(source, "t.sm")).unwrap();
+
+            assert_eq!(Err(trap), FastMachine::new().run(&program), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let program = reader::parse(
+            "const 1\nconst 2\nadd\nconst 2\neq\nif\nconst 1\nelse\nconst 0\nendif",
+            "t.sm",
+        );
+        let fast = compile(&program).unwrap();
+
+        assert_eq!(
+            vec![
+                Insn::Const(1),
+                Insn::ConstAdd(2),
+                Insn::Const(2),
+                Insn::EqJumpIfNot(6),
+                Insn::Const(1),
+                Insn::Jump(7),
+                Insn::Const(0),
+                Insn::Ret
+            ],
+            fast.code
+        );
+    }
+
+    #[test]
+    fn test_no_fusion_across_labels() {
+        let program = reader::parse(
+            "const 0\nconst 1\nloop\nadd\nconst 0\nif\nbreak\nendif\nendloop",
+            "t.sm",
+        );
+        let fast = compile(&program).unwrap_err();
+
+        // The loop body is unbalanced, so this never gets as far as fusion
+        assert!(matches!(fast, CompileError::Unverified(_)));
+
+        let program = reader::parse(
+            "const 5\nloop\nconst 1\nadd\nconst 1\nif\nbreak\nendif\nendloop",
+            "t.sm",
+        );
+        let fast = compile(&program).unwrap();
+
+        assert_eq!(Insn::Const(5), fast.code[0]);
+        assert_eq!(Insn::ConstAdd(1), fast.code[1]);
+    }
+
+    #[test]
+    fn test_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let program = reader::load(path.to_str().unwrap()).unwrap();
+            if let Ok(compiled) = compile(&program) {
+                let mut sm = StackMachine::new(2u32.pow(8));
+                sm.execute_program(&program);
+                let mut fast = FastMachine::new();
+                fast.run(&compiled).unwrap();
+
+                assert_eq!(sm.stack, fast.stack, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_unverified() {
+        let program = reader::parse("const 1\nadd", "t.sm");
+
+        assert!(matches!(
+            compile(&program),
+            Err(CompileError::Unverified(_))
+        ));
+
+        let program = reader::parse("fork", "t.sm");
+
+        assert_eq!(
+            Err(CompileError::Unsupported(0, Op::Fork)),
+            compile(&program).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_loop_with_unknown_height() {
+        // `depth` makes the loop body's height unknown to the checker, and
+        // `printchars` would pop past the bottom of the stack
+        let program = reader::parse(
+            "const 7\nconst 1\nloop\nif\nconst 5\ndepth\nprintchars\nconst 0\nelse\nbreak\nendif\n\
+                endloop\npop",
+            "t.sm",
+        );
+
+        assert!(matches!(
+            compile(&program),
+            Err(CompileError::Unverified(_))
+        ));
+    }
+
+    #[test]
+    fn test_underflow_traps() {
+        let program = FastProgram {
+            code: vec![Insn::Const(0), Insn::PrintChars, Insn::Pop, Insn::Ret],
+        };
+
+        assert_eq!(
+            Err(Trap::StackUnderflow(Op::Pop)),
+            FastMachine::new().run(&program)
+        );
+    }
+}
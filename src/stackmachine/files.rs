@@ -0,0 +1,292 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::stackmachine::function::Exec;
+use crate::stackmachine::heap::{Heap, Object};
+use crate::stackmachine::{Op, Trap};
+
+/*
+ * Files a program may use. Nothing is reachable until the host grants a
+ * directory with `StackMachine::allow` (or `--allow-read` and
+ * `--allow-write` on the command line), much like WASI's preopened
+ * directories. A path is resolved, following `..` and symbolic links, before
+ * it is checked, so a program cannot climb out of what it was given, and a
+ * path outside every grant fails with `DENIED` without being opened.
+ *
+ * `file.open` pops a path (a string handle) then a mode, 0 to read, 1 to
+ * write from the start and 2 to append, and pushes a descriptor. Files are
+ * read and written a line at a time: `file.read` pops a descriptor and pushes
+ * the next line and 1, or an empty string and 0 at the end of the file, and
+ * `file.write` pops a descriptor and a string and writes it as a line.
+ * `file.close` pops a descriptor. Where an opcode fails it pushes one of the
+ * negative codes below in place of the descriptor or status, so a program can
+ * check for them or `throw` them.
+ */
+
+pub const DENIED: i32 = -7;
+pub const FILE_ERROR: i32 = -8;
+pub const BAD_DESCRIPTOR: i32 = -9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+enum OpenFile {
+    Reader(BufReader<File>),
+    Writer(File),
+}
+
+#[derive(Default)]
+pub struct Files {
+    // Canonical directories and what may be done under them
+    grants: Vec<(PathBuf, Access)>,
+    // Indexed by descriptor; closed descriptors are reused
+    open: Vec<Option<OpenFile>>,
+}
+
+impl Files {
+    pub fn new() -> Files {
+        Files::default()
+    }
+
+    // Fails if `dir` does not exist, rather than granting nothing quietly
+    pub fn allow<P: AsRef<Path>>(&mut self, dir: P, access: Access) -> io::Result<()> {
+        let dir = dir.as_ref().canonicalize()?;
+        self.grants.push((dir, access));
+        Ok(())
+    }
+
+    fn resolve(&self, path: &str, access: Access) -> Result<PathBuf, i32> {
+        let path = Path::new(path);
+        let name = path.file_name().ok_or(DENIED)?;
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        let mut resolved = parent.canonicalize().map_err(|_| DENIED)?.join(name);
+        // The file itself may be a link to somewhere else
+        if resolved.symlink_metadata().is_ok() {
+            resolved = resolved.canonicalize().map_err(|_| DENIED)?;
+        }
+        if self
+            .grants
+            .iter()
+            .any(|(dir, a)| *a == access && resolved.starts_with(dir))
+        {
+            Ok(resolved)
+        } else {
+            Err(DENIED)
+        }
+    }
+
+    pub fn open(&mut self, path: &str, mode: i32) -> i32 {
+        let access = if mode == 0 {
+            Access::Read
+        } else {
+            Access::Write
+        };
+        let path = match self.resolve(path, access) {
+            Ok(path) => path,
+            Err(code) => return code,
+        };
+        let file = match mode {
+            0 => File::open(path).map(|f| OpenFile::Reader(BufReader::new(f))),
+            1 => File::create(path).map(OpenFile::Writer),
+            _ => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .map(OpenFile::Writer),
+        };
+        let file = match file {
+            Ok(file) => Some(file),
+            Err(_) => return FILE_ERROR,
+        };
+        match self.open.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.open[fd] = file;
+                fd as i32
+            }
+            None => {
+                self.open.push(file);
+                self.open.len() as i32 - 1
+            }
+        }
+    }
+
+    fn file(&mut self, fd: i32) -> Option<&mut OpenFile> {
+        if fd < 0 {
+            return None;
+        }
+        self.open.get_mut(fd as usize)?.as_mut()
+    }
+
+    // The next line without its line ending, or `None` at the end of the file
+    pub fn read_line(&mut self, fd: i32) -> Result<Option<String>, i32> {
+        let reader = match self.file(fd) {
+            Some(OpenFile::Reader(reader)) => reader,
+            _ => return Err(BAD_DESCRIPTOR),
+        };
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+            }
+            Err(_) => Err(FILE_ERROR),
+        }
+    }
+
+    pub fn write_line(&mut self, fd: i32, line: &str) -> i32 {
+        match self.file(fd) {
+            Some(OpenFile::Writer(file)) => match writeln!(file, "{}", line) {
+                Ok(()) => 0,
+                Err(_) => FILE_ERROR,
+            },
+            _ => BAD_DESCRIPTOR,
+        }
+    }
+
+    pub fn close(&mut self, fd: i32) -> i32 {
+        match self.file(fd) {
+            Some(_) => {
+                self.open[fd as usize] = None;
+                0
+            }
+            None => BAD_DESCRIPTOR,
+        }
+    }
+}
+
+// Whether `execute` runs `op`
+pub fn is_file(op: Op) -> bool {
+    op.info().exec == Exec::File
+}
+
+// Runs a file opcode on the stack, or returns `None` if `op` is not one
+pub fn execute(
+    files: &Mutex<Files>,
+    heap: &mut Heap,
+    op: Op,
+    stack: &mut Vec<i32>,
+) -> Option<Result<(), Trap>> {
+    if !is_file(op) {
+        return None;
+    }
+    let mut files = files.lock().unwrap();
+    let a = stack.pop().unwrap();
+    match op {
+        Op::FileOpen => {
+            let mode = stack.pop().unwrap();
+            if !(0..=2).contains(&mode) {
+                return Some(Err(Trap::OutOfRange(op)));
+            }
+            let path = match heap.string(a) {
+                Some(path) => path,
+                None => return Some(Err(Trap::BadHandle(op))),
+            };
+            let fd = files.open(path, mode);
+            stack.push(fd);
+        }
+        Op::FileRead => {
+            let (line, status) = match files.read_line(a) {
+                Ok(Some(line)) => (line, 1),
+                Ok(None) => (String::new(), 0),
+                Err(code) => (String::new(), code),
+            };
+            stack.push(heap.alloc(Object::Str(line)));
+            stack.push(status);
+        }
+        Op::FileWrite => {
+            let line = stack.pop().unwrap();
+            let line = match heap.string(line) {
+                Some(line) => line,
+                None => return Some(Err(Trap::BadHandle(op))),
+            };
+            let status = files.write_line(a, line);
+            stack.push(status);
+        }
+        _ => stack.push(files.close(a)),
+    }
+    Some(Ok(()))
+}
+
+#[cfg(test)]
+mod files_test {
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stackmachine-files-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = scratch("round-trip");
+        let path = dir.join("data/out.txt");
+        let path = path.to_str().unwrap();
+        let mut files = Files::new();
+        files.allow(dir.join("data"), Access::Read).unwrap();
+        files.allow(dir.join("data"), Access::Write).unwrap();
+
+        let fd = files.open(path, 1);
+        assert_eq!(0, fd);
+        assert_eq!(0, files.write_line(fd, "one"));
+        assert_eq!(0, files.close(fd));
+        let fd = files.open(path, 2);
+        assert_eq!(0, files.write_line(fd, "two"));
+        assert_eq!(0, files.close(fd));
+
+        let fd = files.open(path, 0);
+        assert_eq!(Ok(Some("one".to_string())), files.read_line(fd));
+        assert_eq!(Ok(Some("two".to_string())), files.read_line(fd));
+        assert_eq!(Ok(None), files.read_line(fd));
+        assert_eq!(BAD_DESCRIPTOR, files.write_line(fd, "three"));
+        assert_eq!(0, files.close(fd));
+        assert_eq!(BAD_DESCRIPTOR, files.close(fd));
+    }
+
+    #[test]
+    fn test_denied() {
+        let dir = scratch("denied");
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let mut files = Files::new();
+        files.allow(dir.join("data"), Access::Read).unwrap();
+
+        let inside = dir.join("data/../secret.txt");
+        assert_eq!(DENIED, files.open(inside.to_str().unwrap(), 0));
+        let outside = dir.join("secret.txt");
+        assert_eq!(DENIED, files.open(outside.to_str().unwrap(), 0));
+        // Granted for reading only
+        let new = dir.join("data/new.txt");
+        assert_eq!(DENIED, files.open(new.to_str().unwrap(), 1));
+        assert!(!new.exists());
+        assert_eq!(FILE_ERROR, files.open(new.to_str().unwrap(), 0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_out_of_grant() {
+        let dir = scratch("link");
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("data/link")).unwrap();
+        let mut files = Files::new();
+        files.allow(dir.join("data"), Access::Read).unwrap();
+
+        let link = dir.join("data/link");
+        assert_eq!(DENIED, files.open(link.to_str().unwrap(), 0));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Canonical formatting for `.sm` source. Works line by line so that comments
+ * and blank lines survive: mnemonics are lowercased, the words the reader
+ * sees are separated by single spaces, blocks are indented two spaces per
+ * level and runs of blank lines are collapsed to one.
+ */
+
+use crate::stackmachine::{disasm, reader, Op};
+
+const INDENT: &str = "  ";
+
+// Keywords that close a block before their own line is printed
+fn dedents(mnemonic: &str) -> bool {
+    mnemonic.parse::<Op>().is_ok_and(disasm::closes_block)
+}
+
+// Keywords whose following lines are one level deeper
+fn indents(mnemonic: &str) -> bool {
+    mnemonic.parse::<Op>().is_ok_and(disasm::opens_block)
+}
+
+pub fn format_source(source: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0usize;
+    let mut pending_blank = false;
+
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            // Only keep a blank line once there is something before it
+            pending_blank = !out.is_empty();
+            continue;
+        }
+        if pending_blank {
+            out.push('\n');
+            pending_blank = false;
+        }
+
+        if line.starts_with('#') {
+            out.push_str(&INDENT.repeat(depth));
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+
+        let words = reader::words(line);
+        let mnemonic = words[0].to_ascii_lowercase();
+        if dedents(&mnemonic) {
+            depth = depth.saturating_sub(1);
+        }
+
+        out.push_str(&INDENT.repeat(depth));
+        out.push_str(&mnemonic);
+        for word in &words[1..] {
+            out.push(' ');
+            out.push_str(word);
+        }
+        out.push('\n');
+
+        if indents(&mnemonic) {
+            depth += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod formatter_test {
+
+    use super::format_source;
+    use crate::stackmachine::reader;
+
+    #[test]
+    fn test_indentation() {
+        let source = "CONST 1\nif\nconst 2\n  if\n      dbg\nendif\nElse\nconst   3\nendif\n";
+        let expected = "const 1\nif\n  const 2\n  if\n    dbg\n  endif\nelse\n  const 3\nendif\n";
+
+        assert_eq!(expected, format_source(source));
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() {
+        let source = "\n\n# header\nconst 1\n\n\n\n   # indented comment\npushstr a   b\n\n";
+        let expected = "# header\nconst 1\n\n# indented comment\npushstr a b\n";
+
+        assert_eq!(expected, format_source(source));
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = "loop\nblock\nbreak\nendblock\nendloop\n";
+        let expected = "loop\n  block\n    break\n  endblock\nendloop\n";
+
+        assert_eq!(expected, format_source(source));
+    }
+
+    #[test]
+    fn test_same_program() {
+        // Tabs inside a line belong to the word they are in, as for the reader
+        let source = "\tPUSHSTR a\tb   c\n\n\nprintchars\nstr.const x\t y \n";
+        let formatted = format_source(source);
+        let mut before = reader::parse(source, "a.sm");
+        let mut after = reader::parse(&formatted, "a.sm");
+        // Collapsing blank lines moves the spans
+        before.debug = None;
+        after.debug = None;
+
+        assert_eq!("pushstr a\tb c\n\nprintchars\nstr.const x\t y\n", formatted);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let source = "pushstr f\nfunction\nadd\n# body\nendfunction\n";
+        let once = format_source(source);
+
+        assert_eq!(once, format_source(&once));
+    }
+
+    #[test]
+    fn test_examples_formatted() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            let formatted = format_source(&source);
+
+            assert_eq!(source, formatted, "{} is not formatted", path.display());
+            assert_eq!(
+                reader::parse(&source, "a.sm").code,
+                reader::parse(&formatted, "b.sm").code
+            );
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+
+use crate::stackmachine::lang::{LangError, Pos};
+use crate::stackmachine::program::{DebugInfo, Program, Span};
+use crate::stackmachine::Op;
+
+/*
+ * A core Forth subset, so that classic Forth exercises run on the machine:
+ *
+ *   : square ( n -- n*n ) dup * ;
+ *   : squares 5 0 do i square . loop ;
+ *   squares ." done"
+ *
+ * Words are case insensitive and may be redefined. Supported are numbers,
+ * `+ - * / mod negate abs min max`, `and or xor lshift rshift`,
+ * `= <> < > 0= 0< 0>`, `dup drop swap over rot nip tuck 2dup depth`,
+ * `. emit cr .s`, `." text"`, `: name ... ;` with `recurse` and `exit`,
+ * `if else then`, `begin until`, `begin again`, `begin while repeat`,
+ * `do loop` with `i`, `j` and `leave`, and `variable name` with `@` and `!`.
+ * Comments are `( ... )` and `\` to the end of the line.
+ *
+ * Differences from standard Forth: flags are 1 and 0 rather than -1 and 0
+ * (conditionals accept any non-zero value), and output is line based, so
+ * `.`, `emit` and `."` each end their line and `cr` does nothing.
+ *
+ * `do` loops keep their index and limit in memory: word 0 points past the top
+ * of that loop stack, variables follow it and the loop stack comes last.
+ */
+
+// Memory word holding the top of the loop stack
+const RP: i32 = 0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Control {
+    If,
+    Else,
+    Begin,
+    While,
+    Do,
+}
+
+struct Compiler {
+    code: Vec<(Op, Option<i32>)>,
+    spans: Vec<Span>,
+    pos: Pos,
+    words: HashMap<String, ()>,
+    variables: HashMap<String, i32>,
+    control: Vec<(Control, Pos)>,
+    // Name of the word being defined
+    defining: Option<String>,
+    uses_loops: bool,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, arg: Option<i32>) {
+        self.code.push((op, arg));
+        self.spans.push(Span {
+            file: 0,
+            line: self.pos.line,
+            column: self.pos.column,
+        });
+    }
+
+    fn ops(&mut self, ops: &[Op]) {
+        for op in ops {
+            self.emit(*op, None);
+        }
+    }
+
+    fn constant(&mut self, n: i32) {
+        self.emit(Op::Const, Some(n));
+    }
+
+    fn string(&mut self, s: &str) {
+        self.constant(0);
+        for c in s.chars().rev() {
+            self.constant(c as i32);
+        }
+    }
+
+    // Turns a flag where any non-zero value is true into the 1 or 0 `if` expects
+    fn flag(&mut self) {
+        self.constant(0);
+        self.ops(&[Op::r#Eq, Op::Not]);
+    }
+
+    // Pushes the address of a word `offset` bytes from the top of the loop stack
+    fn loop_slot(&mut self, offset: i32) {
+        self.constant(RP);
+        self.emit(Op::Load, None);
+        self.constant(offset);
+        self.emit(Op::Add, None);
+    }
+
+    fn open(&mut self, control: Control) {
+        self.control.push((control, self.pos));
+    }
+
+    fn close(&mut self, word: &str, expected: &[Control]) -> Result<Control, LangError> {
+        match self.control.pop() {
+            Some((c, _)) if expected.contains(&c) => Ok(c),
+            _ => Err(LangError {
+                pos: self.pos,
+                message: format!("`{}` without a matching start", word),
+            }),
+        }
+    }
+
+    fn word(&mut self, word: &str) -> Result<(), LangError> {
+        let lower = word.to_ascii_lowercase();
+        if self.words.contains_key(&lower) {
+            self.string(&lower);
+            self.emit(Op::Call, None);
+            return Ok(());
+        }
+        if let Some(address) = self.variables.get(&lower).copied() {
+            self.constant(address);
+            return Ok(());
+        }
+        match lower.as_str() {
+            "+" => self.ops(&[Op::Add]),
+            "-" => self.ops(&[Op::Swap, Op::Sub]),
+            "*" => self.ops(&[Op::Mul]),
+            "/" => self.ops(&[Op::Swap, Op::Div]),
+            "=" => self.ops(&[Op::r#Eq]),
+            "<>" => self.ops(&[Op::r#Eq, Op::Not]),
+            // The machine compares the top of the stack against the value under it
+            "<" => self.ops(&[Op::GT]),
+            ">" => self.ops(&[Op::LT]),
+            "0=" => {
+                self.constant(0);
+                self.ops(&[Op::r#Eq]);
+            }
+            "0<" => {
+                self.constant(0);
+                self.ops(&[Op::GT]);
+            }
+            "0>" => {
+                self.constant(0);
+                self.ops(&[Op::LT]);
+            }
+            "mod" => self.ops(&[Op::Swap, Op::Mod]),
+            "negate" => self.ops(&[Op::Neg]),
+            "abs" => self.ops(&[Op::Abs]),
+            "min" => self.ops(&[Op::Min]),
+            "max" => self.ops(&[Op::Max]),
+            "and" => self.ops(&[Op::And]),
+            "or" => self.ops(&[Op::Or]),
+            "xor" => self.ops(&[Op::Xor]),
+            "lshift" => self.ops(&[Op::Swap, Op::Shl]),
+            "rshift" => self.ops(&[Op::Swap, Op::Shr]),
+            "true" => self.constant(1),
+            "false" => self.constant(0),
+            "dup" => self.ops(&[Op::Dup]),
+            "drop" => self.ops(&[Op::Pop]),
+            "swap" => self.ops(&[Op::Swap]),
+            "over" => self.ops(&[Op::Over]),
+            "rot" => self.ops(&[Op::Rot]),
+            "nip" => self.ops(&[Op::Nip]),
+            "tuck" => self.ops(&[Op::Tuck]),
+            "depth" => self.ops(&[Op::Depth]),
+            "2dup" => self.ops(&[Op::Over, Op::Over]),
+            "@" => self.ops(&[Op::Load]),
+            "!" => self.ops(&[Op::Store]),
+            "." => self.ops(&[Op::Print, Op::Pop]),
+            "emit" => {
+                self.constant(0);
+                self.ops(&[Op::Swap, Op::PrintChars]);
+            }
+            "cr" => (),
+            ".s" => self.ops(&[Op::Debug]),
+            "if" => {
+                self.flag();
+                self.ops(&[Op::If]);
+                self.open(Control::If);
+            }
+            "else" => {
+                self.close(word, &[Control::If])?;
+                self.ops(&[Op::Else]);
+                self.open(Control::Else);
+            }
+            "then" => {
+                self.close(word, &[Control::If, Control::Else])?;
+                self.ops(&[Op::EndIf]);
+            }
+            "begin" => {
+                self.ops(&[Op::Loop]);
+                self.open(Control::Begin);
+            }
+            "until" => {
+                self.close(word, &[Control::Begin])?;
+                self.flag();
+                self.ops(&[Op::If, Op::Break, Op::EndIf, Op::EndLoop]);
+            }
+            "again" => {
+                self.close(word, &[Control::Begin])?;
+                self.ops(&[Op::EndLoop]);
+            }
+            "while" => {
+                self.close(word, &[Control::Begin])?;
+                self.constant(0);
+                self.ops(&[Op::r#Eq, Op::If, Op::Break, Op::EndIf]);
+                self.open(Control::While);
+            }
+            "repeat" => {
+                self.close(word, &[Control::While])?;
+                self.ops(&[Op::EndLoop]);
+            }
+            "do" => {
+                // ( limit start -- ) pushed onto the loop stack as start, limit
+                self.uses_loops = true;
+                self.loop_slot(0);
+                self.ops(&[Op::Store]);
+                self.loop_slot(4);
+                self.ops(&[Op::Store]);
+                self.loop_slot(8);
+                self.constant(RP);
+                self.ops(&[Op::Store, Op::Loop]);
+                self.open(Control::Do);
+            }
+            "loop" => {
+                self.close(word, &[Control::Do])?;
+                self.loop_slot(-8);
+                self.ops(&[Op::Load]);
+                self.constant(1);
+                self.ops(&[Op::Add]);
+                self.loop_slot(-8);
+                self.ops(&[Op::Store]);
+                // Leave once the index reaches the limit
+                self.loop_slot(-4);
+                self.ops(&[Op::Load]);
+                self.loop_slot(-8);
+                self.ops(&[Op::Load, Op::LT, Op::Not, Op::If, Op::Break, Op::EndIf]);
+                self.ops(&[Op::EndLoop]);
+                self.loop_slot(-8);
+                self.constant(RP);
+                self.ops(&[Op::Store]);
+            }
+            "i" | "j" => {
+                if self
+                    .innermost_do(if lower == "i" { 1 } else { 2 })
+                    .is_none()
+                {
+                    return self.fail(format!("`{}` outside of a `do` loop", word));
+                }
+                self.loop_slot(if lower == "i" { -8 } else { -16 });
+                self.ops(&[Op::Load]);
+            }
+            "leave" => {
+                // `break` leaves the innermost machine loop, which must be the `do`
+                match self
+                    .control
+                    .iter()
+                    .rev()
+                    .find(|(c, _)| *c != Control::If && *c != Control::Else)
+                {
+                    Some((Control::Do, _)) => self.ops(&[Op::Break]),
+                    _ => return self.fail("`leave` outside of a `do` loop".to_string()),
+                }
+            }
+            "recurse" => match self.defining.clone() {
+                Some(name) => {
+                    self.string(&name);
+                    self.ops(&[Op::Call]);
+                }
+                None => return self.fail("`recurse` outside of a definition".to_string()),
+            },
+            "exit" => {
+                if self.defining.is_none() {
+                    return self.fail("`exit` outside of a definition".to_string());
+                }
+                self.ops(&[Op::Return]);
+            }
+            _ => match parse_number(&lower) {
+                Some(n) => self.constant(n),
+                None => return self.fail(format!("unknown word `{}`", word)),
+            },
+        }
+        Ok(())
+    }
+
+    // The `n`th enclosing `do` loop, counting from 1
+    fn innermost_do(&self, n: usize) -> Option<usize> {
+        self.control
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, (c, _))| *c == Control::Do)
+            .nth(n - 1)
+            .map(|(i, _)| i)
+    }
+
+    fn fail<T>(&self, message: String) -> Result<T, LangError> {
+        Err(LangError {
+            pos: self.pos,
+            message,
+        })
+    }
+}
+
+fn parse_number(word: &str) -> Option<i32> {
+    match word.strip_prefix('$') {
+        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+// Splits source into words, with the position of each
+struct Words<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    pos: Pos,
+}
+
+impl<'a> Words<'a> {
+    fn advance(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    fn next_word(&mut self) -> Option<(String, Pos)> {
+        while self.chars.peek()?.is_whitespace() {
+            self.advance();
+        }
+        let pos = self.pos;
+        let mut word = String::new();
+        while let Some(c) = self.chars.peek().copied() {
+            if c.is_whitespace() {
+                break;
+            }
+            word.push(c);
+            self.advance();
+        }
+        Some((word, pos))
+    }
+
+    // Everything up to `end`, which is consumed but not returned
+    fn until(&mut self, end: char) -> Option<String> {
+        let mut text = String::new();
+        loop {
+            match self.advance()? {
+                c if c == end => return Some(text),
+                c => text.push(c),
+            }
+        }
+    }
+}
+
+pub fn compile(source: &str, filename: &str) -> Result<Program, LangError> {
+    let mut words = Words {
+        chars: source.chars().peekable(),
+        pos: Pos { line: 1, column: 1 },
+    };
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        spans: Vec::new(),
+        pos: words.pos,
+        words: HashMap::new(),
+        variables: HashMap::new(),
+        control: Vec::new(),
+        defining: None,
+        uses_loops: false,
+    };
+
+    while let Some((word, pos)) = words.next_word() {
+        compiler.pos = pos;
+        let unterminated = |what: &str| LangError {
+            pos,
+            message: format!("unterminated {}", what),
+        };
+        match word.to_ascii_lowercase().as_str() {
+            "(" => {
+                words.until(')').ok_or_else(|| unterminated("comment"))?;
+            }
+            "\\" => {
+                words.until('\n');
+            }
+            ".\"" => {
+                // The space that ends `."` is not part of the string
+                let text = words.until('"').ok_or_else(|| unterminated("string"))?;
+                compiler.string(text.strip_prefix(' ').unwrap_or(&text));
+                compiler.ops(&[Op::PrintChars]);
+            }
+            ":" => {
+                if compiler.defining.is_some() {
+                    return compiler.fail("definitions cannot be nested".to_string());
+                }
+                let name = match words.next_word() {
+                    Some((name, _)) => name.to_ascii_lowercase(),
+                    None => return compiler.fail("`:` needs a name".to_string()),
+                };
+                compiler.string(&name);
+                compiler.ops(&[Op::Function]);
+                compiler.defining = Some(name);
+            }
+            ";" => {
+                let name = match compiler.defining.take() {
+                    Some(name) => name,
+                    None => return compiler.fail("`;` outside of a definition".to_string()),
+                };
+                if let Some((_, pos)) = compiler.control.last() {
+                    return Err(LangError {
+                        pos: *pos,
+                        message: "block is not closed before `;`".to_string(),
+                    });
+                }
+                compiler.ops(&[Op::EndFunction]);
+                compiler.words.insert(name, ());
+            }
+            "variable" => {
+                let name = match words.next_word() {
+                    Some((name, _)) => name.to_ascii_lowercase(),
+                    None => return compiler.fail("`variable` needs a name".to_string()),
+                };
+                let address = 4 * (compiler.variables.len() as i32 + 1);
+                compiler.variables.insert(name, address);
+            }
+            _ => compiler.word(&word)?,
+        }
+    }
+    if let Some((_, pos)) = compiler.control.last() {
+        return Err(LangError {
+            pos: *pos,
+            message: "block is never closed".to_string(),
+        });
+    }
+    if compiler.defining.is_some() {
+        return compiler.fail("definition is never closed with `;`".to_string());
+    }
+
+    // The loop stack starts after the variables
+    let mut code = Vec::new();
+    let mut spans = Vec::new();
+    if compiler.uses_loops {
+        let start = 4 * (compiler.variables.len() as i32 + 1);
+        code = vec![
+            (Op::Const, Some(start)),
+            (Op::Const, Some(RP)),
+            (Op::Store, None),
+        ];
+        spans = vec![
+            Span {
+                file: 0,
+                line: 1,
+                column: 1
+            };
+            3
+        ];
+    }
+    code.append(&mut compiler.code);
+    spans.append(&mut compiler.spans);
+
+    let mut program = Program::new(code);
+    program.debug = Some(DebugInfo {
+        files: vec![filename.to_string()],
+        spans,
+    });
+    Ok(program)
+}
+
+#[cfg(test)]
+mod forth_test {
+
+    use super::*;
+    use crate::stackmachine::StackMachine;
+
+    fn run(source: &str) -> Vec<i32> {
+        let program = compile(source, "t.fs").unwrap();
+        let mut sm = StackMachine::new(2u32.pow(10));
+        sm.execute_program(&program);
+        sm.stack
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(vec![7, 2, 12, 2], run("10 3 - 4 2 / 3 4 * 7 3 /"));
+        assert_eq!(vec![1, 0, 1, 1, -5], run("1 2 < 1 2 > 3 3 = 0 0= 5 negate"));
+        assert_eq!(
+            vec![1, 3, 2, 12, 12, 4],
+            run("7 3 mod -3 abs 2 9 min 12 3 max 3 2 lshift 16 2 rshift")
+        );
+        assert_eq!(vec![2, 2, 2, 1], run("1 2 swap over 2dup nip rot"));
+    }
+
+    #[test]
+    fn test_definitions() {
+        let source = "
+            : square ( n -- n*n ) dup * ;
+            : fact ( n -- n! ) dup 1 > if dup 1 - recurse * then ;
+            3 square 5 fact
+        ";
+
+        assert_eq!(vec![9, 120], run(source));
+    }
+
+    #[test]
+    fn test_conditionals() {
+        assert_eq!(vec![2], run("-1 if 2 else 3 then"));
+        assert_eq!(vec![3], run("0 if 2 else 3 then"));
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(vec![0, 1, 2, 3], run("4 0 do i loop"));
+        assert_eq!(
+            vec![0, 11, 12, 21, 22],
+            run("0 3 1 do 3 1 do j 10 * i + loop loop")
+        );
+        assert_eq!(vec![0, 1, 2], run("10 0 do i dup 2 = if leave then loop"));
+        assert_eq!(vec![5, 4, 3, 2, 1, 0], run("5 begin dup 1 - dup 0= until"));
+        assert_eq!(vec![3, 2, 1, 0], run("3 begin dup while dup 1 - repeat"));
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            vec![42, 7],
+            run("variable x variable y 42 x ! 7 y ! x @ y @")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("1 2 frob", "1:5: unknown word `frob`"),
+            ("1 then", "1:3: `then` without a matching start"),
+            (": f 1 if ;", "1:7: block is not closed before `;`"),
+            ("i", "1:1: `i` outside of a `do` loop"),
+            ("( comment", "1:1: unterminated comment"),
+            (": f 1", "1:5: definition is never closed with `;`"),
+        ];
+        for (source, message) in cases.iter() {
+            let e = compile(source, "t.fs").unwrap_err();
+            assert_eq!(*message, e.to_string(), "{}", source);
+        }
+    }
+}
@@ -1,41 +1,390 @@
+use std::fmt;
+use std::str::FromStr;
+
 /*
- * All valid opcodes
+ * The one list of opcodes. Every other view of the instruction set (the enum
+ * itself, the metadata table, the reader's mnemonics, the disassembler and
+ * the `Builder` methods and the interpreter's dispatch) is generated from or
+ * looked up in it, so adding an opcode here is enough for it to show up
+ * everywhere.
+ *
+ * Each entry is:
+ *
+ *   Variant, "mnemonic", operand kind, (pops -> pushes) or (?), executor, builder method, "description";
+ *
+ * where `(?)` marks opcodes whose stack effect depends on the program (string
+ * arguments, calls) and the operand kind is one of `None`, `Int` or `Text`.
+ * `Text` operands only exist in source and are expanded by the reader. The
+ * executor is the `Exec` that runs the opcode.
+ *
+ * New opcodes go at the end: the discriminants are part of the `.smb` format.
  */
-#[derive(Clone, PartialEq, Copy, Debug)]
-#[allow(dead_code)]
-#[repr(u16)]
-pub enum Op {
-    Const,
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Print,
-    PrintStr,
-    Pop,
-    Push,
-    PushStr,
-    Noop,
-    Block,
-    Loop,
-    Return,
-    Break,
-    CallExt,
-    Call,
-    Fork,
-    If,
-    Else,
-    Not,
-    GT,
-    LT,
-    GTE,
-    LTE,
-    r#Eq,
-    EndIf,
-    Function,
-    EndFunction,
-    GetPid,
-    Child,
-    Debug,
-    Include,
+macro_rules! for_each_op {
+    ($m:ident) => {
+        $m! {
+            Const, "const", Int, (0 -> 1), Stack, r#const, "Push the operand";
+            Add, "add", None, (2 -> 1), Arith, add, "Pop a then b, push a + b, wrapping on overflow";
+            Sub, "sub", None, (2 -> 1), Arith, sub, "Pop a then b, push a - b, wrapping on overflow";
+            Mul, "mul", None, (2 -> 1), Arith, mul, "Pop a then b, push a * b, wrapping on overflow";
+            Div, "div", None, (2 -> 1), Arith, div, "Pop a then b, push a / b; trap if b is 0";
+            Print, "print", None, (1 -> 1), Vm, print, "Print the top of the stack without popping it";
+            PrintStr, "printstr", None, (1 -> 0), Vm, print_str, "Pop a string handle and print the string";
+            Pop, "pop", None, (1 -> 0), Stack, pop, "Discard the top of the stack";
+            Push, "push", Int, (0 -> 1), Stack, push, "Push the operand";
+            PushStr, "pushstr", Text, (?), Vm, pushstr, "Push a string, last character first";
+            Noop, "noop", None, (0 -> 0), Stack, noop, "Do nothing";
+            Block, "block", None, (0 -> 0), Vm, block, "Start a block that `break` leaves";
+            Loop, "loop", None, (0 -> 0), Vm, r#loop, "Start a block that repeats until `break`";
+            Return, "return", None, (0 -> 0), Vm, r#return, "Leave the current function";
+            Break, "break", None, (0 -> 0), Vm, r#break, "Leave the innermost `block` or `loop`";
+            CallExt, "callext", None, (?), Vm, call_ext, "Pop a name and call that external function";
+            Call, "call", None, (?), Vm, call, "Pop a name and call that function";
+            Fork, "fork", None, (0 -> 0), Vm, fork, "Run the rest of the block in a child as well";
+            If, "if", None, (1 -> 0), Vm, r#if, "Pop a condition and run the block if it is positive";
+            Else, "else", None, (0 -> 0), Vm, r#else, "Start the block run when the `if` condition is not";
+            Not, "not", None, (1 -> 1), Arith, not, "Pop a, push 1 if a is not positive, otherwise 0";
+            GT, "gt", None, (2 -> 1), Arith, gt, "Pop a then b, push 1 if a > b, otherwise 0";
+            LT, "lt", None, (2 -> 1), Arith, lt, "Pop a then b, push 1 if a < b, otherwise 0";
+            GTE, "gte", None, (2 -> 1), Arith, gte, "Pop a then b, push 1 if a >= b, otherwise 0";
+            LTE, "lte", None, (2 -> 1), Arith, lte, "Pop a then b, push 1 if a <= b, otherwise 0";
+            r#Eq, "eq", None, (2 -> 1), Arith, eq, "Pop a then b, push 1 if a == b, otherwise 0";
+            EndIf, "endif", None, (0 -> 0), Vm, end_if, "End an `if` block";
+            Function, "function", None, (?), Vm, function, "Pop a name and define it as the code up to `endfunction`";
+            EndFunction, "endfunction", None, (0 -> 0), Vm, end_function, "End a function definition";
+            GetPid, "getpid", None, (0 -> 1), Vm, get_pid, "Push this machine's PID";
+            Child, "child", None, (0 -> 1), Vm, child, "Push 1 in a forked child, otherwise 0";
+            Debug, "dbg", None, (0 -> 0), Vm, debug, "Print the machine's PID and stack";
+            Include, "include", Text, (?), Vm, include, "Read another source file in place";
+            EndBlock, "endblock", None, (0 -> 0), Vm, end_block, "End a `block`";
+            EndLoop, "endloop", None, (0 -> 0), Vm, end_loop, "Jump back to the start of the `loop`";
+            Load, "load", None, (1 -> 1), Vm, load, "Pop an address, push the 32-bit word stored there";
+            Store, "store", None, (2 -> 0), Vm, store, "Pop an address then a value, store the value there as a 32-bit word";
+            Dup, "dup", None, (1 -> 2), Stack, dup, "Push a copy of the top of the stack";
+            Swap, "swap", None, (2 -> 2), Stack, swap, "Exchange the top two values";
+            Over, "over", None, (2 -> 3), Stack, over, "Push a copy of the second value";
+            Rot, "rot", None, (3 -> 3), Stack, rot, "Move the third value to the top";
+            Nip, "nip", None, (2 -> 1), Stack, nip, "Discard the second value";
+            Tuck, "tuck", None, (2 -> 3), Stack, tuck, "Put a copy of the top value under the second";
+            Pick, "pick", Int, (?), Stack, pick, "Push a copy of the value n below the top; `pick 0` is `dup`";
+            Roll, "roll", Int, (?), Stack, roll, "Move the value n below the top to the top; `roll 2` is `rot`";
+            Depth, "depth", None, (0 -> 1), Stack, depth, "Push the number of values on the stack";
+            Clear, "clear", None, (?), Stack, clear, "Discard every value on the stack";
+            Mod, "mod", None, (2 -> 1), Arith, r#mod, "Pop a then b, push the remainder of a / b";
+            Neg, "neg", None, (1 -> 1), Arith, neg, "Pop a, push -a";
+            Abs, "abs", None, (1 -> 1), Arith, abs, "Pop a, push its absolute value";
+            Min, "min", None, (2 -> 1), Arith, min, "Pop a then b, push the smaller";
+            Max, "max", None, (2 -> 1), Arith, max, "Pop a then b, push the larger";
+            And, "and", None, (2 -> 1), Arith, and, "Pop a then b, push the bitwise and";
+            Or, "or", None, (2 -> 1), Arith, or, "Pop a then b, push the bitwise or";
+            Xor, "xor", None, (2 -> 1), Arith, xor, "Pop a then b, push the bitwise exclusive or";
+            Shl, "shl", None, (2 -> 1), Arith, shl, "Pop a then b, push a shifted left by b bits";
+            Shr, "shr", None, (2 -> 1), Arith, shr, "Pop a then b, push a shifted right by b bits, filling with zeros";
+            Sar, "sar", None, (2 -> 1), Arith, sar, "Pop a then b, push a shifted right by b bits, keeping the sign";
+            AddChecked, "add.checked", None, (2 -> 1), Arith, add_checked, "Like `add`, but trap on overflow";
+            SubChecked, "sub.checked", None, (2 -> 1), Arith, sub_checked, "Like `sub`, but trap on overflow";
+            MulChecked, "mul.checked", None, (2 -> 1), Arith, mul_checked, "Like `mul`, but trap on overflow";
+            DivChecked, "div.checked", None, (2 -> 1), Arith, div_checked, "Like `div`, but trap on overflow";
+            ModChecked, "mod.checked", None, (2 -> 1), Arith, mod_checked, "Like `mod`, but trap on overflow";
+            NegChecked, "neg.checked", None, (1 -> 1), Arith, neg_checked, "Like `neg`, but trap on overflow";
+            AbsChecked, "abs.checked", None, (1 -> 1), Arith, abs_checked, "Like `abs`, but trap on overflow";
+            I64Const, "i64.const", Text, (?), Vm, i64_const, "Push a 64-bit integer as two cells, high word on top";
+            FConst, "f.const", Text, (?), Vm, f_const, "Push a 64-bit float as two cells, high word on top";
+            I64Add, "i64.add", None, (4 -> 2), Wide, i64_add, "Pop 64-bit a then b, push a + b, wrapping on overflow";
+            I64Sub, "i64.sub", None, (4 -> 2), Wide, i64_sub, "Pop 64-bit a then b, push a - b, wrapping on overflow";
+            I64Mul, "i64.mul", None, (4 -> 2), Wide, i64_mul, "Pop 64-bit a then b, push a * b, wrapping on overflow";
+            I64Div, "i64.div", None, (4 -> 2), Wide, i64_div, "Pop 64-bit a then b, push a / b; trap if b is 0";
+            I64Mod, "i64.mod", None, (4 -> 2), Wide, i64_mod, "Pop 64-bit a then b, push the remainder of a / b";
+            I64Eq, "i64.eq", None, (4 -> 1), Wide, i64_eq, "Pop 64-bit a then b, push 1 if a == b, otherwise 0";
+            I64Lt, "i64.lt", None, (4 -> 1), Wide, i64_lt, "Pop 64-bit a then b, push 1 if a < b, otherwise 0";
+            I64Gt, "i64.gt", None, (4 -> 1), Wide, i64_gt, "Pop 64-bit a then b, push 1 if a > b, otherwise 0";
+            I64Print, "i64.print", None, (2 -> 2), Vm, i64_print, "Print the 64-bit integer on top without popping it";
+            FAdd, "f.add", None, (4 -> 2), Wide, f_add, "Pop float a then b, push a + b";
+            FSub, "f.sub", None, (4 -> 2), Wide, f_sub, "Pop float a then b, push a - b";
+            FMul, "f.mul", None, (4 -> 2), Wide, f_mul, "Pop float a then b, push a * b";
+            FDiv, "f.div", None, (4 -> 2), Wide, f_div, "Pop float a then b, push a / b";
+            FNeg, "f.neg", None, (2 -> 2), Wide, f_neg, "Pop float a, push -a";
+            FSqrt, "f.sqrt", None, (2 -> 2), Wide, f_sqrt, "Pop float a, push its square root";
+            FEq, "f.eq", None, (4 -> 1), Wide, f_eq, "Pop float a then b, push 1 if a == b, otherwise 0";
+            FLt, "f.lt", None, (4 -> 1), Wide, f_lt, "Pop float a then b, push 1 if a < b, otherwise 0";
+            FGt, "f.gt", None, (4 -> 1), Wide, f_gt, "Pop float a then b, push 1 if a > b, otherwise 0";
+            FPrint, "f.print", None, (2 -> 2), Vm, f_print, "Print the float on top without popping it";
+            I2F, "i2f", None, (1 -> 2), Wide, i2f, "Pop an integer, push it as a float";
+            F2I, "f2i", None, (2 -> 1), Wide, f2i, "Pop a float, push it truncated to an integer";
+            I2L, "i2l", None, (1 -> 2), Wide, i2l, "Pop an integer, push it as a 64-bit integer";
+            L2I, "l2i", None, (2 -> 1), Wide, l2i, "Pop a 64-bit integer, push its low 32 bits";
+            L2F, "l2f", None, (2 -> 2), Wide, l2f, "Pop a 64-bit integer, push it as a float";
+            F2L, "f2l", None, (2 -> 2), Wide, f2l, "Pop a float, push it truncated to a 64-bit integer";
+            StrNew, "str.new", None, (?), Heap, str_new, "Pop a null-terminated string of characters, push a handle to a copy";
+            StrConst, "str.const", Text, (?), Vm, str_const, "Push a handle to the string";
+            StrLen, "str.len", None, (1 -> 1), Heap, str_len, "Pop a string, push its length";
+            StrConcat, "str.concat", None, (2 -> 1), Heap, str_concat, "Pop strings a then b, push a followed by b";
+            StrEq, "str.eq", None, (2 -> 1), Heap, str_eq, "Pop strings a then b, push 1 if they are equal, otherwise 0";
+            StrAt, "str.at", None, (2 -> 1), Heap, str_at, "Pop a string then an index, push the character at that index";
+            StrSlice, "str.slice", None, (3 -> 1), Heap, str_slice, "Pop a string, a start and an end, push the characters from start up to end";
+            IntToStr, "int->str", None, (1 -> 1), Heap, int_to_str, "Pop a, push a as a decimal string";
+            PrintChars, "printchars", None, (?), Vm, print_chars, "Pop a null-terminated string of characters and print it";
+            Alloc, "alloc", Int, (0 -> 1), Vm, alloc, "Push a handle to a new array of n zeroed cells";
+            Free, "free", None, (1 -> 0), Heap, free, "Pop a handle and release the string or array at once";
+            ArrayNew, "array.new", None, (1 -> 1), Heap, array_new, "Pop a length, push a handle to a new array of that many zeroed cells";
+            ArrayGet, "array.get", None, (2 -> 1), Heap, array_get, "Pop an array then an index, push the cell at that index";
+            ArraySet, "array.set", None, (3 -> 0), Heap, array_set, "Pop an array, an index and a value, store the value at that index";
+            ArrayLen, "array.len", None, (1 -> 1), Heap, array_len, "Pop an array, push its length";
+            FnRef, "fnref", Text, (?), Vm, fn_ref, "Pop a function name, push a handle to that function";
+            CallIndirect, "call.indirect", None, (?), Vm, call_indirect, "Pop a function handle and call that function";
+            Closure, "closure", Int, (?), Vm, closure, "Pop a function handle and n values, push a closure capturing them";
+            CallClosure, "call.closure", None, (?), Vm, call_closure, "Pop a closure and call its function with its captured values";
+            EnvGet, "env.get", Int, (0 -> 1), Vm, env_get, "Push captured value n of the running closure";
+            Try, "try", None, (0 -> 0), Vm, r#try, "Run the block up to `catch`, and the rest with the error code if it fails";
+            Catch, "catch", None, (0 -> 0), Vm, catch, "Start the block run when the `try` block fails";
+            EndTry, "endtry", None, (0 -> 0), Vm, end_try, "End a `try` block";
+            Throw, "throw", None, (1 -> 0), Vm, throw, "Pop an error code and fail with it";
+            ReadInt, "read.int", None, (0 -> 1), Input, read_int, "Read a decimal integer from the input and push it";
+            ReadChar, "read.char", None, (0 -> 1), Input, read_char, "Read a character from the input and push it, or -1 at the end";
+            ReadLine, "read.line", None, (?), Input, read_line, "Read a line from the input and push it as a null-terminated string";
+            Eof, "eof", None, (0 -> 1), Input, eof, "Push 1 if the input has nothing left to read, otherwise 0";
+            FileOpen, "file.open", None, (2 -> 1), File, file_open, "Pop a path then a mode, push a descriptor for the file or an error code";
+            FileRead, "file.read", None, (1 -> 2), File, file_read, "Pop a descriptor, push the next line and 1, or an empty string and 0 at the end";
+            FileWrite, "file.write", None, (2 -> 1), File, file_write, "Pop a descriptor then a string, write the string as a line, push 0 or an error code";
+            FileClose, "file.close", None, (1 -> 1), File, file_close, "Pop a descriptor and close it, push 0 or an error code";
+            Argc, "argc", None, (0 -> 1), Vm, argc, "Push the number of program arguments, counting the program itself";
+            Argv, "argv", None, (1 -> 1), Vm, argv, "Pop an index, push that program argument as a string";
+            GetEnv, "getenv", None, (1 -> 2), Vm, getenv, "Pop a variable name, push its value and 1, or an empty string and 0 if it is not set";
+            Exit, "exit", None, (1 -> 0), Vm, exit, "Pop a status and end the program with it";
+        }
+    };
+}
+
+pub(crate) use for_each_op;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    Int,
+    // Source-only argument such as the string after `pushstr`
+    Text,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    // Values popped and then pushed
+    Fixed(usize, usize),
+    // Depends on the strings or functions involved
+    Dynamic,
+}
+
+/*
+ * Which code runs an opcode. Everything but `Vm` only needs the stack and one
+ * part of the machine, and has an `execute` function of its own that `run`
+ * hands it to; `Vm` opcodes (control flow, calls, output, memory) are run by
+ * the machine itself.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exec {
+    Vm,
+    // `StackMachine::stack_op`
+    Stack,
+    // `arith::execute`
+    Arith,
+    // `wide::execute`
+    Wide,
+    // `Heap::execute`
+    Heap,
+    // `input::execute`
+    Input,
+    // `files::execute`
+    File,
+}
+
+#[derive(Debug)]
+pub struct OpInfo {
+    pub op: Op,
+    pub mnemonic: &'static str,
+    pub operand: Operand,
+    pub effect: Effect,
+    pub exec: Exec,
+    pub description: &'static str,
+}
+
+macro_rules! effect {
+    (($pops:literal -> $pushes:literal)) => {
+        Effect::Fixed($pops, $pushes)
+    };
+    ((?)) => {
+        Effect::Dynamic
+    };
+}
+
+macro_rules! define_ops {
+    ($($op:ident, $mnemonic:literal, $operand:ident, $effect:tt, $exec:ident, $method:ident, $desc:literal;)*) => {
+        /*
+         * All valid opcodes
+         */
+        #[derive(Clone, PartialEq, Eq, Copy, Debug)]
+        #[repr(u16)]
+        pub enum Op {
+            $($op,)*
+        }
+
+        // Metadata for every opcode, indexed by its `repr(u16)` discriminant
+        pub const OPS: &[OpInfo] = &[
+            $(OpInfo {
+                op: Op::$op,
+                mnemonic: $mnemonic,
+                operand: Operand::$operand,
+                effect: effect!($effect),
+                exec: Exec::$exec,
+                description: $desc,
+            },)*
+        ];
+    };
+}
+
+for_each_op!(define_ops);
+
+impl Op {
+    pub fn info(self) -> &'static OpInfo {
+        &OPS[self as usize]
+    }
+
+    pub fn from_u16(n: u16) -> Option<Op> {
+        OPS.get(n as usize).map(|info| info.op)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseOpError(pub String);
+
+impl fmt::Display for ParseOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown mnemonic {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseOpError {}
+
+// Mnemonics are case insensitive
+impl FromStr for Op {
+    type Err = ParseOpError;
+
+    fn from_str(s: &str) -> Result<Op, ParseOpError> {
+        OPS.iter()
+            .find(|info| info.mnemonic.eq_ignore_ascii_case(s))
+            .map(|info| info.op)
+            .ok_or_else(|| ParseOpError(s.to_string()))
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.info().mnemonic)
+    }
+}
+
+#[cfg(test)]
+mod function_test {
+
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::stackmachine::files::{self, Files};
+    use crate::stackmachine::heap::{Heap, Object};
+    use crate::stackmachine::{arith, input, wide, StackMachine};
+
+    #[test]
+    fn test_table_order() {
+        for (i, info) in OPS.iter().enumerate() {
+            assert_eq!(i, info.op as usize);
+            assert_eq!(Some(info.op), Op::from_u16(i as u16));
+        }
+        assert_eq!(None, Op::from_u16(OPS.len() as u16));
+    }
+
+    #[test]
+    fn test_mnemonics_roundtrip() {
+        for info in OPS {
+            assert_eq!(Ok(info.op), info.op.to_string().parse());
+            assert_eq!(Ok(info.op), info.mnemonic.to_uppercase().parse());
+        }
+        assert_eq!(
+            Err(ParseOpError("frobnicate".to_string())),
+            "frobnicate".parse::<Op>()
+        );
+    }
+
+    // Each executor runs exactly the opcodes the table hands it
+    #[test]
+    fn test_executors_match_table() {
+        for info in OPS {
+            let mut heap = Heap::new();
+            heap.alloc(Object::Str("abcd".to_string()));
+            let files = Mutex::new(Files::new());
+            let source = input::source("12\n".as_bytes());
+            // Room for the widest operands, and a terminated string for `str.new`
+            let stack = vec![0, 4, 3, 2, 1];
+            let runs = [
+                (Exec::Arith, arith::execute(info.op, &mut stack.clone())),
+                (Exec::Wide, wide::execute(info.op, &mut stack.clone())),
+                (Exec::Heap, heap.execute(info.op, &mut stack.clone())),
+                (Exec::Input, input::execute(&source, info.op, &mut vec![])),
+                (
+                    Exec::File,
+                    files::execute(&files, &mut heap, info.op, &mut stack.clone()),
+                ),
+            ];
+            for (exec, result) in runs {
+                assert_eq!(info.exec == exec, result.is_some(), "{:?}", info.op);
+            }
+        }
+    }
+
+    // Structural opcodes only make sense alongside their partners, so they are
+    // covered by the control flow tests instead.
+    fn standalone(op: Op) -> bool {
+        !matches!(
+            op,
+            Op::If
+                | Op::Else
+                | Op::EndIf
+                | Op::EndFunction
+                | Op::Block
+                | Op::Loop
+                | Op::EndBlock
+                | Op::EndLoop
+                | Op::Fork
+                | Op::EnvGet
+                | Op::Try
+                | Op::Catch
+                | Op::EndTry
+                | Op::Throw
+        )
+    }
+
+    #[test]
+    fn test_fixed_effects_match_vm() {
+        for info in OPS.iter().filter(|i| standalone(i.op)) {
+            if let Effect::Fixed(pops, pushes) = info.effect {
+                let mut sm = StackMachine::new(2u32.pow(8));
+                sm.set_input("12\n".as_bytes());
+                sm.args = vec!["t.sm".to_string(), "a".to_string()];
+                // Enough for the two-cell operands of the wide opcodes, and
+                // valid handles and indices for the string ones
+                sm.stack = vec![4, 3, 2, 1];
+                let array = matches!(info.op, Op::ArrayGet | Op::ArraySet | Op::ArrayLen);
+                for _ in 0..4 {
+                    sm.heap.alloc(if array {
+                        Object::Array(vec![0; 4])
+                    } else {
+                        Object::Str("abcd".to_string())
+                    });
+                }
+                let arg = match info.operand {
+                    Operand::Int => Some(4),
+                    _ => None,
+                };
+                sm.execute(vec![(info.op, arg)]);
+
+                assert_eq!(4 - pops + pushes, sm.stack.len(), "{:?}", info.op);
+            }
+        }
+    }
 }
@@ -0,0 +1,398 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::stackmachine::function::Exec;
+use crate::stackmachine::{strings, Op, Trap};
+
+/*
+ * The managed heap behind strings and arrays. The stack holds handles to
+ * objects, which index a table starting at 1; the slot of an object that has
+ * been freed or collected is reused by a later allocation.
+ *
+ * `free` releases an object at once. Everything else is left to a
+ * mark-and-sweep collector, which runs between instructions once the number
+ * of live objects has doubled since the last collection. Values carry no type,
+ * so the collector is conservative: any value on the operand stack, in any
+ * word of memory or in a live array that equals the handle of a live object
+ * keeps it alive. Functions keep their values on the operand stack and
+ * globals live in memory, so that covers call frames and globals as well;
+ * the machine adds the closures that are running.
+ *
+ * Arrays hold 32-bit cells, start zeroed and cannot be resized; a length
+ * that is negative or over `MAX_ARRAY_LEN` traps rather than exhausting the
+ * host's memory. As with strings, an index outside an array traps, and so does
+ * a value that is not a handle to the right kind of object, which the
+ * accessors below answer with `None`.
+ */
+
+// Live objects below which the collector never runs
+const MIN_COLLECTION: usize = 64;
+
+// Cells in the largest array `array.new` and `alloc` make (64 MiB)
+pub const MAX_ARRAY_LEN: usize = 1 << 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Object {
+    Str(String),
+    Array(Vec<i32>),
+    // A function, by name, and the values `closure` captured for it
+    Closure(String, Vec<i32>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub allocated: u64,
+    // Released by `free`
+    pub freed: u64,
+    // Reclaimed by the collector
+    pub collected: u64,
+    pub collections: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free_slots: Vec<usize>,
+    live: usize,
+    next_collection: usize,
+    pub stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+// Whether `Heap::execute` runs `op`; `alloc` takes its size as an operand and
+// has `Heap::new_array` instead
+pub fn is_heap(op: Op) -> bool {
+    op.info().exec == Exec::Heap
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            live: 0,
+            next_collection: MIN_COLLECTION,
+            stats: GcStats::default(),
+        }
+    }
+
+    // Stores an object and returns its handle
+    pub fn alloc(&mut self, object: Object) -> i32 {
+        self.live += 1;
+        self.stats.allocated += 1;
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.objects[slot] = Some(object);
+                slot as i32 + 1
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.objects.len() as i32
+            }
+        }
+    }
+
+    // The slot of a live object
+    fn slot(&self, handle: i32) -> Option<usize> {
+        let slot = usize::try_from(handle).ok()?.checked_sub(1)?;
+        self.objects.get(slot)?.as_ref().map(|_| slot)
+    }
+
+    pub fn is_live(&self, handle: i32) -> bool {
+        self.slot(handle).is_some()
+    }
+
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    fn object(&self, handle: i32) -> Option<&Object> {
+        self.objects[self.slot(handle)?].as_ref()
+    }
+
+    pub fn string(&self, handle: i32) -> Option<&str> {
+        match self.object(handle)? {
+            Object::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn array(&self, handle: i32) -> Option<&[i32]> {
+        match self.object(handle)? {
+            Object::Array(cells) => Some(cells),
+            _ => None,
+        }
+    }
+
+    pub fn closure(&self, handle: i32) -> Option<(&str, &[i32])> {
+        match self.object(handle)? {
+            Object::Closure(function, env) => Some((function, env)),
+            _ => None,
+        }
+    }
+
+    fn array_mut(&mut self, handle: i32) -> Option<&mut Vec<i32>> {
+        let slot = self.slot(handle)?;
+        match self.objects[slot].as_mut()? {
+            Object::Array(cells) => Some(cells),
+            _ => None,
+        }
+    }
+
+    pub fn new_array(&mut self, op: Op, len: i32) -> Result<i32, Trap> {
+        match usize::try_from(len) {
+            Ok(len) if len <= MAX_ARRAY_LEN => Ok(self.alloc(Object::Array(vec![0; len]))),
+            _ => Err(Trap::OutOfRange(op)),
+        }
+    }
+
+    // Returns `None` if `handle` is not a live object
+    pub fn free(&mut self, handle: i32) -> Option<()> {
+        let slot = self.slot(handle)?;
+        self.release(slot);
+        self.stats.freed += 1;
+        Some(())
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.objects[slot] = None;
+        self.free_slots.push(slot);
+        self.live -= 1;
+    }
+
+    pub fn wants_collection(&self) -> bool {
+        self.live >= self.next_collection
+    }
+
+    /*
+     * Frees every object that cannot be reached from `roots`, following
+     * handles stored in arrays and closures.
+     */
+    pub fn collect<I: IntoIterator<Item = i32>>(&mut self, roots: I) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<i32> = roots.into_iter().collect();
+        while let Some(handle) = pending.pop() {
+            if let Some(slot) = self.slot(handle) {
+                if marked[slot] {
+                    continue;
+                }
+                marked[slot] = true;
+                match &self.objects[slot] {
+                    Some(Object::Array(cells)) | Some(Object::Closure(_, cells)) => {
+                        pending.extend(cells)
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for (slot, marked) in marked.into_iter().enumerate() {
+            if self.objects[slot].is_some() && !marked {
+                self.release(slot);
+                self.stats.collected += 1;
+            }
+        }
+        self.stats.collections += 1;
+        self.next_collection = MIN_COLLECTION.max(2 * self.live);
+    }
+
+    /*
+     * Runs a string or array opcode on the stack, or returns `None` if `op`
+     * is not one. `printstr` is left to the caller, which decides where output
+     * goes.
+     */
+    pub fn execute(&mut self, op: Op, stack: &mut Vec<i32>) -> Option<Result<(), Trap>> {
+        if let Some(result) = strings::execute(self, op, stack) {
+            return Some(result);
+        }
+        match op {
+            Op::Free | Op::ArrayNew | Op::ArrayGet | Op::ArraySet | Op::ArrayLen => {
+                Some(self.array_op(op, stack))
+            }
+            _ => None,
+        }
+    }
+
+    fn array_op(&mut self, op: Op, stack: &mut Vec<i32>) -> Result<(), Trap> {
+        let bad_handle = Trap::BadHandle(op);
+        match op {
+            Op::Free => {
+                let handle = stack.pop().unwrap();
+                self.free(handle).ok_or(bad_handle)?;
+            }
+            Op::ArrayNew => {
+                let len = stack.pop().unwrap();
+                stack.push(self.new_array(op, len)?);
+            }
+            Op::ArrayGet => {
+                let a = stack.pop().unwrap();
+                let i = stack.pop().unwrap();
+                let cells = self.array(a).ok_or(bad_handle)?;
+                match usize::try_from(i).ok().and_then(|i| cells.get(i)) {
+                    Some(v) => stack.push(*v),
+                    None => return Err(Trap::OutOfRange(op)),
+                }
+            }
+            Op::ArraySet => {
+                let a = stack.pop().unwrap();
+                let i = stack.pop().unwrap();
+                let v = stack.pop().unwrap();
+                let cells = self.array_mut(a).ok_or(bad_handle)?;
+                match usize::try_from(i).ok().and_then(|i| cells.get_mut(i)) {
+                    Some(cell) => *cell = v,
+                    None => return Err(Trap::OutOfRange(op)),
+                }
+            }
+            _ => {
+                let a = stack.pop().unwrap();
+                stack.push(self.array(a).ok_or(bad_handle)?.len() as i32);
+            }
+        }
+        Ok(())
+    }
+}
+
+// How `dbg` shows the heap
+impl fmt::Display for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Heap<live {}, allocated {}, freed {}, collected {}, collections {}>",
+            self.live,
+            self.stats.allocated,
+            self.stats.freed,
+            self.stats.collected,
+            self.stats.collections
+        )
+    }
+}
+
+#[cfg(test)]
+mod heap_test {
+
+    use super::*;
+
+    fn run(heap: &mut Heap, ops: &[Op], stack: &mut Vec<i32>) -> Result<(), Trap> {
+        for op in ops {
+            heap.execute(*op, stack).unwrap()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut heap = Heap::new();
+        let mut stack = vec![3];
+        run(&mut heap, &[Op::ArrayNew], &mut stack).unwrap();
+        let a = stack[0];
+        stack.extend([7, 2, a]);
+        run(&mut heap, &[Op::ArraySet], &mut stack).unwrap();
+        stack.extend([2, a]);
+        run(&mut heap, &[Op::ArrayGet], &mut stack).unwrap();
+        stack.push(a);
+        run(&mut heap, &[Op::ArrayLen], &mut stack).unwrap();
+
+        assert_eq!(vec![a, 7, 3], stack);
+        assert_eq!(Some(&[0, 0, 7][..]), heap.array(a));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut heap = Heap::new();
+        let a = heap.new_array(Op::Alloc, 2).unwrap();
+
+        let mut stack = vec![2, a];
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::ArrayGet)),
+            run(&mut heap, &[Op::ArrayGet], &mut stack)
+        );
+
+        let mut stack = vec![0, -1, a];
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::ArraySet)),
+            run(&mut heap, &[Op::ArraySet], &mut stack)
+        );
+
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::Alloc)),
+            heap.new_array(Op::Alloc, -1)
+        );
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::ArrayNew)),
+            heap.new_array(Op::ArrayNew, 2000000000)
+        );
+    }
+
+    #[test]
+    fn test_free_reuses_slot() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(Object::Array(vec![1]));
+        let b = heap.alloc(Object::Str("b".to_string()));
+        heap.free(a).unwrap();
+
+        assert!(!heap.is_live(a));
+        assert_eq!(a, heap.alloc(Object::Array(Vec::new())));
+        assert_eq!(Some("b"), heap.string(b));
+        assert_eq!(1, heap.stats.freed);
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut heap = Heap::new();
+        let inner = heap.alloc(Object::Str("kept".to_string()));
+        let outer = heap.alloc(Object::Array(vec![inner]));
+        let garbage = heap.alloc(Object::Array(vec![outer]));
+        heap.collect(vec![outer, 12345]);
+
+        assert!(heap.is_live(outer));
+        assert!(heap.is_live(inner));
+        assert!(!heap.is_live(garbage));
+        assert_eq!(
+            GcStats {
+                allocated: 3,
+                freed: 0,
+                collected: 1,
+                collections: 1
+            },
+            heap.stats
+        );
+    }
+
+    #[test]
+    fn test_collect_cycles() {
+        let mut heap = Heap::new();
+        let a = heap.new_array(Op::Alloc, 1).unwrap();
+        let b = heap.new_array(Op::Alloc, 1).unwrap();
+        heap.array_mut(a).unwrap()[0] = b;
+        heap.array_mut(b).unwrap()[0] = a;
+        heap.collect(Vec::new());
+
+        assert_eq!(0, heap.live());
+    }
+
+    #[test]
+    fn test_bad_handles() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(Object::Array(Vec::new()));
+        let s = heap.alloc(Object::Str("s".to_string()));
+
+        let mut stack = vec![a, a];
+        run(&mut heap, &[Op::Free], &mut stack).unwrap();
+        assert_eq!(
+            Err(Trap::BadHandle(Op::Free)),
+            run(&mut heap, &[Op::Free], &mut stack)
+        );
+
+        let mut stack = vec![0, s];
+        assert_eq!(
+            Err(Trap::BadHandle(Op::ArrayGet)),
+            run(&mut heap, &[Op::ArrayGet], &mut stack)
+        );
+        assert_eq!(None, heap.array(s));
+        assert_eq!(None, heap.closure(-1));
+    }
+}
@@ -0,0 +1,138 @@
+use std::convert::TryFrom;
+
+use crate::stackmachine::check::Signature;
+use crate::stackmachine::{Heap, Op, Trap};
+
+/*
+ * Functions provided by the program embedding the machine, called with
+ * `callext`. Unlike the plain `fn(&mut Vec<i32>)` entries of
+ * `StackMachine::ext_functions`, a host function can keep state of its own,
+ * sees the machine's memory, heap and PID, and can fail with a `Trap` that a
+ * `try` block in the program catches like any other error; `Trap::Thrown`
+ * gives it error codes of its own.
+ *
+ * Every host function declares how many values it takes and leaves. The
+ * machine checks there are enough values before the call and that the
+ * function left as many as it said afterwards, and the checker uses the same
+ * declaration to follow the stack through `callext`.
+ */
+
+pub struct HostContext<'a> {
+    pub stack: &'a mut Vec<i32>,
+    pub memory: &'a mut [u8],
+    pub heap: &'a mut Heap,
+    pub pid: u16,
+}
+
+impl HostContext<'_> {
+    // Pops an argument; the declared inputs are always there
+    pub fn pop(&mut self) -> i32 {
+        self.stack.pop().unwrap()
+    }
+
+    pub fn push(&mut self, v: i32) {
+        self.stack.push(v);
+    }
+
+    fn word(&self, address: i32) -> Result<std::ops::Range<usize>, Trap> {
+        match usize::try_from(address) {
+            Ok(start) if start + 4 <= self.memory.len() => Ok(start..start + 4),
+            _ => Err(Trap::OutOfRange(Op::CallExt)),
+        }
+    }
+
+    // Reads a word of memory as `load` does, trapping outside of memory
+    pub fn load(&self, address: i32) -> Result<i32, Trap> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.memory[self.word(address)?]);
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    pub fn store(&mut self, address: i32, value: i32) -> Result<(), Trap> {
+        let range = self.word(address)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+}
+
+pub trait HostFunction: Send {
+    fn signature(&self) -> Signature;
+
+    fn call(&mut self, ctx: &mut HostContext) -> Result<(), Trap>;
+}
+
+// A closure passed to `StackMachine::register`, with the signature given there
+pub struct HostClosure<F> {
+    pub signature: Signature,
+    pub function: F,
+}
+
+impl<F> HostFunction for HostClosure<F>
+where
+    F: FnMut(&mut HostContext) -> Result<(), Trap> + Send,
+{
+    fn signature(&self) -> Signature {
+        self.signature
+    }
+
+    fn call(&mut self, ctx: &mut HostContext) -> Result<(), Trap> {
+        (self.function)(ctx)
+    }
+}
+
+#[cfg(test)]
+mod host_test {
+
+    use super::*;
+    use crate::stackmachine::{reader, StackMachine};
+
+    // Counts its calls; the state a plain `fn` could not keep
+    struct Counter(i32);
+
+    impl HostFunction for Counter {
+        fn signature(&self) -> Signature {
+            Signature {
+                inputs: 0,
+                outputs: 1,
+            }
+        }
+
+        fn call(&mut self, ctx: &mut HostContext) -> Result<(), Trap> {
+            self.0 += 1;
+            ctx.push(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stateful_function() {
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.register_host("count", Counter(0));
+        let code = reader::parse(
+            "const 0\npushstr count\ncallext\nconst 0\npushstr count\ncallext",
+            "t.sm",
+        )
+        .code;
+        sm.execute(code);
+
+        assert_eq!(vec![1, 2], sm.stack);
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut stack = Vec::new();
+        let mut memory = vec![0; 8];
+        let mut heap = Heap::new();
+        let mut ctx = HostContext {
+            stack: &mut stack,
+            memory: &mut memory,
+            heap: &mut heap,
+            pid: 0,
+        };
+
+        assert_eq!(Ok(()), ctx.store(4, -2));
+        assert_eq!(Ok(-2), ctx.load(4));
+        assert_eq!(Err(Trap::OutOfRange(Op::CallExt)), ctx.load(5));
+        assert_eq!(Err(Trap::OutOfRange(Op::CallExt)), ctx.store(-1, 0));
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+
+use crate::stackmachine::function::Exec;
+use crate::stackmachine::{Op, Trap};
+
+/*
+ * Where a machine reads from. `read.int`, `read.char`, `read.line` and `eof`
+ * read the machine's input source, stdin unless told otherwise, and like the
+ * output sink it is shared, so forked children read from the same input as
+ * their parent and each value is read once.
+ *
+ * Characters are Unicode code points read as UTF-8, with U+FFFD standing in
+ * for bytes that are not. `read.int` skips whitespace and reads an optionally
+ * signed decimal number, leaving whatever follows it unread; `read.char` and
+ * `read.line` push -1 and an empty string at the end of the input, so `eof`
+ * tells them apart from a real -1 or an empty line.
+ */
+
+pub type Source = Arc<Mutex<dyn BufRead + Send>>;
+
+pub fn source<R: BufRead + Send + 'static>(reader: R) -> Source {
+    Arc::new(Mutex::new(reader))
+}
+
+pub fn stdin() -> Source {
+    source(BufReader::new(io::stdin()))
+}
+
+// Whether `execute` runs `op`
+pub fn is_input(op: Op) -> bool {
+    op.info().exec == Exec::Input
+}
+
+// Runs an input opcode on the stack, or returns `None` if `op` is not one
+pub fn execute(source: &Source, op: Op, stack: &mut Vec<i32>) -> Option<Result<(), Trap>> {
+    if !is_input(op) {
+        return None;
+    }
+    let mut reader = source.lock().unwrap();
+    let reader = &mut *reader;
+    match op {
+        Op::ReadInt => match read_int(reader) {
+            Ok(v) => stack.push(v),
+            Err(trap) => return Some(Err(trap)),
+        },
+        Op::ReadChar => stack.push(read_char(reader).map_or(-1, |c| c as i32)),
+        Op::ReadLine => {
+            let mut line = Vec::new();
+            reader
+                .read_until(b'\n', &mut line)
+                .expect("Could not read from the input source");
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+            // Null-terminated, first character on top
+            stack.push(0);
+            let chars = String::from_utf8_lossy(&line).chars().collect::<Vec<_>>();
+            stack.extend(chars.iter().rev().map(|c| *c as i32));
+        }
+        _ => stack.push(peek(reader).is_none() as i32),
+    }
+    Some(Ok(()))
+}
+
+fn peek(reader: &mut dyn BufRead) -> Option<u8> {
+    reader
+        .fill_buf()
+        .expect("Could not read from the input source")
+        .first()
+        .copied()
+}
+
+fn read_char(reader: &mut dyn BufRead) -> Option<char> {
+    let first = peek(reader)?;
+    let len = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    let mut bytes = vec![first];
+    reader.consume(1);
+    while bytes.len() < len {
+        match peek(reader) {
+            Some(b) if b & 0xc0 == 0x80 => {
+                bytes.push(b);
+                reader.consume(1);
+            }
+            _ => break,
+        }
+    }
+    Some(
+        std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER),
+    )
+}
+
+fn read_int(reader: &mut dyn BufRead) -> Result<i32, Trap> {
+    while peek(reader).filter(u8::is_ascii_whitespace).is_some() {
+        reader.consume(1);
+    }
+    let mut text = String::new();
+    if let Some(sign @ b'-') | Some(sign @ b'+') = peek(reader) {
+        text.push(sign as char);
+        reader.consume(1);
+    }
+    while let Some(digit) = peek(reader).filter(u8::is_ascii_digit) {
+        text.push(digit as char);
+        reader.consume(1);
+    }
+    if !text.ends_with(|c: char| c.is_ascii_digit()) {
+        return Err(Trap::InvalidInput(Op::ReadInt));
+    }
+    text.parse().map_err(|_| Trap::Overflow(Op::ReadInt))
+}
+
+#[cfg(test)]
+mod input_test {
+
+    use super::*;
+
+    fn run(input: &'static str, ops: &[Op]) -> Result<Vec<i32>, Trap> {
+        let source = source(input.as_bytes());
+        let mut stack = Vec::new();
+        for op in ops {
+            execute(&source, *op, &mut stack).unwrap()?;
+        }
+        Ok(stack)
+    }
+
+    #[test]
+    fn test_read_int() {
+        assert_eq!(
+            Ok(vec![12, -3, 7]),
+            run(" 12\n-3 +7", &[Op::ReadInt, Op::ReadInt, Op::ReadInt])
+        );
+        assert_eq!(
+            Err(Trap::InvalidInput(Op::ReadInt)),
+            run("x", &[Op::ReadInt])
+        );
+        assert_eq!(
+            Err(Trap::InvalidInput(Op::ReadInt)),
+            run("", &[Op::ReadInt])
+        );
+        assert_eq!(
+            Err(Trap::Overflow(Op::ReadInt)),
+            run("99999999999", &[Op::ReadInt])
+        );
+    }
+
+    #[test]
+    fn test_read_char_and_eof() {
+        assert_eq!(
+            Ok(vec![0, 104, 0, 233, 1, -1]),
+            run(
+                "hé",
+                &[
+                    Op::Eof,
+                    Op::ReadChar,
+                    Op::Eof,
+                    Op::ReadChar,
+                    Op::Eof,
+                    Op::ReadChar
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_read_line() {
+        assert_eq!(
+            Ok(vec![0, 105, 104, 0, 0]),
+            run("hi\r\n\n", &[Op::ReadLine, Op::ReadLine, Op::ReadLine])
+        );
+    }
+
+    #[test]
+    fn test_int_then_line() {
+        // What follows the number, here the newline, is left for `read.line`
+        assert_eq!(
+            Ok(vec![5, 0, 0, 107, 111]),
+            run("5\nok\n", &[Op::ReadInt, Op::ReadLine, Op::ReadLine])
+        );
+    }
+}
@@ -0,0 +1,982 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::stackmachine::program::{DebugInfo, Program, Span};
+use crate::stackmachine::Op;
+
+/*
+ * A small infix language that compiles to stack machine code, to show how a
+ * compiler targets a stack machine:
+ *
+ *   // Comments run to the end of the line
+ *   fn square(n) { return n * n; }
+ *
+ *   let x = 4;
+ *   while (x > 0) {
+ *       if (x == 2) { print("two"); } else { print(square(x)); }
+ *       x = x - 1;
+ *   }
+ *
+ * Expressions are integers, variables, calls and the operators
+ * `|| && == != < <= > >= + - * / % ! -` (lowest precedence first). Values are
+ * true when positive, like the `if` instruction. Besides `let`, assignment,
+ * `if`/`else`, `while`, `break`, `return` and expression statements there are
+ * `print(expr)`, `print("text")`, `fork;` and `dbg;`, and the expressions
+ * `child()` and `getpid()`. Semicolons may be left out before a `}`.
+ *
+ * Every expression leaves exactly one value on the stack, so statements pop
+ * whatever they do not use. Variables live in machine memory: word 0 holds
+ * the frame pointer, word 1 the top of the frame stack and globals follow.
+ * Each call to a function with parameters or locals gets a frame at the top of
+ * the frame stack holding the caller's frame pointer and then its variables,
+ * which is what lets functions recurse. Functions always return a value, 0 if
+ * they finish without `return`.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pos {
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LangError {
+    pub pos: Pos,
+    pub message: String,
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.pos.line, self.pos.column, self.message)
+    }
+}
+
+impl std::error::Error for LangError {}
+
+fn error<T>(pos: Pos, message: String) -> Result<T, LangError> {
+    Err(LangError { pos, message })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Int(i32),
+    Ident(String),
+    Str(String),
+    Punct(&'static str),
+    Eof,
+}
+
+// Longest first, so that `<=` is not read as `<` then `=`
+const PUNCTUATION: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "=", "!", "(", ")", "{",
+    "}", ",", ";",
+];
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    pos: Pos,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        } else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    // Consumes characters for as long as `f` holds for them
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.peek().filter(|c| f(*c)) {
+            taken.push(c);
+            self.advance();
+        }
+        taken
+    }
+
+    fn string(&mut self, start: Pos) -> Result<String, LangError> {
+        self.advance();
+        let mut s = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.advance() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some(c) => s.push(c),
+                    None => break,
+                },
+                Some('\n') | None => break,
+                Some(c) => s.push(c),
+            }
+        }
+        error(start, "unterminated string".to_string())
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Pos)>, LangError> {
+    let mut tokens = Vec::new();
+    let mut lexer = Lexer {
+        source,
+        offset: 0,
+        pos: Pos { line: 1, column: 1 },
+    };
+
+    while let Some(c) = lexer.peek() {
+        let start = lexer.pos;
+        let rest = &source[lexer.offset..];
+        if c.is_whitespace() {
+            lexer.advance();
+        } else if rest.starts_with("//") {
+            lexer.take_while(|c| c != '\n');
+        } else if c.is_ascii_digit() {
+            let digits = lexer.take_while(|c| c.is_ascii_digit());
+            match digits.parse() {
+                Ok(n) => tokens.push((Token::Int(n), start)),
+                Err(_) => return error(start, format!("integer {} is too large", digits)),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let ident = lexer.take_while(|c| c.is_alphanumeric() || c == '_');
+            tokens.push((Token::Ident(ident), start));
+        } else if c == '"' {
+            tokens.push((Token::Str(lexer.string(start)?), start));
+        } else {
+            match PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+                Some(p) => {
+                    for _ in 0..p.len() {
+                        lexer.advance();
+                    }
+                    tokens.push((Token::Punct(p), start));
+                }
+                None => return error(start, format!("unexpected character {:?}", c)),
+            }
+        }
+    }
+    tokens.push((Token::Eof, lexer.pos));
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Int(i32),
+    Var(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<(Expr, Pos)>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Let(String, Expr),
+    Assign(String, Expr),
+    If(Expr, Block, Block),
+    While(Expr, Block),
+    Return(Option<Expr>),
+    Break,
+    Print(Expr),
+    PrintStr(String),
+    Fork,
+    Debug,
+    Expr(Expr),
+}
+
+// Statements with the position each one starts at
+type Block = Vec<(Stmt, Pos)>;
+
+#[derive(Debug)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Block,
+    pos: Pos,
+}
+
+struct Parser {
+    tokens: Vec<(Token, Pos)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn pos(&self) -> Pos {
+        self.tokens[self.next].1
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.next].0.clone();
+        if token != Token::Eof {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn is(&self, punct: &str) -> bool {
+        matches!(self.peek(), Token::Punct(p) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(s) if s == keyword)
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), LangError> {
+        if self.is(punct) {
+            self.bump();
+            Ok(())
+        } else {
+            error(
+                self.pos(),
+                format!("expected `{}`, found {}", punct, self.describe()),
+            )
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, LangError> {
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                self.bump();
+                Ok(name)
+            }
+            _ => error(
+                self.pos(),
+                format!("expected a name, found {}", self.describe()),
+            ),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Token::Int(n) => format!("`{}`", n),
+            Token::Ident(s) => format!("`{}`", s),
+            Token::Str(_) => "a string".to_string(),
+            Token::Punct(p) => format!("`{}`", p),
+            Token::Eof => "the end of the file".to_string(),
+        }
+    }
+
+    fn program(&mut self) -> Result<(Vec<Function>, Block), LangError> {
+        let mut functions = Vec::new();
+        let mut main = Vec::new();
+        while *self.peek() != Token::Eof {
+            if self.is_keyword("fn") {
+                functions.push(self.function()?);
+            } else {
+                main.push(self.statement()?);
+            }
+        }
+        Ok((functions, main))
+    }
+
+    fn function(&mut self) -> Result<Function, LangError> {
+        let pos = self.pos();
+        self.bump();
+        let name = self.ident()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.is(")") {
+            params.push(self.ident()?);
+            if !self.is(")") {
+                self.expect(",")?;
+            }
+        }
+        self.bump();
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            body,
+            pos,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, LangError> {
+        self.expect("{")?;
+        let mut body = Vec::new();
+        while !self.is("}") {
+            if *self.peek() == Token::Eof {
+                return error(
+                    self.pos(),
+                    "expected `}`, found the end of the file".to_string(),
+                );
+            }
+            body.push(self.statement()?);
+        }
+        self.bump();
+        Ok(body)
+    }
+
+    // Statements that do not end in a block need a `;`, except before a `}`
+    fn end_statement(&mut self) -> Result<(), LangError> {
+        if self.is("}") || *self.peek() == Token::Eof {
+            Ok(())
+        } else {
+            self.expect(";")
+        }
+    }
+
+    fn statement(&mut self) -> Result<(Stmt, Pos), LangError> {
+        let pos = self.pos();
+        let keyword = match self.peek() {
+            Token::Ident(s) => s.clone(),
+            _ => String::new(),
+        };
+        let stmt = match keyword.as_str() {
+            "let" => {
+                self.bump();
+                let name = self.ident()?;
+                self.expect("=")?;
+                Stmt::Let(name, self.expr()?)
+            }
+            "if" => return Ok((self.if_statement()?, pos)),
+            "while" => {
+                self.bump();
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                return Ok((Stmt::While(cond, self.block()?), pos));
+            }
+            "return" => {
+                self.bump();
+                if self.is(";") || self.is("}") {
+                    Stmt::Return(None)
+                } else {
+                    Stmt::Return(Some(self.expr()?))
+                }
+            }
+            "break" => {
+                self.bump();
+                Stmt::Break
+            }
+            "print" => {
+                self.bump();
+                self.expect("(")?;
+                let stmt = match self.peek().clone() {
+                    Token::Str(s) => {
+                        self.bump();
+                        Stmt::PrintStr(s)
+                    }
+                    _ => Stmt::Print(self.expr()?),
+                };
+                self.expect(")")?;
+                stmt
+            }
+            "fork" => {
+                self.bump();
+                Stmt::Fork
+            }
+            "dbg" => {
+                self.bump();
+                Stmt::Debug
+            }
+            _ => {
+                let is_assignment = matches!(self.tokens[self.next + 1].0, Token::Punct("="));
+                if let (Token::Ident(name), true) = (self.peek().clone(), is_assignment) {
+                    self.bump();
+                    self.bump();
+                    Stmt::Assign(name, self.expr()?)
+                } else {
+                    Stmt::Expr(self.expr()?)
+                }
+            }
+        };
+        self.end_statement()?;
+        Ok((stmt, pos))
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, LangError> {
+        self.bump();
+        self.expect("(")?;
+        let cond = self.expr()?;
+        self.expect(")")?;
+        let then = self.block()?;
+        let mut otherwise = Vec::new();
+        if self.is_keyword("else") {
+            self.bump();
+            if self.is_keyword("if") {
+                let pos = self.pos();
+                otherwise.push((self.if_statement()?, pos));
+            } else {
+                otherwise = self.block()?;
+            }
+        }
+        Ok(Stmt::If(cond, then, otherwise))
+    }
+
+    fn expr(&mut self) -> Result<Expr, LangError> {
+        self.binary(0)
+    }
+
+    // Binary operators by increasing precedence
+    const LEVELS: &'static [&'static [&'static str]] = &[
+        &["||"],
+        &["&&"],
+        &["==", "!="],
+        &["<", "<=", ">", ">="],
+        &["+", "-"],
+        &["*", "/", "%"],
+    ];
+
+    fn binary(&mut self, level: usize) -> Result<Expr, LangError> {
+        if level == Self::LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Token::Punct(p) = *self.peek() {
+            if !Self::LEVELS[level].contains(&p) {
+                break;
+            }
+            self.bump();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(p, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, LangError> {
+        if self.is("!") {
+            self.bump();
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.is("-") {
+            self.bump();
+            return Ok(match self.unary()? {
+                Expr::Int(n) => Expr::Int(n.wrapping_neg()),
+                e => Expr::Neg(Box::new(e)),
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, LangError> {
+        if matches!(self.peek(), Token::Str(_) | Token::Eof | Token::Punct(_)) && !self.is("(") {
+            return error(
+                self.pos(),
+                format!("expected an expression, found {}", self.describe()),
+            );
+        }
+        match self.bump() {
+            Token::Int(n) => Ok(Expr::Int(n)),
+            Token::Ident(s) if s == "true" => Ok(Expr::Int(1)),
+            Token::Ident(s) if s == "false" => Ok(Expr::Int(0)),
+            Token::Ident(name) if self.is("(") => {
+                self.bump();
+                let mut args = Vec::new();
+                while !self.is(")") {
+                    let pos = self.pos();
+                    args.push((self.expr()?, pos));
+                    if !self.is(")") {
+                        self.expect(",")?;
+                    }
+                }
+                self.bump();
+                Ok(Expr::Call(name, args))
+            }
+            Token::Ident(name) => Ok(Expr::Var(name)),
+            _ => {
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+        }
+    }
+}
+
+// Memory words holding the frame pointer and the top of the frame stack
+const FP: i32 = 0;
+const TOP: i32 = 4;
+const GLOBALS: i32 = 8;
+
+#[derive(Default)]
+struct Generator {
+    code: Vec<(Op, Option<i32>)>,
+    spans: Vec<Span>,
+    pos: Option<Pos>,
+    globals: HashMap<String, i32>,
+    // Parameter count of every function
+    arity: HashMap<String, usize>,
+    // Frame slots of the function being compiled, or `None` at the top level
+    locals: Option<HashMap<String, i32>>,
+    loops: usize,
+}
+
+impl Generator {
+    fn emit(&mut self, op: Op, arg: Option<i32>) {
+        let pos = self.pos.unwrap_or(Pos { line: 0, column: 0 });
+        self.code.push((op, arg));
+        self.spans.push(Span {
+            file: 0,
+            line: pos.line,
+            column: pos.column,
+        });
+    }
+
+    fn op(&mut self, op: Op) {
+        self.emit(op, None);
+    }
+
+    fn constant(&mut self, n: i32) {
+        self.emit(Op::Const, Some(n));
+    }
+
+    fn string(&mut self, s: &str) {
+        self.constant(0);
+        for c in s.chars().rev() {
+            self.constant(c as i32);
+        }
+    }
+
+    // Pushes the address of a variable
+    fn address(&mut self, name: &str, pos: Pos) -> Result<(), LangError> {
+        if let Some(slot) = self.locals.as_ref().and_then(|l| l.get(name)).copied() {
+            self.constant(FP);
+            self.op(Op::Load);
+            self.constant(4 * (slot + 1));
+            self.op(Op::Add);
+            return Ok(());
+        }
+        match self.globals.get(name) {
+            Some(slot) => {
+                self.constant(GLOBALS + 4 * slot);
+                Ok(())
+            }
+            None => error(pos, format!("`{}` is not defined", name)),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr, pos: Pos) -> Result<(), LangError> {
+        match e {
+            Expr::Int(n) => self.constant(*n),
+            Expr::Var(name) => {
+                self.address(name, pos)?;
+                self.op(Op::Load);
+            }
+            Expr::Not(e) => {
+                self.expr(e, pos)?;
+                self.op(Op::Not);
+            }
+            Expr::Neg(e) => {
+                self.expr(e, pos)?;
+                self.op(Op::Neg);
+            }
+            Expr::Binary("&&", lhs, rhs) => {
+                self.expr(lhs, pos)?;
+                self.op(Op::If);
+                self.truth(rhs, pos)?;
+                self.op(Op::Else);
+                self.constant(0);
+                self.op(Op::EndIf);
+            }
+            Expr::Binary("||", lhs, rhs) => {
+                self.expr(lhs, pos)?;
+                self.op(Op::If);
+                self.constant(1);
+                self.op(Op::Else);
+                self.truth(rhs, pos)?;
+                self.op(Op::EndIf);
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                // The machine takes the left operand from the top of the stack
+                self.expr(rhs, pos)?;
+                self.expr(lhs, pos)?;
+                match *op {
+                    "+" => self.op(Op::Add),
+                    "-" => self.op(Op::Sub),
+                    "*" => self.op(Op::Mul),
+                    "/" => self.op(Op::Div),
+                    "%" => self.op(Op::Mod),
+                    "==" => self.op(Op::r#Eq),
+                    "!=" => {
+                        self.op(Op::r#Eq);
+                        self.op(Op::Not);
+                    }
+                    "<" => self.op(Op::LT),
+                    "<=" => self.op(Op::LTE),
+                    ">" => self.op(Op::GT),
+                    _ => self.op(Op::GTE),
+                }
+            }
+            Expr::Call(name, args) if name == "child" || name == "getpid" => {
+                if !args.is_empty() {
+                    return error(pos, format!("`{}` takes no arguments", name));
+                }
+                self.op(if name == "child" {
+                    Op::Child
+                } else {
+                    Op::GetPid
+                });
+            }
+            Expr::Call(name, args) => {
+                match self.arity.get(name) {
+                    Some(n) if *n == args.len() => (),
+                    Some(n) => {
+                        return error(
+                            pos,
+                            format!("`{}` takes {} argument(s) but got {}", name, n, args.len()),
+                        )
+                    }
+                    None => return error(pos, format!("function `{}` is not defined", name)),
+                }
+                for (arg, pos) in args {
+                    self.expr(arg, *pos)?;
+                }
+                self.string(name);
+                self.op(Op::Call);
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates to exactly 1 or 0
+    fn truth(&mut self, e: &Expr, pos: Pos) -> Result<(), LangError> {
+        self.expr(e, pos)?;
+        self.op(Op::Not);
+        self.op(Op::Not);
+        Ok(())
+    }
+
+    fn block(&mut self, body: &[(Stmt, Pos)]) -> Result<(), LangError> {
+        for (stmt, pos) in body {
+            self.pos = Some(*pos);
+            self.statement(stmt, *pos)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt, pos: Pos) -> Result<(), LangError> {
+        match stmt {
+            Stmt::Let(name, e) | Stmt::Assign(name, e) => {
+                self.expr(e, pos)?;
+                self.address(name, pos)?;
+                self.op(Op::Store);
+            }
+            Stmt::If(cond, then, otherwise) => {
+                self.expr(cond, pos)?;
+                self.op(Op::If);
+                self.block(then)?;
+                if !otherwise.is_empty() {
+                    self.pos = Some(pos);
+                    self.op(Op::Else);
+                    self.block(otherwise)?;
+                }
+                self.pos = Some(pos);
+                self.op(Op::EndIf);
+            }
+            Stmt::While(cond, body) => {
+                self.op(Op::Loop);
+                self.expr(cond, pos)?;
+                self.op(Op::Not);
+                self.op(Op::If);
+                self.op(Op::Break);
+                self.op(Op::EndIf);
+                self.loops += 1;
+                self.block(body)?;
+                self.loops -= 1;
+                self.pos = Some(pos);
+                self.op(Op::EndLoop);
+            }
+            Stmt::Return(e) => {
+                if self.locals.is_none() {
+                    return error(pos, "`return` outside of a function".to_string());
+                }
+                match e {
+                    Some(e) => self.expr(e, pos)?,
+                    None => self.constant(0),
+                }
+                self.epilogue();
+                self.op(Op::Return);
+            }
+            Stmt::Break => {
+                if self.loops == 0 {
+                    return error(pos, "`break` outside of a loop".to_string());
+                }
+                self.op(Op::Break);
+            }
+            Stmt::Print(e) => {
+                self.expr(e, pos)?;
+                self.op(Op::Print);
+                self.op(Op::Pop);
+            }
+            Stmt::PrintStr(s) => {
+                self.string(s);
+                self.op(Op::PrintChars);
+            }
+            Stmt::Fork => self.op(Op::Fork),
+            Stmt::Debug => self.op(Op::Debug),
+            Stmt::Expr(e) => {
+                self.expr(e, pos)?;
+                self.op(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the function being compiled has a frame
+    fn framed(&self) -> bool {
+        self.locals.as_ref().is_some_and(|l| !l.is_empty())
+    }
+
+    fn prologue(&mut self, params: usize, size: i32) {
+        // Save the caller's frame pointer at the top of the frame stack
+        self.constant(FP);
+        self.op(Op::Load);
+        self.constant(TOP);
+        self.op(Op::Load);
+        self.op(Op::Store);
+        // That is where this frame starts
+        self.constant(TOP);
+        self.op(Op::Load);
+        self.constant(FP);
+        self.op(Op::Store);
+        self.constant(FP);
+        self.op(Op::Load);
+        self.constant(4 * (size + 1));
+        self.op(Op::Add);
+        self.constant(TOP);
+        self.op(Op::Store);
+        // The last argument is on top
+        for slot in (0..params as i32).rev() {
+            self.constant(FP);
+            self.op(Op::Load);
+            self.constant(4 * (slot + 1));
+            self.op(Op::Add);
+            self.op(Op::Store);
+        }
+    }
+
+    fn epilogue(&mut self) {
+        if !self.framed() {
+            return;
+        }
+        self.constant(FP);
+        self.op(Op::Load);
+        self.constant(TOP);
+        self.op(Op::Store);
+        self.constant(FP);
+        self.op(Op::Load);
+        self.op(Op::Load);
+        self.constant(FP);
+        self.op(Op::Store);
+    }
+
+    fn function(&mut self, f: &Function) -> Result<(), LangError> {
+        self.pos = Some(f.pos);
+        self.string(&f.name);
+        self.op(Op::Function);
+
+        let mut locals = HashMap::new();
+        for (i, param) in f.params.iter().enumerate() {
+            if locals.insert(param.clone(), i as i32).is_some() {
+                return error(f.pos, format!("parameter `{}` appears twice", param));
+            }
+        }
+        declare_variables(&f.body, &mut locals);
+        let size = locals.len() as i32;
+        self.locals = Some(locals);
+
+        if size > 0 {
+            self.prologue(f.params.len(), size);
+        }
+        self.block(&f.body)?;
+        self.pos = Some(f.pos);
+        self.constant(0);
+        self.epilogue();
+        self.locals = None;
+        self.op(Op::EndFunction);
+        Ok(())
+    }
+}
+
+/*
+ * Gives every variable declared in `body` a slot, so that frame sizes are
+ * known before a function body is compiled and globals can be used in the
+ * functions defined before them. Like `var` in JavaScript, a variable belongs
+ * to the whole function (or program) it is declared in.
+ */
+fn declare_variables(body: &[(Stmt, Pos)], slots: &mut HashMap<String, i32>) {
+    for (stmt, _) in body {
+        match stmt {
+            Stmt::Let(name, _) => {
+                let slot = slots.len() as i32;
+                slots.entry(name.clone()).or_insert(slot);
+            }
+            Stmt::If(_, then, otherwise) => {
+                declare_variables(then, slots);
+                declare_variables(otherwise, slots);
+            }
+            Stmt::While(_, body) => declare_variables(body, slots),
+            _ => (),
+        }
+    }
+}
+
+pub fn compile(source: &str, filename: &str) -> Result<Program, LangError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, next: 0 };
+    let (functions, main) = parser.program()?;
+
+    let mut gen = Generator::default();
+    for f in &functions {
+        if gen.arity.insert(f.name.clone(), f.params.len()).is_some() {
+            return error(f.pos, format!("function `{}` is defined twice", f.name));
+        }
+    }
+
+    declare_variables(&main, &mut gen.globals);
+    for f in &functions {
+        gen.function(f)?;
+    }
+    gen.block(&main)?;
+
+    // The frame stack starts after the globals, which are only all known now
+    let mut init = Generator {
+        pos: Some(Pos { line: 1, column: 1 }),
+        ..Generator::default()
+    };
+    init.constant(GLOBALS + 4 * gen.globals.len() as i32);
+    init.constant(TOP);
+    init.op(Op::Store);
+    init.code.append(&mut gen.code);
+    init.spans.append(&mut gen.spans);
+
+    let mut program = Program::new(init.code);
+    program.debug = Some(DebugInfo {
+        files: vec![filename.to_string()],
+        spans: init.spans,
+    });
+    Ok(program)
+}
+
+#[cfg(test)]
+mod lang_test {
+
+    use super::*;
+    use crate::stackmachine::StackMachine;
+
+    // Runs a program and returns the values of its globals in order of
+    // declaration
+    fn run(source: &str) -> Vec<i32> {
+        let program = compile(source, "t.sml").unwrap();
+        let mut sm = StackMachine::new(2u32.pow(12));
+        sm.execute_program(&program);
+        assert_eq!(Vec::<i32>::new(), sm.stack, "{}", source);
+
+        let mut globals = Vec::new();
+        let top = i32::from_le_bytes([sm.memory[4], sm.memory[5], sm.memory[6], sm.memory[7]]);
+        for address in (GLOBALS as usize..top.max(GLOBALS) as usize).step_by(4) {
+            let mut word = [0; 4];
+            word.copy_from_slice(&sm.memory[address..address + 4]);
+            globals.push(i32::from_le_bytes(word));
+        }
+        globals
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(vec![14], run("let x = 2 + 3 * 4;"));
+        assert_eq!(vec![2, -1], run("let x = 2 + 7 % 5 * 0; let y = -7 % 3;"));
+        assert_eq!(
+            vec![-1, 2, 7],
+            run("let a = 1 - 2; let b = 8 / 4; let c = -(a - 6)")
+        );
+        assert_eq!(
+            vec![1, 0, 1, 1],
+            run("let a = 3 < 4; let b = !a; let c = 2 != 3; let d = 5 >= 5")
+        );
+        assert_eq!(
+            vec![0, 1, 1],
+            run("let a = 1 && 0; let b = 0 || 7; let c = 1 && 2 == 2")
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            let x = 4;
+            let y = 0;
+            if (x == 4) { y = x * 2 } else { y = 1 }
+            let n = 0;
+            while (true) {
+                n = n + 1;
+                if (n == 10) { break; }
+            }
+            while (x > 0) { x = x - 1 }
+        ";
+
+        assert_eq!(vec![0, 8, 10], run(source));
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = "
+            fn fib(n) {
+                if (n < 2) { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn sub(a, b) { let d = a - b; return d; }
+            fn nothing() { }
+            let f = fib(10);
+            let s = sub(10, 3);
+            let z = nothing();
+        ";
+
+        assert_eq!(vec![55, 7, 0], run(source));
+    }
+
+    #[test]
+    fn test_globals_in_functions() {
+        let source = "
+            let count = 0;
+            fn bump(by) { count = count + by; }
+            bump(2);
+            bump(3);
+        ";
+
+        assert_eq!(vec![5], run(source));
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("let x = ;", "1:9: expected an expression, found `;`"),
+            ("x = 1;", "1:1: `x` is not defined"),
+            ("f(1);", "1:1: function `f` is not defined"),
+            ("fn f(a) {}\nf();", "2:1: `f` takes 1 argument(s) but got 0"),
+            ("break;", "1:1: `break` outside of a loop"),
+            ("let s = \"abc", "1:9: unterminated string"),
+            (
+                "if (1) { print(1)",
+                "1:18: expected `}`, found the end of the file",
+            ),
+        ];
+        for (source, message) in cases.iter() {
+            let e = compile(source, "t.sml").unwrap_err();
+            assert_eq!(*message, e.to_string(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let program = compile("let x = 1;\n\nprint(x);", "t.sml").unwrap();
+        let debug = program.debug.unwrap();
+
+        assert_eq!(program.code.len(), debug.spans.len());
+        assert_eq!(
+            Some("t.sml:3:1".to_string()),
+            debug.location(program.code.len() - 1)
+        );
+    }
+}
@@ -1,183 +1,642 @@
 use std::char;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+pub mod arith;
 pub mod builder;
+pub mod check;
+pub mod disasm;
+pub mod emit;
+pub mod emit_c;
+pub mod emit_wat;
+pub mod fast;
+pub mod files;
+pub mod formatter;
+pub mod forth;
 pub mod function;
+pub mod heap;
+pub mod host;
+pub mod input;
+pub mod lang;
+pub mod optimize;
+pub mod program;
 pub mod reader;
+pub mod sink;
+pub mod strings;
+pub mod wide;
 
+pub use crate::stackmachine::arith::Trap;
 pub use crate::stackmachine::builder::Builder;
+use crate::stackmachine::check::Signature;
+use crate::stackmachine::files::{Access, Files};
 pub use crate::stackmachine::function::Op;
+use crate::stackmachine::function::{Effect, Exec};
+pub use crate::stackmachine::heap::Heap;
+use crate::stackmachine::heap::Object;
+use crate::stackmachine::host::HostClosure;
+pub use crate::stackmachine::host::{HostContext, HostFunction};
+use crate::stackmachine::input::Source;
+pub use crate::stackmachine::program::Program;
+use crate::stackmachine::sink::Sink;
+
+// How a run of code finished. `break` and `return` unwind through the nested
+// calls that run `if` branches and loop bodies until something handles them;
+// a trap unwinds all the way out of the program.
+#[derive(Clone, Debug, PartialEq)]
+enum Flow {
+    Continue,
+    Break,
+    Return,
+    Trap(Trap),
+    // `exit` ends the whole program, and `try` does not stop it
+    Exit(i32),
+}
+
+/*
+ * Finds the end of the block opened at `start`, along with the `else` of an
+ * `if` block or the `catch` of a `try` block. Blocks of the same kind nest; a
+ * missing end is reported the same way as other syntax errors.
+ */
+fn match_block(
+    code: &[(Op, Option<i32>)],
+    start: usize,
+    open: Op,
+    close: Op,
+) -> (Option<usize>, usize) {
+    let mut nest = 0;
+    let mut else_idx = None;
+    for (i, (op, _)) in code.iter().enumerate().skip(start + 1) {
+        if *op == open {
+            nest += 1;
+        } else if *op == close {
+            if nest == 0 {
+                return (else_idx, i);
+            }
+            nest -= 1;
+        } else if nest == 0
+            && ((*op == Op::Else && open == Op::If) || (*op == Op::Catch && open == Op::Try))
+        {
+            else_idx = Some(i);
+        }
+    }
+    panic!("Each `{}` must have a matching `{}`.", open, close);
+}
 
 pub struct StackMachine {
     pub stack: Vec<i32>,
     pub memory: Vec<u8>,
+    pub heap: Heap,
     pub ext_functions: HashMap<String, fn(&mut Vec<i32>)>,
+    // Looked up before `ext_functions`; see `register`
+    // Shared with forked children, so they see the same state
+    pub host_functions: HashMap<String, Arc<Mutex<dyn HostFunction>>>,
     pub function_table: HashMap<String, Vec<(Op, Option<i32>)>>,
+    // Function names by handle, see `function_handle`
+    pub function_handles: Vec<String>,
+    // The closure each running function was called through, innermost last;
+    // `None` for plain calls
+    pub envs: Vec<Option<i32>>,
+    // Names of the functions running, outermost first
+    pub calls: Vec<String>,
+    // The stacks the `try` blocks running will put back if they fail
+    pub try_stacks: Vec<Vec<i32>>,
+    // The calls that were running when the last uncaught trap happened
+    pub backtrace: Vec<String>,
     pub pid: u16,
     pub child_pid: u16,
     pub child: bool,
+    pub fork_policy: ForkPolicy,
+    // Program output; see `sink`
+    pub output: Sink,
+    // The interpreter's trace of what it runs, off when `None`
+    pub trace: Option<Sink>,
+    // What the `read` opcodes read; see `input`
+    pub input: Source,
+    // The directories the `file` opcodes may use, and the files they opened
+    pub files: Arc<Mutex<Files>>,
+    // What `argv` and `argc` see, the program first
+    pub args: Vec<String>,
+    // The variables `getenv` can read; empty unless the host fills it
+    pub vars: BTreeMap<String, String>,
+    // The status the last run passed to `exit`, if it exited
+    pub exit_status: Option<i32>,
+}
+
+/*
+ * What a child started by `fork` gets from its parent; anything it does not
+ * inherit starts out empty. Globals live in memory, so `memory` covers them.
+ * Host functions are shared rather than copied: parent and children call the
+ * same instance and see the same state. The default inherits everything.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForkPolicy {
+    pub stack: bool,
+    pub memory: bool,
+    // Strings, arrays and closures, and the closures running
+    pub heap: bool,
+    // Functions defined with `function`, and their handles
+    pub functions: bool,
+    // `host_functions` and `ext_functions`
+    pub host_functions: bool,
+    // The output and trace sinks, the input source, the open files and
+    // granted directories, and the arguments and variables; a child that
+    // does not share them writes to stdout, traces nothing, reads stdin, may
+    // not use any file and has no arguments or variables
+    pub io: bool,
+}
+
+impl Default for ForkPolicy {
+    fn default() -> Self {
+        ForkPolicy {
+            stack: true,
+            memory: true,
+            heap: true,
+            functions: true,
+            host_functions: true,
+            io: true,
+        }
+    }
 }
 
 impl StackMachine {
     pub fn last(&self) -> Option<i32> {
-        if self.stack.len() == 0 {
-            return None;
-        } else {
-            return Some(self.stack[self.stack.len() - 1]);
-        }
+        self.stack.last().copied()
     }
 
     pub fn pop(&mut self) -> Option<i32> {
-        return self.stack.pop();
+        self.stack.pop()
     }
 
     pub fn push(&mut self, item: i32) {
-        return self.stack.push(item);
+        self.stack.push(item);
     }
 
     pub fn add(&mut self, a: i32, b: i32) {
-        self.push(a + b);
+        self.push(a.wrapping_add(b));
     }
 
     pub fn sub(&mut self, a: i32, b: i32) {
-        self.push(a - b);
+        self.push(a.wrapping_sub(b));
     }
 
     pub fn mul(&mut self, a: i32, b: i32) {
-        self.push(a * b);
+        self.push(a.wrapping_mul(b));
     }
 
+    // Panics if `b` is 0; the `div` opcode traps instead
     pub fn div(&mut self, a: i32, b: i32) {
-        self.push(a / b);
+        self.push(a.wrapping_div(b));
     }
 
-    pub fn collect_str(&mut self) -> String {
-        let mut res = String::new();
-        loop {
-            if let Some(v) = self.pop() {
-                if v == 0 {
-                    break;
-                } else {
-                    res.push((v as u8) as char);
+    // Memory is byte addressed; words are stored little-endian. A word
+    // outside of memory traps.
+    fn word(&self, op: Op, address: i32) -> Result<std::ops::Range<usize>, Trap> {
+        match usize::try_from(address) {
+            Ok(start) if start + 4 <= self.memory.len() => Ok(start..start + 4),
+            _ => Err(Trap::OutOfRange(op)),
+        }
+    }
+
+    pub fn load(&mut self, address: i32) -> Result<(), Trap> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.memory[self.word(Op::Load, address)?]);
+        self.push(i32::from_le_bytes(bytes));
+        Ok(())
+    }
+
+    pub fn store(&mut self, address: i32, value: i32) -> Result<(), Trap> {
+        let range = self.word(Op::Store, address)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    // Index of the value `n` below the top of the stack, for `op`
+    fn below_top(&self, op: Op, n: i32) -> Result<usize, Trap> {
+        let n = usize::try_from(n).map_err(|_| Trap::OutOfRange(op))?;
+        let depth = self.stack.len();
+        if n >= depth {
+            return Err(Trap::StackUnderflow(op));
+        }
+        Ok(depth - 1 - n)
+    }
+
+    pub fn pick(&mut self, n: i32) -> Result<(), Trap> {
+        let value = self.stack[self.below_top(Op::Pick, n)?];
+        self.push(value);
+        Ok(())
+    }
+
+    pub fn roll(&mut self, n: i32) -> Result<(), Trap> {
+        let value = self.stack.remove(self.below_top(Op::Roll, n)?);
+        self.push(value);
+        Ok(())
+    }
+
+    // Runs an opcode that only moves values around the stack
+    fn stack_op(&mut self, op: Op, arg: Option<i32>) -> Result<(), Trap> {
+        match op {
+            Op::Const | Op::Push => self.push(arg.unwrap()),
+            Op::Pop => {
+                self.pop().unwrap();
+            }
+            Op::Dup => {
+                let a = self.last().unwrap();
+                self.push(a);
+            }
+            Op::Swap => {
+                let a = self.pop().unwrap();
+                let b = self.pop().unwrap();
+                self.push(a);
+                self.push(b);
+            }
+            Op::Over => {
+                let a = self.pop().unwrap();
+                let b = self.last().unwrap();
+                self.push(a);
+                self.push(b);
+            }
+            Op::Rot => {
+                let a = self.pop().unwrap();
+                let b = self.pop().unwrap();
+                let c = self.pop().unwrap();
+                self.push(b);
+                self.push(a);
+                self.push(c);
+            }
+            Op::Nip => {
+                let a = self.pop().unwrap();
+                self.pop().unwrap();
+                self.push(a);
+            }
+            Op::Tuck => {
+                let a = self.pop().unwrap();
+                let b = self.pop().unwrap();
+                self.push(a);
+                self.push(b);
+                self.push(a);
+            }
+            Op::Pick => return self.pick(arg.unwrap()),
+            Op::Roll => return self.roll(arg.unwrap()),
+            Op::Depth => self.push(self.stack.len() as i32),
+            Op::Clear => self.stack.clear(),
+            Op::Noop => (),
+            _ => unreachable!("`{}` is not a stack opcode", op),
+        }
+        Ok(())
+    }
+
+    /*
+     * Hands `op` to the executor the opcode table names for it, or returns
+     * `None` for the opcodes `run` has to handle itself.
+     */
+    fn dispatch(&mut self, op: Op, arg: Option<i32>) -> Option<Result<(), Trap>> {
+        let result = match op.info().exec {
+            Exec::Vm => return None,
+            Exec::Stack => Some(self.stack_op(op, arg)),
+            Exec::Arith => arith::execute(op, &mut self.stack),
+            Exec::Wide => wide::execute(op, &mut self.stack),
+            Exec::Heap => self.heap.execute(op, &mut self.stack),
+            Exec::Input => input::execute(&self.input, op, &mut self.stack),
+            Exec::File => files::execute(&self.files, &mut self.heap, op, &mut self.stack),
+        };
+        Some(result.unwrap_or_else(|| panic!("`{}` is not run by its executor", op)))
+    }
+
+    // Pops a null-terminated string of characters for `op`, as pushed by
+    // `pushstr`
+    pub fn collect_str(&mut self, op: Op) -> Result<String, Trap> {
+        let chars = strings::pop_chars(op, &mut self.stack)?;
+        Ok(chars.into_iter().map(|v| (v as u8) as char).collect())
+    }
+
+    // Frees heap objects that nothing on the stack or in memory refers to
+    pub fn collect_garbage(&mut self) {
+        let memory = self.memory.windows(4).map(|w| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(w);
+            i32::from_le_bytes(bytes)
+        });
+        let envs = self.envs.iter().flatten().copied();
+        let saved = self.try_stacks.iter().flatten().copied();
+        self.heap.collect(
+            self.stack
+                .iter()
+                .copied()
+                .chain(memory)
+                .chain(envs)
+                .chain(saved),
+        );
+    }
+
+    /*
+     * The handle `fnref` pushes for a defined function. Functions are
+     * numbered from 1 in the order they are defined or first referenced, and
+     * keep their handle if they are redefined.
+     */
+    pub fn function_handle(&mut self, name: &str) -> Option<i32> {
+        if !self.function_table.contains_key(name) {
+            return None;
+        }
+        let index = match self.function_handles.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.function_handles.push(name.to_string());
+                self.function_handles.len() - 1
+            }
+        };
+        Some(index as i32 + 1)
+    }
+
+    /*
+     * Makes `function` callable with `callext name`. It takes `inputs` values
+     * off the stack and leaves `outputs` in their place.
+     */
+    pub fn register<F>(&mut self, name: &str, inputs: usize, outputs: usize, function: F)
+    where
+        F: FnMut(&mut HostContext) -> Result<(), Trap> + Send + 'static,
+    {
+        let signature = Signature { inputs, outputs };
+        self.register_host(
+            name,
+            HostClosure {
+                signature,
+                function,
+            },
+        );
+    }
+
+    pub fn register_host<H: HostFunction + 'static>(&mut self, name: &str, function: H) {
+        self.host_functions
+            .insert(name.to_string(), Arc::new(Mutex::new(function)));
+    }
+
+    // What the registered host functions declared, for `check::check_with_externals`
+    pub fn host_signatures(&self) -> BTreeMap<String, Signature> {
+        self.host_functions
+            .iter()
+            .map(|(name, f)| (name.clone(), f.lock().unwrap().signature()))
+            .collect()
+    }
+
+    fn call_host(&mut self, key: &str) -> Option<Result<(), Trap>> {
+        let function = Arc::clone(self.host_functions.get(key)?);
+        let mut function = function.lock().unwrap();
+        let signature = function.signature();
+        if self.stack.len() < signature.inputs {
+            return Some(Err(Trap::StackUnderflow(Op::CallExt)));
+        }
+        let expected = self.stack.len() - signature.inputs + signature.outputs;
+        let mut ctx = HostContext {
+            stack: &mut self.stack,
+            memory: &mut self.memory,
+            heap: &mut self.heap,
+            pid: self.pid,
+        };
+        let result = function.call(&mut ctx);
+        if result.is_ok() && self.stack.len() != expected {
+            panic!(
+                "Host function {} declared {} but left the stack at {} values instead of {}.",
+                key,
+                signature,
+                self.stack.len(),
+                expected
+            );
+        }
+        Some(result)
+    }
+
+    // The function a handle from `fnref` refers to
+    fn function_name(&self, op: Op, handle: i32) -> Result<String, Trap> {
+        usize::try_from(handle)
+            .ok()
+            .and_then(|h| self.function_handles.get(h.checked_sub(1)?))
+            .cloned()
+            .ok_or(Trap::BadHandle(op))
+    }
+
+    // A `return` (or stray `break`) ends the function only. `env` is the
+    // closure the function is called through, if any.
+    fn call(&mut self, key: &str, env: Option<i32>) -> Flow {
+        match self.function_table.get(key) {
+            Some(body) => {
+                let body = body.to_vec();
+                self.envs.push(env);
+                self.calls.push(key.to_string());
+                let flow = self.run(body);
+                // The innermost call that sees a trap knows the whole stack
+                if matches!(flow, Flow::Trap(_)) && self.backtrace.is_empty() {
+                    self.backtrace = self.calls.clone();
                 }
+                self.calls.pop();
+                self.envs.pop();
+                match flow {
+                    Flow::Trap(_) | Flow::Exit(_) => flow,
+                    _ => Flow::Continue,
+                }
+            }
+            None => Flow::Trap(Trap::UndefinedFunction(key.to_string())),
+        }
+    }
+
+    /*
+     * Runs a `try` block, leaving `index` on the matching `endtry`. If the
+     * code up to `catch` fails, the stack is put back the way it was at `try`,
+     * the error code pushed and the code after `catch` run instead.
+     */
+    fn r#try(&mut self, index: &mut usize, code: &[(Op, Option<i32>)]) -> Flow {
+        let start = *index + 1;
+        let (catch_idx, end) = match_block(code, *index, Op::Try, Op::EndTry);
+        let catch_idx = match catch_idx {
+            Some(i) => i,
+            None => panic!("Each `try` must have a `catch`."),
+        };
+        *index = end;
+
+        // Kept on the machine so the collector sees what it refers to
+        self.try_stacks.push(self.stack.clone());
+        let flow = self.run(code[start..catch_idx].to_vec());
+        let saved = self.try_stacks.pop().unwrap();
+        match flow {
+            Flow::Trap(trap) => {
+                self.stack = saved;
+                self.push(trap.code());
+                self.backtrace.clear();
+                self.run(code[catch_idx + 1..end].to_vec())
+            }
+            flow => flow,
+        }
+    }
+
+    /*
+     * Describes a trap that nothing caught, along with the functions that
+     * were running when it happened, innermost first.
+     */
+    pub fn trap_report(&self, trap: &Trap) -> String {
+        let mut report = format!("Trap: {}.", trap);
+        for name in self.backtrace.iter().rev() {
+            report.push_str(&format!("\n    in function {}", name));
+        }
+        report
+    }
+
+    // Values `op` takes off the stack, where that is known before it runs
+    fn pops(op: Op, arg: Option<i32>) -> Option<usize> {
+        match (op.info().effect, op) {
+            (Effect::Fixed(pops, _), _) => Some(pops),
+            (_, Op::Pick) | (_, Op::Roll) | (_, Op::Closure) => {
+                usize::try_from(arg?).ok().map(|n| n + 1)
             }
+            _ => None,
         }
-        res
     }
 
     pub fn new(memsize: u32) -> StackMachine {
         StackMachine {
             stack: Vec::<i32>::new(),
-            memory: Vec::with_capacity(memsize as usize),
+            memory: vec![0; memsize as usize],
+            heap: Heap::new(),
             ext_functions: HashMap::<String, fn(&mut Vec<i32>)>::new(),
+            host_functions: HashMap::new(),
             function_table: HashMap::<String, Vec<(Op, Option<i32>)>>::new(),
+            function_handles: Vec::new(),
+            envs: Vec::new(),
+            calls: Vec::new(),
+            try_stacks: Vec::new(),
+            backtrace: Vec::new(),
             pid: 0,
             child_pid: 0,
             child: false,
+            fork_policy: ForkPolicy::default(),
+            output: sink::stdout(),
+            trace: None,
+            input: input::stdin(),
+            files: Arc::new(Mutex::new(Files::new())),
+            args: Vec::new(),
+            vars: BTreeMap::new(),
+            exit_status: None,
         }
     }
 
-    fn r#if(&mut self, index: &mut usize, code: &mut Vec<(Op, Option<i32>)>) {
+    pub fn set_output<W: Write + Send + 'static>(&mut self, output: W) {
+        self.output = sink::sink(output);
+    }
+
+    pub fn set_trace<W: Write + Send + 'static>(&mut self, trace: W) {
+        self.trace = Some(sink::sink(trace));
+    }
+
+    pub fn set_input<R: BufRead + Send + 'static>(&mut self, input: R) {
+        self.input = input::source(input);
+    }
+
+    // Lets the `file` opcodes use `dir` and everything under it
+    pub fn allow<P: AsRef<Path>>(&mut self, dir: P, access: Access) -> io::Result<()> {
+        self.files.lock().unwrap().allow(dir, access)
+    }
+
+    fn print(&self, line: fmt::Arguments) {
+        sink::write_line(&self.output, line);
+    }
+
+    fn trace(&self, line: fmt::Arguments) {
+        if let Some(trace) = &self.trace {
+            sink::write_line(trace, line);
+        }
+    }
+
+    // The machine a `fork` runs the rest of the block on, following
+    // `fork_policy`
+    fn fork_child(&self, pid: u16) -> StackMachine {
+        let policy = self.fork_policy;
+        let mut sm = StackMachine::new(self.memory.len() as u32);
+        sm.pid = pid;
+        sm.child = true;
+        sm.fork_policy = policy;
+        sm.calls = self.calls.clone();
+        if policy.stack {
+            sm.stack = self.stack.clone();
+        }
+        if policy.memory {
+            sm.memory = self.memory.clone();
+        }
+        if policy.heap {
+            sm.heap = self.heap.clone();
+            sm.envs = self.envs.clone();
+        }
+        if policy.functions {
+            sm.function_table = self.function_table.clone();
+            sm.function_handles = self.function_handles.clone();
+        }
+        if policy.host_functions {
+            sm.host_functions = self.host_functions.clone();
+            sm.ext_functions = self.ext_functions.clone();
+        }
+        if policy.io {
+            sm.output = Arc::clone(&self.output);
+            sm.trace = self.trace.clone();
+            sm.input = Arc::clone(&self.input);
+            sm.files = Arc::clone(&self.files);
+            sm.args = self.args.clone();
+            sm.vars = self.vars.clone();
+        }
+        sm
+    }
+
+    /*
+     * Runs the branch of an `if` selected by the condition on top of the
+     * stack and leaves `index` on the matching `EndIf`.
+     */
+    fn r#if(&mut self, index: &mut usize, code: &[(Op, Option<i32>)]) -> Flow {
         // _a_ is the value that represents the conditional
-        if let Some(a) = self.pop() {
-            if a > 0 {
-                // The condition was met
-
-                /*
-                 * Search for either an EndIf statement that matches the same
-                 * nesting level or an else block that matches the same nesting
-                 * level.
-                 *
-                 * If an Else is found, the index is incremented to the end of the
-                 * `EndIf` statement, not the `Else` statement.
-                 */
-                let mut nest = 1;
-                let start = *index + 1;
-                while nest > 0 {
-                    *index += 1;
-                    nest += match code[*index] {
-                        (Op::Else, _) => {
-                            if nest == 1 {
-                                self.execute(
-                                    code.iter()
-                                        .cloned()
-                                        .skip(start)
-                                        .take(*index - start)
-                                        .collect(),
-                                );
-                                *index += code
-                                    .iter()
-                                    .cloned()
-                                    .skip(*index)
-                                    .take_while(|(x, _)| *x != Op::EndIf)
-                                    .count();
-                                return;
-                            }
-                            0
-                        }
-                        (Op::EndIf, _) => -1,
-                        (Op::If, _) => 1,
-                        _ => 0,
-                    };
-                }
-                // Execute through the end of the if block
-                self.execute(
-                    code.iter()
-                        .cloned()
-                        .skip(start)
-                        .take(*index - start)
-                        .collect(),
-                );
-            } else {
-                // The condition was not met
-
-                if *index == code.len() {
-                    return;
-                }
+        let a = match self.pop() {
+            Some(a) => a,
+            None => panic!("`if` statement called without anything on the stack!"),
+        };
 
-                /*
-                 * Find a matching else block that corresponds with the current
-                 * level of `if` block. If not found, the condition after this for
-                 * loop will fall through and execution will pick up at the end of
-                 * the `if` block.
-                 */
-                let mut nest = 1;
-                let mut else_idx = None; // starting index of else block
-                while nest > 0 {
-                    *index += 1;
-                    match code[*index] {
-                        (Op::EndIf, _) => {
-                            nest -= 1;
-                            if nest == 1 {
-                                break;
-                            }
-                        }
-                        (Op::Else, _) => {
-                            if nest == 1 {
-                                else_idx = Some(*index);
-                            }
-                        }
-                        (Op::If, _) => {
-                            nest += 1;
-                        }
-                        _ => (),
-                    };
-                }
+        let start = *index + 1;
+        let (else_idx, end) = match_block(code, *index, Op::If, Op::EndIf);
+        *index = end;
 
-                if let Some(idx) = else_idx {
-                    self.execute(
-                        code.iter()
-                            .cloned()
-                            .skip(idx + 1)
-                            .take(*index - idx - 1)
-                            .collect(),
-                    );
-                }
-            }
+        if a > 0 {
+            // The condition was met
+            self.run(code[start..else_idx.unwrap_or(end)].to_vec())
+        } else if let Some(idx) = else_idx {
+            self.run(code[idx + 1..end].to_vec())
         } else {
-            panic!("`if` statement called without anything on the stack!");
+            Flow::Continue
+        }
+    }
+
+    /*
+     * Runs a `block` once or a `loop` until it breaks, leaving `index` on the
+     * matching end. A `return` inside keeps unwinding to the function.
+     */
+    fn block(&mut self, index: &mut usize, code: &[(Op, Option<i32>)]) -> Flow {
+        let op = code[*index].0;
+        let end_op = if op == Op::Loop {
+            Op::EndLoop
+        } else {
+            Op::EndBlock
+        };
+        let start = *index + 1;
+        let (_, end) = match_block(code, *index, op, end_op);
+        *index = end;
+
+        let body: Vec<(Op, Option<i32>)> = code[start..end].to_vec();
+        loop {
+            match self.run(body.clone()) {
+                Flow::Break => return Flow::Continue,
+                Flow::Return => return Flow::Return,
+                Flow::Trap(trap) => return Flow::Trap(trap),
+                Flow::Exit(status) => return Flow::Exit(status),
+                Flow::Continue if op == Op::Block => return Flow::Continue,
+                Flow::Continue => (),
+            }
         }
     }
 
@@ -189,6 +648,13 @@ impl StackMachine {
         let mut elses = 0;
         let mut fns = 0;
         let mut endfns = 0;
+        let mut loops = 0;
+        let mut endloops = 0;
+        let mut blocks = 0;
+        let mut endblocks = 0;
+        let mut tries = 0;
+        let mut catches = 0;
+        let mut endtries = 0;
         for (op, _) in code {
             match op {
                 Op::If => ifs += 1,
@@ -196,6 +662,13 @@ impl StackMachine {
                 Op::Else => elses += 1,
                 Op::Function => fns += 1,
                 Op::EndFunction => endfns += 1,
+                Op::Loop => loops += 1,
+                Op::EndLoop => endloops += 1,
+                Op::Block => blocks += 1,
+                Op::EndBlock => endblocks += 1,
+                Op::Try => tries += 1,
+                Op::Catch => catches += 1,
+                Op::EndTry => endtries += 1,
                 _ => (),
             }
         }
@@ -208,18 +681,67 @@ impl StackMachine {
             panic!("Each `function` must have a matching `endfunction`. Got {} function statements and {} endfunction statements.",
                    fns, endfns);
         }
+        if loops != endloops {
+            panic!("Each `loop` must have a matching `endloop`. Got {} loop statements and {} endloop statements.",
+                   loops, endloops);
+        }
+        if blocks != endblocks {
+            panic!("Each `block` must have a matching `endblock`. Got {} block statements and {} endblock statements.",
+                   blocks, endblocks);
+        }
         if elses > ifs {
             panic!("`Else` may appear max of one time per if block.");
         }
+        if tries != endtries || tries != catches {
+            panic!("Each `try` must have one `catch` and a matching `endtry`. Got {} try, {} catch and {} endtry statements.",
+                   tries, catches, endtries);
+        }
+    }
+
+    // Loads the program's function table and then runs its code
+    pub fn execute_program(&mut self, program: &Program) {
+        if let Err(trap) = self.try_execute_program(program) {
+            panic!("{}", self.trap_report(&trap));
+        }
     }
 
-    pub fn execute(&mut self, mut code: Vec<(Op, Option<i32>)>) {
-        #[cfg(debug_assertions)]
-        println!("Executing routine: {:?}", code);
+    // Panics if the program traps; see `try_execute`
+    pub fn execute(&mut self, code: Vec<(Op, Option<i32>)>) {
+        if let Err(trap) = self.try_execute(code) {
+            panic!("{}", self.trap_report(&trap));
+        }
+    }
+
+    // Runs code, stopping at the first trap or `exit`. The stack is left as
+    // it was when the trapping instruction had popped its operands.
+    pub fn try_execute(&mut self, code: Vec<(Op, Option<i32>)>) -> Result<(), Trap> {
+        self.backtrace.clear();
+        self.exit_status = None;
+        match self.run(code) {
+            Flow::Trap(trap) => Err(trap),
+            Flow::Exit(status) => {
+                self.exit_status = Some(status);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn try_execute_program(&mut self, program: &Program) -> Result<(), Trap> {
+        for (name, body) in &program.functions {
+            self.function_table.insert(name.clone(), body.clone());
+            self.function_handle(name);
+        }
+        self.try_execute(program.code.clone())
+    }
+
+    fn run(&mut self, code: Vec<(Op, Option<i32>)>) -> Flow {
+        self.trace(format_args!("Executing routine: {:?}", code));
 
         self.syntax_check(&code);
         let mut children = Vec::<thread::JoinHandle<_>>::new();
         let mut index = 0;
+        let mut flow = Flow::Continue;
         loop {
             if index >= code.len() {
                 break;
@@ -227,160 +749,365 @@ impl StackMachine {
 
             let (op, arg) = &code[index];
 
-            // Match the opcodes with corresponding handlers or actions
-            match op {
-                Op::Const => {
-                    self.push(arg.unwrap());
-                }
-                Op::Add => {
-                    let a = self.pop().unwrap();
-                    let b = self.pop().unwrap();
-                    self.add(a, b);
-                }
-                Op::Sub => {
-                    let a = self.pop().unwrap();
-                    let b = self.pop().unwrap();
-                    self.sub(a, b);
-                }
-                Op::Mul => {
-                    let a = self.pop().unwrap();
-                    let b = self.pop().unwrap();
-                    self.mul(a, b);
-                }
-                Op::Div => {
-                    let a = self.pop().unwrap();
-                    let b = self.pop().unwrap();
-                    self.div(a, b);
-                }
-                Op::r#Eq => {
-                    let a = self.pop().unwrap();
-                    let b = self.pop().unwrap();
-                    if a == b {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
-                }
-                Op::Call => {
-                    let key = self.collect_str();
-                    if self.function_table.contains_key(&key) {
-                        self.execute(self.function_table.get(&key).unwrap().to_vec());
-                    } else {
-                        panic!(
-                            "Function {} was called, but no definition could be found.",
-                            key
-                        );
-                    }
-                }
-                Op::If => {
-                    self.r#if(&mut index, &mut code);
-                }
-                Op::Not => {
-                    let a = self.pop().unwrap();
-                    if a <= 0 {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
-                }
-                Op::Function => {
-                    let fn_body: Vec<(Op, Option<i32>)> = code
-                        .iter()
-                        .cloned()
-                        .skip(index + 1)
-                        .take_while(|(o, _)| *o != Op::EndFunction)
-                        .collect();
-                    index += fn_body.len() + 1;
-                    let key = self.collect_str();
-
-                    #[cfg(debug_assertions)]
-                    println!("{} => {:?} ({} lines)", key, fn_body, fn_body.len());
-
-                    self.function_table.insert(key, fn_body);
-                }
-                Op::Else | Op::EndIf => {
-                    panic!("Each `else` or `endif` must have a matching `if` statement!")
-                }
-                // Simluates a fork system call using threads.
-                // Creates a new stack machine on the new thread, pushes the
-                // rest of the code to currently being executed on this stack
-                // machine, and sets the child's PID and 'child' member.
-                //
-                // TODO: favor a rudimentary scheduler instead of using threads
-                Op::Fork => {
-                    let child_code = code
-                        .iter()
-                        .cloned()
-                        .skip(index + 1) // omitting the +1 leades to infinite threads
-                        .collect();
-                    let stack = self.stack.clone();
-                    self.child_pid *= 2;
-                    let child_pid = self.child_pid + 1;
-                    self.child = false;
-                    children.push(
-                        thread::Builder::new()
-                            .name(format!("Thread<{}>", child_pid).to_string())
-                            .spawn(move || {
-                                let mut sm = StackMachine::new(2u32.pow(16));
-                                sm.pid = child_pid;
-                                sm.stack = stack;
-                                sm.child = true;
-                                sm.execute(child_code);
-                            })
-                            .expect("Could not spawn thread"),
-                    );
-                }
-                Op::GetPid => {
-                    self.push(self.pid.into());
-                }
-                Op::Child => {
-                    if self.child {
-                        self.push(1);
-                    } else {
-                        self.push(0);
-                    }
+            if let Some(pops) = StackMachine::pops(*op, *arg) {
+                if self.stack.len() < pops {
+                    flow = Flow::Trap(Trap::StackUnderflow(*op));
+                    break;
                 }
-                Op::Pop => {
-                    self.pop().unwrap();
+            }
+
+            // The opcode table says where most opcodes run; the ones left for
+            // the machine itself are matched here
+            match self.dispatch(*op, *arg) {
+                Some(Ok(())) => (),
+                Some(Err(trap)) => {
+                    flow = Flow::Trap(trap);
+                    break;
                 }
-                Op::Push => self.push(arg.unwrap()),
-
-                // Call external function described in-code
-                Op::CallExt => {
-                    let key = self.collect_str();
-                    if self.ext_functions.contains_key(&key) {
-                        (self.ext_functions.get(&key).unwrap())(&mut self.stack);
-                    } else {
-                        panic!(
-                            "External function {} was called, but no definition could be found.",
-                            key
-                        );
+                None => {
+                    match op {
+                        Op::Call => {
+                            let key = match self.collect_str(*op) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            flow = self.call(&key, None);
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        Op::FnRef => {
+                            let key = match self.collect_str(*op) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            match self.function_handle(&key) {
+                                Some(handle) => self.push(handle),
+                                None => {
+                                    flow = Flow::Trap(Trap::UndefinedFunction(key));
+                                    break;
+                                }
+                            }
+                        }
+                        Op::CallIndirect => {
+                            let handle = self.pop().unwrap();
+                            let key = match self.function_name(*op, handle) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            flow = self.call(&key, None);
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        Op::Closure => {
+                            let n = arg.unwrap();
+                            let handle = self.pop().unwrap();
+                            let key = match self.function_name(*op, handle) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            let at = match usize::try_from(n)
+                                .ok()
+                                .and_then(|n| self.stack.len().checked_sub(n))
+                            {
+                                Some(at) => at,
+                                None => {
+                                    flow = Flow::Trap(Trap::OutOfRange(*op));
+                                    break;
+                                }
+                            };
+                            let env = self.stack.split_off(at);
+                            let handle = self.heap.alloc(heap::Object::Closure(key, env));
+                            self.push(handle);
+                        }
+                        Op::CallClosure => {
+                            let handle = self.pop().unwrap();
+                            let key = match self.heap.closure(handle) {
+                                Some((key, _)) => key.to_string(),
+                                None => {
+                                    flow = Flow::Trap(Trap::BadHandle(*op));
+                                    break;
+                                }
+                            };
+                            flow = self.call(&key, Some(handle));
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        // Outside of a closure there are no values to get
+                        Op::EnvGet => {
+                            let env = match self.envs.last() {
+                                Some(Some(handle)) => self.heap.closure(*handle).map(|c| c.1),
+                                _ => None,
+                            };
+                            match usize::try_from(arg.unwrap()).ok().and_then(|i| env?.get(i)) {
+                                Some(v) => {
+                                    let v = *v;
+                                    self.push(v);
+                                }
+                                None => {
+                                    flow = Flow::Trap(Trap::OutOfRange(*op));
+                                    break;
+                                }
+                            }
+                        }
+                        Op::If => {
+                            flow = self.r#if(&mut index, &code);
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        Op::Try => {
+                            flow = self.r#try(&mut index, &code);
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        Op::Throw => {
+                            let error = self.pop().unwrap();
+                            flow = Flow::Trap(Trap::Thrown(error));
+                            break;
+                        }
+                        Op::Exit => {
+                            let status = self.pop().unwrap();
+                            flow = Flow::Exit(status);
+                            break;
+                        }
+                        Op::Argc => self.push(self.args.len() as i32),
+                        Op::Argv => {
+                            let n = self.pop().unwrap();
+                            match usize::try_from(n).ok().and_then(|i| self.args.get(i)) {
+                                Some(arg) => {
+                                    let handle = self.heap.alloc(Object::Str(arg.clone()));
+                                    self.push(handle);
+                                }
+                                None => {
+                                    flow = Flow::Trap(Trap::OutOfRange(*op));
+                                    break;
+                                }
+                            }
+                        }
+                        Op::GetEnv => {
+                            let name = self.pop().unwrap();
+                            let value = match self.heap.string(name) {
+                                Some(name) => self.vars.get(name).cloned(),
+                                None => {
+                                    flow = Flow::Trap(Trap::BadHandle(*op));
+                                    break;
+                                }
+                            };
+                            let set = value.is_some() as i32;
+                            let handle = self.heap.alloc(Object::Str(value.unwrap_or_default()));
+                            self.push(handle);
+                            self.push(set);
+                        }
+                        Op::Block | Op::Loop => {
+                            flow = self.block(&mut index, &code);
+                            if flow != Flow::Continue {
+                                break;
+                            }
+                        }
+                        Op::Break => {
+                            flow = Flow::Break;
+                            break;
+                        }
+                        Op::Return => {
+                            flow = Flow::Return;
+                            break;
+                        }
+                        Op::Function => {
+                            let fn_body: Vec<(Op, Option<i32>)> = code
+                                .iter()
+                                .cloned()
+                                .skip(index + 1)
+                                .take_while(|(o, _)| *o != Op::EndFunction)
+                                .collect();
+                            index += fn_body.len() + 1;
+                            let key = match self.collect_str(*op) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+
+                            self.trace(format_args!(
+                                "{} => {:?} ({} lines)",
+                                key,
+                                fn_body,
+                                fn_body.len()
+                            ));
+
+                            self.function_table.insert(key.clone(), fn_body);
+                            self.function_handle(&key);
+                        }
+                        Op::Else | Op::EndIf => {
+                            panic!("Each `else` or `endif` must have a matching `if` statement!")
+                        }
+                        Op::Catch | Op::EndTry => {
+                            panic!("Each `catch` or `endtry` must have a matching `try` statement!")
+                        }
+                        Op::EndLoop | Op::EndBlock => {
+                            panic!("Each `endloop` or `endblock` must have a matching `loop` or `block`!")
+                        }
+                        // Simluates a fork system call using threads.
+                        // Creates a new stack machine on the new thread, pushes the
+                        // rest of the code to currently being executed on this stack
+                        // machine, and sets the child's PID and 'child' member.
+                        //
+                        // TODO: favor a rudimentary scheduler instead of using threads
+                        Op::Fork => {
+                            let child_code = code
+                                .iter()
+                                .cloned()
+                                .skip(index + 1) // omitting the +1 leades to infinite threads
+                                .collect();
+                            self.child_pid *= 2;
+                            let child_pid = self.child_pid + 1;
+                            self.child = false;
+                            let mut sm = self.fork_child(child_pid);
+                            children.push(
+                                thread::Builder::new()
+                                    .name(format!("Thread<{}>", child_pid).to_string())
+                                    .spawn(move || {
+                                        // A trap or `exit` ends the child only, like a
+                                        // process exiting; the parent's status is its own.
+                                        // A trap is reported on stderr and nowhere else:
+                                        // the parent neither sees it nor fails because of it
+                                        if let Err(trap) = sm.try_execute(child_code) {
+                                            eprintln!(
+                                                "Thread<{}>: {}",
+                                                child_pid,
+                                                sm.trap_report(&trap)
+                                            );
+                                        }
+                                    })
+                                    .expect("Could not spawn thread"),
+                            );
+                        }
+                        Op::GetPid => {
+                            self.push(self.pid.into());
+                        }
+                        Op::Child => {
+                            if self.child {
+                                self.push(1);
+                            } else {
+                                self.push(0);
+                            }
+                        }
+                        Op::Load => {
+                            let address = self.pop().unwrap();
+                            if let Err(trap) = self.load(address) {
+                                flow = Flow::Trap(trap);
+                                break;
+                            }
+                        }
+                        Op::Store => {
+                            let address = self.pop().unwrap();
+                            let value = self.pop().unwrap();
+                            if let Err(trap) = self.store(address, value) {
+                                flow = Flow::Trap(trap);
+                                break;
+                            }
+                        }
+
+                        // Call external function described in-code
+                        Op::CallExt => {
+                            let key = match self.collect_str(*op) {
+                                Ok(key) => key,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            if let Some(result) = self.call_host(&key) {
+                                if let Err(trap) = result {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            } else if self.ext_functions.contains_key(&key) {
+                                (self.ext_functions.get(&key).unwrap())(&mut self.stack);
+                            } else {
+                                flow = Flow::Trap(Trap::UndefinedFunction(key));
+                                break;
+                            }
+                        }
+                        Op::PrintStr => {
+                            let handle = self.pop().unwrap();
+                            match self.heap.string(handle) {
+                                Some(s) => self.print(format_args!("{}", s)),
+                                None => {
+                                    flow = Flow::Trap(Trap::BadHandle(*op));
+                                    break;
+                                }
+                            }
+                        }
+                        Op::PrintChars => {
+                            let s = match self.collect_str(*op) {
+                                Ok(s) => s,
+                                Err(trap) => {
+                                    flow = Flow::Trap(trap);
+                                    break;
+                                }
+                            };
+                            self.print(format_args!("{}", s));
+                        }
+                        Op::Print => {
+                            self.print(format_args!("{}", self.last().unwrap()));
+                        }
+                        Op::Debug => {
+                            self.print(format_args!("DEBUG::{}", self));
+                        }
+                        Op::I64Print | Op::FPrint => {
+                            self.print(format_args!("{}", wide::format_top(*op, &self.stack)));
+                        }
+                        Op::Alloc => match self.heap.new_array(*op, arg.unwrap()) {
+                            Ok(handle) => self.push(handle),
+                            Err(trap) => {
+                                flow = Flow::Trap(trap);
+                                break;
+                            }
+                        },
+                        _ => panic!("Command {:?} not implemented.", op),
                     }
                 }
-                Op::PrintStr => {
-                    let s = self.collect_str();
-                    println!("{}", s);
-                }
-                Op::Print => {
-                    println!("{}", self.last().unwrap());
-                }
-                Op::Debug => {
-                    println!("DEBUG::{}", self);
-                }
-                _ => panic!("Command {:?} not implemented.", op),
-            };
+            }
+            if self.heap.wants_collection() {
+                self.collect_garbage();
+            }
             index += 1;
         }
 
-        // wait for children to finish
+        // wait for children to finish, passing on a child's panic as it was
         for handle in children {
-            handle.join().unwrap();
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
         }
+        flow
     }
 }
 
 impl fmt::Display for StackMachine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "StackMachine<{}, {:?}>", self.pid, self.stack)
+        write!(f, "StackMachine<{}, {:?}>", self.pid, self.stack)?;
+        // Programs that never touch the heap print what they always have
+        if self.heap.stats.allocated > 0 {
+            write!(f, " {}", self.heap)?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+
+use crate::stackmachine::program::Program;
+use crate::stackmachine::{arith, Op};
+
+/*
+ * Peephole optimizer over the instruction vector. Each pass rewrites a short
+ * pattern into something with the same observable behaviour, and passes are
+ * repeated until nothing changes:
+ *
+ *   - `const a const b <op>` and `const a <op>` fold to a single constant for
+ *     arithmetic and comparisons, unless the VM would trap
+ *   - `const c if ... [else ...] endif` keeps only the branch that runs
+ *   - code after `break` or `return` up to the end of its block is dropped
+ *   - `not not` is dropped when the value is already 0 or 1, or is only
+ *     used as an `if` condition
+ *   - functions that are never called are dropped, as long as every call in
+ *     the program names its function with a string literal
+ *
+ * Instructions remember where they came from so that debug info survives.
+ */
+
+#[derive(Clone, Copy)]
+struct Insn {
+    op: Op,
+    arg: Option<i32>,
+    // Index in the original code, for debug spans
+    origin: usize,
+}
+
+fn fold(op: Op, b: i32, a: i32) -> Option<i32> {
+    // `a` was pushed last, so it is popped first
+    if let Some(result) = arith::binary(op, a, b) {
+        return result.ok();
+    }
+    match op {
+        Op::r#Eq => Some((a == b) as i32),
+        Op::GT => Some((a > b) as i32),
+        Op::LT => Some((a < b) as i32),
+        Op::GTE => Some((a >= b) as i32),
+        Op::LTE => Some((a <= b) as i32),
+        _ => None,
+    }
+}
+
+fn constant_fold(code: &mut Vec<Insn>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < code.len() {
+        if code[i].op != Op::Const {
+            i += 1;
+            continue;
+        }
+        let a = code[i].arg.unwrap_or(0);
+        let unary = match code[i + 1].op {
+            Op::Not => Some((a <= 0) as i32),
+            op => arith::unary(op, a).and_then(Result::ok),
+        };
+        if let Some(v) = unary {
+            code[i].arg = Some(v);
+            code[i].origin = code[i + 1].origin;
+            code.remove(i + 1);
+            changed = true;
+            continue;
+        }
+        if i + 2 < code.len() && code[i + 1].op == Op::Const {
+            if let (Some(b), Some(a)) = (code[i].arg, code[i + 1].arg) {
+                if let Some(v) = fold(code[i + 2].op, b, a) {
+                    code[i] = Insn {
+                        op: Op::Const,
+                        arg: Some(v),
+                        origin: code[i + 2].origin,
+                    };
+                    code.drain(i + 1..i + 3);
+                    changed = true;
+                    // The result may fold with what comes before it
+                    i = i.saturating_sub(1);
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// Index of the `else` (if any) and `endif` matching the `if` at `start`
+fn if_bounds(code: &[Insn], start: usize) -> Option<(Option<usize>, usize)> {
+    let mut nest = 0;
+    let mut else_idx = None;
+    for (i, insn) in code.iter().enumerate().skip(start + 1) {
+        match insn.op {
+            Op::If => nest += 1,
+            Op::Else if nest == 0 => else_idx = Some(i),
+            Op::EndIf if nest == 0 => return Some((else_idx, i)),
+            Op::EndIf => nest -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+fn eliminate_branches(code: &mut Vec<Insn>) -> bool {
+    let mut i = 0;
+    while i + 1 < code.len() {
+        if code[i].op == Op::Const && code[i + 1].op == Op::If {
+            if let Some((else_idx, end)) = if_bounds(code, i + 1) {
+                let taken = if code[i].arg.unwrap_or(0) > 0 {
+                    i + 2..else_idx.unwrap_or(end)
+                } else {
+                    else_idx.map_or(end..end, |e| e + 1..end)
+                };
+
+                // A fork inside a branch only copies the rest of that branch
+                // to the child, so inlining it would change what the child runs.
+                if !code[i + 2..end].iter().any(|insn| insn.op == Op::Fork) {
+                    let body: Vec<Insn> = code[taken].to_vec();
+                    code.splice(i..=end, body);
+                    return true;
+                }
+            }
+        }
+        i += 1;
+    }
+    false
+}
+
+fn opens_block(op: Op) -> bool {
+    matches!(op, Op::If | Op::Block | Op::Loop | Op::Function | Op::Try)
+}
+
+fn closes_block(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Else
+            | Op::EndIf
+            | Op::EndBlock
+            | Op::EndLoop
+            | Op::EndFunction
+            | Op::Catch
+            | Op::EndTry
+    )
+}
+
+fn remove_dead_code(code: &mut Vec<Insn>) -> bool {
+    let mut changed = false;
+    for i in 0..code.len() {
+        if i >= code.len() {
+            break;
+        }
+        if !matches!(code[i].op, Op::Break | Op::Return | Op::Throw | Op::Exit) {
+            continue;
+        }
+        let mut depth = 0;
+        let mut end = code.len();
+        for (j, insn) in code.iter().enumerate().skip(i + 1) {
+            if opens_block(insn.op) {
+                depth += 1;
+            } else if closes_block(insn.op) && !matches!(insn.op, Op::Else | Op::Catch) {
+                if depth == 0 {
+                    end = j;
+                    break;
+                }
+                depth -= 1;
+            } else if matches!(insn.op, Op::Else | Op::Catch) && depth == 0 {
+                end = j;
+                break;
+            }
+        }
+        if end > i + 1 {
+            code.drain(i + 1..end);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Opcodes that always leave 0 or 1 on the stack
+fn is_boolean(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Not | Op::r#Eq | Op::GT | Op::LT | Op::GTE | Op::LTE | Op::Child
+    )
+}
+
+fn remove_double_not(code: &mut Vec<Insn>) -> bool {
+    let mut i = 0;
+    while i + 1 < code.len() {
+        if code[i].op == Op::Not && code[i + 1].op == Op::Not {
+            let boolean_input = i > 0 && is_boolean(code[i - 1].op);
+            let feeds_if = code.get(i + 2).map(|insn| insn.op) == Some(Op::If);
+            if boolean_input || feeds_if {
+                code.drain(i..i + 2);
+                return true;
+            }
+        }
+        i += 1;
+    }
+    false
+}
+
+/*
+ * If the instructions before `end` are `const 0` followed by the characters
+ * of a string literal, returns the index of the `const 0` and the string.
+ */
+fn literal_before(code: &[Insn], end: usize) -> Option<(usize, String)> {
+    let mut name = String::new();
+    let mut i = end;
+    while i > 0 {
+        i -= 1;
+        match (code[i].op, code[i].arg) {
+            (Op::Const, Some(0)) => return Some((i, name)),
+            (Op::Const, Some(c)) if (1..=0x7f).contains(&c) => name.push(c as u8 as char),
+            _ => return None,
+        }
+    }
+    None
+}
+
+// Names of every function called anywhere, or `None` if some call's target is
+// computed at run time
+fn called_functions<'a, I>(streams: I) -> Option<HashSet<String>>
+where
+    I: Iterator<Item = &'a Vec<Insn>>,
+{
+    let mut called = HashSet::new();
+    for code in streams {
+        for (i, insn) in code.iter().enumerate() {
+            // A referenced function may be called indirectly
+            if insn.op == Op::Call || insn.op == Op::FnRef {
+                called.insert(literal_before(code, i)?.1);
+            }
+        }
+    }
+    Some(called)
+}
+
+fn remove_unused_definitions(code: &mut Vec<Insn>, called: &HashSet<String>) -> bool {
+    for i in 0..code.len() {
+        if code[i].op != Op::Function {
+            continue;
+        }
+        if let Some((start, name)) = literal_before(code, i) {
+            if called.contains(&name) {
+                continue;
+            }
+            let end = (i..code.len())
+                .find(|j| code[*j].op == Op::EndFunction)
+                .unwrap_or(code.len() - 1);
+            code.drain(start..=end);
+            return true;
+        }
+    }
+    false
+}
+
+fn lift(code: &[(Op, Option<i32>)]) -> Vec<Insn> {
+    code.iter()
+        .enumerate()
+        .map(|(origin, (op, arg))| Insn {
+            op: *op,
+            arg: *arg,
+            origin,
+        })
+        .collect()
+}
+
+fn lower(code: &[Insn]) -> Vec<(Op, Option<i32>)> {
+    code.iter().map(|insn| (insn.op, insn.arg)).collect()
+}
+
+fn peephole(code: &mut Vec<Insn>) -> bool {
+    let mut changed = false;
+    while constant_fold(code)
+        | eliminate_branches(code)
+        | remove_dead_code(code)
+        | remove_double_not(code)
+    {
+        changed = true;
+    }
+    changed
+}
+
+pub fn optimize_code(code: &[(Op, Option<i32>)]) -> Vec<(Op, Option<i32>)> {
+    optimize(&Program::new(code.to_vec())).code
+}
+
+pub fn optimize(program: &Program) -> Program {
+    let mut code = lift(&program.code);
+    let mut functions: Vec<(String, Vec<Insn>)> = program
+        .functions
+        .iter()
+        .map(|(name, body)| (name.clone(), lift(body)))
+        .collect();
+
+    loop {
+        let mut changed = peephole(&mut code);
+        for (_, body) in functions.iter_mut() {
+            changed |= peephole(body);
+        }
+
+        let streams = std::iter::once(&code).chain(functions.iter().map(|(_, b)| b));
+        if let Some(called) = called_functions(streams) {
+            while remove_unused_definitions(&mut code, &called) {
+                changed = true;
+            }
+            let before = functions.len();
+            functions.retain(|(name, _)| called.contains(name));
+            changed |= functions.len() != before;
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let debug = program.debug.as_ref().map(|d| {
+        let mut debug = d.clone();
+        debug.spans = code.iter().map(|insn| d.spans[insn.origin]).collect();
+        debug
+    });
+    Program {
+        code: lower(&code),
+        functions: functions
+            .iter()
+            .map(|(name, body)| (name.clone(), lower(body)))
+            .collect(),
+        debug,
+    }
+}
+
+#[cfg(test)]
+mod optimize_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+    use crate::stackmachine::sink::Capture;
+    use crate::stackmachine::StackMachine;
+
+    fn source(src: &str) -> Vec<(Op, Option<i32>)> {
+        reader::parse(src, "t.sm").code
+    }
+
+    // The final stack and everything the program printed
+    fn run(code: Vec<(Op, Option<i32>)>) -> (Vec<i32>, String) {
+        let output = Capture::new();
+        let mut sm = StackMachine::new(2u32.pow(8));
+        sm.set_output(output.clone());
+        sm.execute(code);
+        (sm.stack, output.contents())
+    }
+
+    // Programs whose final stack and output must not change under optimization
+    const CASES: &[&str] = &[
+        "const 2\nconst 3\nadd\nconst 4\nmul\nconst 1\nsub",
+        "const 7\nconst 2\ndiv\nconst 3\neq",
+        "const 5\nconst 0\ndiv\npop",
+        "const 1\nif\nconst 2\nelse\nconst 3\nendif",
+        "const 0\nif\nconst 2\nelse\nconst 3\nendif",
+        "const 0\nif\nconst 2\nendif\nconst 4",
+        "const 1\nconst 1\neq\nif\nconst 1\nif\nconst 9\nendif\nendif",
+        "const 5\nnot\nnot",
+        "const 5\nconst 5\neq\nnot\nnot",
+        "const 5\nnot\nnot\nif\nconst 1\nendif",
+        "const 0\nnot\nnot\nnot",
+        "block\nconst 1\nbreak\nconst 2\nconst 3\nendblock\nconst 4",
+        "const 3\nloop\nconst -1\nadd\nconst 1\nif\nbreak\nconst 8\nendif\nendloop",
+        "const 0\npushstr unused\nfunction\nadd\nendfunction\nconst 1",
+        "const 0\npushstr f\nfunction\nconst 2\nconst 3\nmul\nreturn\nconst 4\nendfunction\nconst 0\npushstr f\ncall",
+        "const 2147483647\nconst 1\nsub\nconst 3\nconst 4\ngt\nconst 3\nconst 4\nlte",
+        "const 2\nconst 3\nadd\nprint\nconst 4\nmul\nprint",
+        "const 1\nconst 2\nprint\npop\nprint\nnot\nnot\nprint",
+        "const 0\nif\nconst 1\nprint\nendif\nconst 2\nprint\npop",
+        "block\nconst 1\nprint\nbreak\nconst 2\nprint\nendblock\ndbg",
+        "const 0\npushstr hi\nprintchars\nconst 5\nconst 5\neq\nprint",
+    ];
+
+    #[test]
+    fn test_differential() {
+        for case in CASES {
+            let code = source(case);
+            let optimized = optimize_code(&code);
+
+            assert_eq!(run(code.clone()), run(optimized.clone()), "{}", case);
+            assert!(optimized.len() <= code.len(), "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_differential_examples() {
+        for name in &["adder", "cond", "fn_call", "mul", "print_string", "recurse"] {
+            let code = reader::read(&format!("examples/{}.sm", name)).unwrap();
+            let optimized = optimize_code(&code);
+
+            assert_eq!(run(code), run(optimized), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        let optimized = optimize_code(&source("const 2\nconst 3\nadd\nconst 4\nmul"));
+
+        assert_eq!(vec![(Op::Const, Some(20))], optimized);
+
+        let optimized = optimize_code(&source("const 1\nconst 2147483647\nadd\nneg\nconst 3\nxor"));
+
+        assert_eq!(vec![(Op::Const, Some(i32::MIN ^ 3))], optimized);
+    }
+
+    #[test]
+    fn test_keeps_traps() {
+        let code = source("const 0\nconst 5\ndiv");
+
+        assert_eq!(code, optimize_code(&code));
+
+        let code = source("const 1\nconst 2147483647\nadd.checked");
+
+        assert_eq!(code, optimize_code(&code));
+    }
+
+    #[test]
+    fn test_branch_elimination() {
+        let optimized = optimize_code(&source("const 0\nif\nconst 2\nelse\nconst 3\nendif"));
+
+        assert_eq!(vec![(Op::Const, Some(3))], optimized);
+    }
+
+    #[test]
+    fn test_keeps_fork_branches() {
+        let code = source("const 1\nif\nfork\nconst 2\nendif");
+
+        assert_eq!(code, optimize_code(&code));
+    }
+
+    #[test]
+    fn test_dead_code_after_throw() {
+        let code = source("try\nthrow 1\nconst 2\ncatch\nconst 3\nendtry");
+
+        assert_eq!(
+            source("try\nthrow 1\ncatch\nconst 3\nendtry"),
+            optimize_code(&code)
+        );
+    }
+
+    #[test]
+    fn test_prunes_unused_functions() {
+        let code = source("const 0\npushstr f\nfunction\nadd\nendfunction\nconst 1");
+
+        assert_eq!(vec![(Op::Const, Some(1))], optimize_code(&code));
+    }
+
+    #[test]
+    fn test_keeps_referenced_functions() {
+        let code = source("const 0\npushstr f\nfunction\nadd\nendfunction\nfnref f");
+
+        assert_eq!(code, optimize_code(&code));
+    }
+
+    #[test]
+    fn test_keeps_functions_with_dynamic_calls() {
+        let code = source("const 0\npushstr f\nfunction\nadd\nendfunction\nchild\ncall");
+
+        assert_eq!(code, optimize_code(&code));
+    }
+
+    #[test]
+    fn test_debug_spans_follow_code() {
+        let program = reader::parse("const 1\nconst 2\nadd\nprint", "t.sm");
+        let optimized = optimize(&program);
+        let debug = optimized.debug.unwrap();
+
+        assert_eq!(2, debug.spans.len());
+        assert_eq!(Some("t.sm:3:1".to_string()), debug.location(0));
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::stackmachine::disasm;
+use crate::stackmachine::Op;
+
+/*
+ * Layout of a `.smb` file. All multi-byte integers other than the header are
+ * LEB128 varints; signed constants are zigzag encoded first.
+ *
+ *   magic        4 bytes   `SMB\0`
+ *   version      u16 LE
+ *   flags        u16 LE    bit 0 set when a debug section follows the code
+ *   constants    count, then each constant
+ *   functions    count, then (name length, name bytes, instruction stream)
+ *   code         instruction stream
+ *   debug        file count, file names, then (file, line, column) per
+ *                instruction of `code`
+ *
+ * An instruction stream is a count followed by (opcode, operand) pairs, where
+ * operand 0 means "no argument" and n > 0 refers to constant n - 1.
+ *
+ * Version 1 files predate heap strings; their `printstr` is read as
+ * `printchars`, which prints the way `printstr` used to.
+ */
+pub const MAGIC: &[u8; 4] = b"SMB\0";
+pub const VERSION: u16 = 2;
+
+const FLAG_DEBUG: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    // One span per instruction of `Program::code`
+    pub spans: Vec<Span>,
+}
+
+impl DebugInfo {
+    pub fn file_index(&mut self, filename: &str) -> u32 {
+        match self.files.iter().position(|f| f == filename) {
+            Some(i) => i as u32,
+            None => {
+                self.files.push(filename.to_string());
+                (self.files.len() - 1) as u32
+            }
+        }
+    }
+
+    // Human readable `file:line:column` for the instruction at `index`
+    pub fn location(&self, index: usize) -> Option<String> {
+        let span = self.spans.get(index)?;
+        let file = self.files.get(span.file as usize)?;
+        Some(format!("{}:{}:{}", file, span.line, span.column))
+    }
+}
+
+/*
+ * A loadable unit of code: the top-level instruction stream, any functions
+ * that should be in the function table before it runs, and optionally the
+ * source spans each instruction came from.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub code: Vec<(Op, Option<i32>)>,
+    pub functions: BTreeMap<String, Vec<(Op, Option<i32>)>>,
+    pub debug: Option<DebugInfo>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    VarintOverflow,
+    InvalidOpcode(u64),
+    InvalidConstant(u64),
+    InvalidUtf8,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a stackmachine binary (bad magic)"),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported format version {} (expected {})", v, VERSION)
+            }
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::VarintOverflow => write!(f, "varint does not fit in 64 bits"),
+            DecodeError::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+            DecodeError::InvalidConstant(i) => write!(f, "constant index {} out of range", i),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} unexpected trailing bytes", n),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Program {
+    pub fn new(code: Vec<(Op, Option<i32>)>) -> Program {
+        Program {
+            code,
+            functions: BTreeMap::new(),
+            debug: None,
+        }
+    }
+
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Build the constant pool in order of first use so that output is
+        // deterministic for a given program, with each value's index kept
+        // alongside for the instruction streams.
+        let mut pool = Vec::<i32>::new();
+        let mut index = HashMap::<i32, usize>::new();
+        let streams = std::iter::once(&self.code).chain(self.functions.values());
+        for stream in streams {
+            for (_, arg) in stream {
+                if let Some(v) = arg {
+                    index.entry(*v).or_insert_with(|| {
+                        pool.push(*v);
+                        pool.len() - 1
+                    });
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        let flags = if self.debug.is_some() { FLAG_DEBUG } else { 0 };
+        out.extend_from_slice(&flags.to_le_bytes());
+
+        write_varint(&mut out, pool.len() as u64);
+        for v in &pool {
+            write_varint(&mut out, zigzag(*v));
+        }
+
+        write_varint(&mut out, self.functions.len() as u64);
+        for (name, body) in &self.functions {
+            write_str(&mut out, name);
+            write_stream(&mut out, body, &index);
+        }
+
+        write_stream(&mut out, &self.code, &index);
+
+        if let Some(debug) = &self.debug {
+            write_varint(&mut out, debug.files.len() as u64);
+            for file in &debug.files {
+                write_str(&mut out, file);
+            }
+            for span in &debug.spans {
+                write_varint(&mut out, span.file.into());
+                write_varint(&mut out, span.line.into());
+                write_varint(&mut out, span.column.into());
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        if !Program::is_binary(bytes) {
+            return Err(DecodeError::BadMagic);
+        }
+        let mut cur = Cursor {
+            bytes,
+            pos: MAGIC.len(),
+            version: VERSION,
+        };
+
+        cur.version = cur.u16()?;
+        if !(1..=VERSION).contains(&cur.version) {
+            return Err(DecodeError::UnsupportedVersion(cur.version));
+        }
+        let flags = cur.u16()?;
+
+        let npool = cur.varint()?;
+        let mut pool = Vec::new();
+        for _ in 0..npool {
+            pool.push(unzigzag(cur.varint()?));
+        }
+
+        let mut functions = BTreeMap::new();
+        for _ in 0..cur.varint()? {
+            let name = cur.string()?;
+            let body = cur.stream(&pool)?;
+            functions.insert(name, body);
+        }
+
+        let code = cur.stream(&pool)?;
+
+        let debug = if flags & FLAG_DEBUG != 0 {
+            let mut debug = DebugInfo::default();
+            for _ in 0..cur.varint()? {
+                debug.files.push(cur.string()?);
+            }
+            for _ in 0..code.len() {
+                debug.spans.push(Span {
+                    file: cur.varint()? as u32,
+                    line: cur.varint()? as u32,
+                    column: cur.varint()? as u32,
+                });
+            }
+            Some(debug)
+        } else {
+            None
+        };
+
+        if cur.pos != bytes.len() {
+            return Err(DecodeError::TrailingBytes(bytes.len() - cur.pos));
+        }
+
+        Ok(Program {
+            code,
+            functions,
+            debug,
+        })
+    }
+}
+
+/*
+ * Emits source that reads back to the same program. Functions from the
+ * function table become definitions at the top of the file.
+ */
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, body) in &self.functions {
+            writeln!(f, "const 0")?;
+            writeln!(f, "pushstr {}", name)?;
+            writeln!(f, "function")?;
+            let mut inner = String::new();
+            disasm::write_source(&mut inner, body)?;
+            for line in inner.lines() {
+                writeln!(f, "  {}", line)?;
+            }
+            writeln!(f, "endfunction")?;
+        }
+        disasm::write_source(f, &self.code)
+    }
+}
+
+fn zigzag(v: i32) -> u64 {
+    (((v << 1) ^ (v >> 31)) as u32).into()
+}
+
+fn unzigzag(v: u64) -> i32 {
+    let v = v as u32;
+    ((v >> 1) as i32) ^ -((v & 1) as i32)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Operands are written as 1 + their index in the constant pool, 0 for none
+fn write_stream(out: &mut Vec<u8>, code: &[(Op, Option<i32>)], pool: &HashMap<i32, usize>) {
+    write_varint(out, code.len() as u64);
+    for (op, arg) in code {
+        write_varint(out, *op as u64);
+        let operand = match arg {
+            Some(v) => pool[v] as u64 + 1,
+            None => 0,
+        };
+        write_varint(out, operand);
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.take(1)?[0];
+            if shift >= 64 {
+                return Err(DecodeError::VarintOverflow);
+            }
+            result |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.varint()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn stream(&mut self, pool: &[i32]) -> Result<Vec<(Op, Option<i32>)>, DecodeError> {
+        let len = self.varint()?;
+        let mut code = Vec::new();
+        for _ in 0..len {
+            let raw = self.varint()?;
+            let op = u16::try_from(raw)
+                .ok()
+                .and_then(Op::from_u16)
+                .ok_or(DecodeError::InvalidOpcode(raw))?;
+            let op = match op {
+                Op::PrintStr if self.version < 2 => Op::PrintChars,
+                op => op,
+            };
+            let arg = match self.varint()? {
+                0 => None,
+                i => Some(
+                    *pool
+                        .get(i as usize - 1)
+                        .ok_or(DecodeError::InvalidConstant(i))?,
+                ),
+            };
+            code.push((op, arg));
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod program_test {
+
+    use super::*;
+    use crate::stackmachine::reader;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut program = Program::new(vec![
+            (Op::Const, Some(-1)),
+            (Op::Const, Some(300)),
+            (Op::Add, None),
+            (Op::Const, Some(i32::MIN)),
+            (Op::Const, Some(i32::MAX)),
+            (Op::Print, None),
+        ]);
+        program.functions.insert(
+            "fn".to_string(),
+            vec![(Op::Const, Some(300)), (Op::Mul, None)],
+        );
+
+        assert_eq!(
+            Ok(program.clone()),
+            Program::from_bytes(&program.to_bytes())
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_debug_info() {
+        let program = reader::read_program("examples/cond.sm").unwrap();
+        assert!(program.debug.is_some());
+
+        let decoded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(program, decoded);
+        assert_eq!(
+            Some("examples/cond.sm:2:1".to_string()),
+            decoded.debug.unwrap().location(0)
+        );
+    }
+
+    #[test]
+    fn test_constant_pool_dedup() {
+        let program = Program::new(vec![(Op::Const, Some(123456)); 64]);
+        let bytes = program.to_bytes();
+
+        // Each instruction costs two bytes no matter how large the constant
+        assert!(bytes.len() < 64 * 2 + 16);
+    }
+
+    #[test]
+    fn test_version_1_strings() {
+        let program = Program::new(vec![(Op::Const, Some(0)), (Op::PrintStr, None)]);
+        let mut bytes = program.to_bytes();
+        bytes[4] = 1;
+
+        assert_eq!(
+            Program::new(vec![(Op::Const, Some(0)), (Op::PrintChars, None)]),
+            Program::from_bytes(&bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bad_input() {
+        assert_eq!(Err(DecodeError::BadMagic), Program::from_bytes(b"const 1"));
+
+        let mut bytes = Program::new(vec![(Op::Add, None)]).to_bytes();
+        bytes[4] = 99;
+        assert_eq!(
+            Err(DecodeError::UnsupportedVersion(99)),
+            Program::from_bytes(&bytes)
+        );
+
+        let bytes = Program::new(vec![(Op::Const, Some(1))]).to_bytes();
+        assert_eq!(
+            Err(DecodeError::UnexpectedEof),
+            Program::from_bytes(&bytes[..bytes.len() - 1])
+        );
+    }
+}
@@ -2,8 +2,26 @@ use std::borrow::Cow;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
+use std::str::FromStr;
 
+use crate::stackmachine::program::{DebugInfo, Program, Span};
 use crate::stackmachine::Op;
+use crate::stackmachine::{forth, lang, wide};
+
+/*
+ * Multiple strings passed to pushstr are joined by a single space, no matter
+ * what they were originally seperated by when pushed.
+ */
+fn push_text(words: &[&str], code: &mut Vec<(Op, Option<i32>)>) {
+    for (i, string) in words.iter().rev().enumerate() {
+        if i > 0 {
+            code.push((Op::Const, Some(' ' as i32)));
+        }
+        for c in string.chars().rev() {
+            code.push((Op::Const, Some(c as i32)));
+        }
+    }
+}
 
 pub struct Reader {
     pub filename: String,
@@ -21,71 +39,48 @@ where
     Ok(io::BufReader::new(file).lines())
 }
 
-fn resolve_path(short_path: &String) -> Result<String, ()> {
+fn resolve_path(short_path: &str) -> Result<String, ()> {
     let pkgs = short_path.split(".").collect::<Vec<&str>>();
     if pkgs.len() == 1 {
         let p = Path::new(pkgs[0]).join(".sm");
         if p.exists() {
-            return match p.to_string_lossy() {
+            match p.to_string_lossy() {
                 Cow::Borrowed(resolved) => Ok(resolved.to_string()),
                 Cow::Owned(resolved) => {
-                    println!("Filepath {} was not valid UTF-8.", resolved);
+                    eprintln!("Filepath {} was not valid UTF-8.", resolved);
                     Err(())
                 }
-            };
+            }
         } else {
-            println!("No local file with name {}.sm was found.", pkgs[0]);
+            eprintln!("No local file with name {}.sm was found.", pkgs[0]);
             Err(())
         }
     } else {
-        println!("No standard library yet. Please only include local files.");
+        eprintln!("No standard library yet. Please only include local files.");
         Err(())
     }
 }
 
-fn parse_opcode(line: &std::string::String, code: &mut Vec<(Op, Option<i32>)>) {
-    if line.len() == 0 {
+/*
+ * The words of a source line. Only spaces separate them, so a tab inside a
+ * string literal is part of the string; the formatter splits lines the same
+ * way so it never changes what a line means.
+ */
+pub fn words(line: &str) -> Vec<&str> {
+    line.trim().split(' ').filter(|v| !v.is_empty()).collect()
+}
+
+fn parse_opcode(line: &str, code: &mut Vec<(Op, Option<i32>)>, debug: &mut DebugInfo) {
+    if line.is_empty() {
         return;
     }
-    let strargs = String::from(line.as_str().trim());
-
-    let args = strargs
-        .split(" ")
-        .filter(|v| v.len() != 0)
-        .collect::<Vec<&str>>();
+    let args = words(line);
 
-    if args.len() == 0 || args[0] == "#" || args[0].chars().nth(0) == Some('#') {
+    if args.is_empty() || args[0] == "#" || args[0].starts_with('#') {
         return;
     }
 
     let res = match args[0].to_ascii_lowercase().as_str() {
-        "const" => Some(Op::Const),
-        "add" => Some(Op::Add),
-        "sub" => Some(Op::Sub),
-        "mul" => Some(Op::Mul),
-        "div" => Some(Op::Div),
-        "pop" => Some(Op::Pop),
-        "push" => Some(Op::Push),
-        "pushstr" => Some(Op::PushStr),
-        "if" => Some(Op::If),
-        "else" => Some(Op::Else),
-        "endif" => Some(Op::EndIf),
-        "gt" => Some(Op::GT),
-        "lt" => Some(Op::LT),
-        "gte" => Some(Op::GTE),
-        "lte" => Some(Op::LTE),
-        "eq" => Some(Op::r#Eq),
-        "call" => Some(Op::Call),
-        "function" => Some(Op::Function),
-        "endfunction" => Some(Op::EndFunction),
-        "return" => Some(Op::Return),
-        "fork" => Some(Op::Fork),
-        "child" => Some(Op::Child),
-        "getpid" => Some(Op::GetPid),
-        "dbg" => Some(Op::Debug),
-        "print" => Some(Op::Print),
-        "printstr" => Some(Op::PrintStr),
-        "include" => Some(Op::Include),
         "true" => {
             code.push((Op::Const, Some(1)));
             return;
@@ -94,10 +89,47 @@ fn parse_opcode(line: &std::string::String, code: &mut Vec<(Op, Option<i32>)>) {
             code.push((Op::Const, Some(0)));
             return;
         }
-        _ => None,
+        mnemonic => mnemonic.parse::<Op>().ok(),
     };
 
     if let Some(op) = res {
+        // Wide literals expand to their two cells, low word first
+        if op == Op::I64Const || op == Op::FConst {
+            let literal = args.get(1).copied().unwrap_or("");
+            let cells = if op == Op::I64Const {
+                literal.parse().ok().map(wide::i64_cells)
+            } else {
+                wide::parse_f64(literal).map(wide::f64_cells)
+            };
+            match cells {
+                Some(cells) => code.extend(cells.map(|c| (Op::Const, Some(c)))),
+                None => panic!("Could not parse {:?} as the operand of `{}`.", literal, op),
+            }
+            return;
+        }
+        // A string literal is its characters, made into a handle by `str.new`
+        if op == Op::StrConst {
+            code.push((Op::Const, Some(0)));
+            push_text(&args[1..], code);
+            code.push((Op::StrNew, None));
+            return;
+        }
+        // `fnref NAME` pushes the name for `fnref` to pop, like `call` does
+        if op == Op::FnRef && args.len() > 1 {
+            code.push((Op::Const, Some(0)));
+            push_text(&args[1..], code);
+            code.push((Op::FnRef, None));
+            return;
+        }
+        // `throw CODE`, `exit CODE` and `argv N` push the number for the
+        // opcode to pop
+        if matches!(op, Op::Throw | Op::Exit | Op::Argv) && args.len() > 1 {
+            match args[1].parse::<i32>() {
+                Ok(n) => code.extend([(Op::Const, Some(n)), (op, None)]),
+                Err(_) => panic!("Could not parse {:?} as a number for `{}`.", args[1], op),
+            }
+            return;
+        }
         if args.len() > 1 {
             // handle i32 arg
             if let Ok(val) = args[1].parse::<i32>() {
@@ -108,30 +140,13 @@ fn parse_opcode(line: &std::string::String, code: &mut Vec<(Op, Option<i32>)>) {
             // Handle special non-i32 args
             match op {
                 Op::Include => {
-                    if let Ok(filename) = resolve_path(&args[1].to_string()) {
-                        let input = read(&filename);
-                        if let Some(c) = input {
-                            code.extend(c)
-                        } else {
+                    if let Ok(filename) = resolve_path(args[1]) {
+                        if !read_into(&filename, code, debug) {
                             panic!("Could not parse included file {}.", args[1]);
                         }
                     }
                 }
-                /*
-                 * Multiple strings passed to pushstr are joined by a single
-                 * space, no matter what they were originally seperated by
-                 * when pushed.
-                 */
-                Op::PushStr => {
-                    for (i, string) in args[1..].to_vec().iter().rev().enumerate() {
-                        if i > 0 {
-                            code.push((Op::Const, Some(' ' as i32)));
-                        }
-                        for c in string.chars().rev() {
-                            code.push((Op::Const, Some(c as i32)));
-                        }
-                    }
-                }
+                Op::PushStr => push_text(&args[1..], code),
                 _ => panic!("Could not parse argument to i32 and op was not a string opcode."),
             }
             return;
@@ -142,26 +157,150 @@ fn parse_opcode(line: &std::string::String, code: &mut Vec<(Op, Option<i32>)>) {
     }
 }
 
-// Reads opcodes from a file
-pub fn read(filename: &String) -> Option<Vec<(Op, Option<i32>)>> {
-    let mut code: Vec<(Op, Option<i32>)> = Vec::new();
-
-    #[cfg(debug_assertions)]
-    println!("-- {}", filename);
-    if let Ok(lines) = read_lines(filename) {
-        for line in lines {
-            if let Ok(l) = line {
-                parse_opcode(&l, &mut code);
+fn parse_lines<I>(
+    lines: I,
+    filename: &str,
+    code: &mut Vec<(Op, Option<i32>)>,
+    debug: &mut DebugInfo,
+) where
+    I: Iterator<Item = String>,
+{
+    let file = debug.file_index(filename);
+    for (lineno, l) in lines.enumerate() {
+        parse_opcode(&l, code, debug);
+
+        // Everything this line expanded to shares its span. Included files
+        // have already recorded spans of their own.
+        let column = l.len() - l.trim_start().len() + 1;
+        while debug.spans.len() < code.len() {
+            debug.spans.push(Span {
+                file,
+                line: lineno as u32 + 1,
+                column: column as u32,
+            });
+        }
+    }
+}
+
+// Fails on a file that cannot be read or is not UTF-8, rather than parsing
+// only the lines before the problem
+fn read_into(filename: &str, code: &mut Vec<(Op, Option<i32>)>, debug: &mut DebugInfo) -> bool {
+    let lines = read_lines(filename).and_then(|lines| lines.collect::<io::Result<Vec<_>>>());
+    match lines {
+        Ok(lines) => {
+            parse_lines(lines.into_iter(), filename, code, debug);
+            true
+        }
+        Err(e) => {
+            eprintln!("Could not read {}: {}", filename, e);
+            false
+        }
+    }
+}
+
+// Parses source text that did not come from a file
+pub fn parse(source: &str, filename: &str) -> Program {
+    let mut program = Program::default();
+    let mut debug = DebugInfo::default();
+    parse_lines(
+        source.lines().map(String::from),
+        filename,
+        &mut program.code,
+        &mut debug,
+    );
+    program.debug = Some(debug);
+    program
+}
+
+// Reads a program from a file, recording the source span of each opcode
+pub fn read_program(filename: &str) -> Option<Program> {
+    let mut program = Program::default();
+    let mut debug = DebugInfo::default();
+    if !read_into(filename, &mut program.code, &mut debug) {
+        return None;
+    }
+    program.debug = Some(debug);
+    Some(program)
+}
+
+// Source languages a file can be read as. `Sm` covers the native formats,
+// which are told apart by extension and magic bytes. `Chars` is native source
+// written before strings were heap values, where `printstr` printed a
+// null-terminated string of characters; it reads `printstr` as `printchars`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Sm,
+    Forth,
+    Chars,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sm" => Ok(Dialect::Sm),
+            "forth" => Ok(Dialect::Forth),
+            "chars" => Ok(Dialect::Chars),
+            _ => Err(format!("unknown dialect {}", s)),
+        }
+    }
+}
+
+// Loads a file written in the given dialect
+pub fn load_dialect(filename: &str, dialect: Dialect) -> Option<Program> {
+    match dialect {
+        Dialect::Sm => load(filename),
+        Dialect::Chars => {
+            let mut program = read_program(filename)?;
+            for (op, _) in &mut program.code {
+                if *op == Op::PrintStr {
+                    *op = Op::PrintChars;
+                }
+            }
+            Some(program)
+        }
+        Dialect::Forth => {
+            let source = std::fs::read_to_string(filename).ok()?;
+            match forth::compile(&source, filename) {
+                Ok(program) => Some(program),
+                Err(e) => {
+                    eprintln!("{}:{}", filename, e);
+                    None
+                }
+            }
+        }
+    }
+}
+
+// Loads either `.sm` source or a compiled `.smb` binary, based on the file's
+// leading magic bytes rather than its extension. `.sml` files are compiled
+// from the infix language.
+pub fn load(filename: &str) -> Option<Program> {
+    let bytes = std::fs::read(filename).ok()?;
+    if filename.ends_with(".sml") {
+        let source = String::from_utf8(bytes).ok()?;
+        match lang::compile(&source, filename) {
+            Ok(program) => Some(program),
+            Err(e) => {
+                eprintln!("{}:{}", filename, e);
+                None
             }
         }
-        #[cfg(debug_assertions)]
-        for (op, v) in code.iter().cloned() {
-            match v {
-                Some(v) => println!("{:?} {}", op, v),
-                None => println!("{:?}", op),
+    } else if Program::is_binary(&bytes) {
+        match Program::from_bytes(&bytes) {
+            Ok(program) => Some(program),
+            Err(e) => {
+                eprintln!("Could not load {}: {}", filename, e);
+                None
             }
         }
-        return Some(code);
+    } else {
+        read_program(filename)
     }
-    None
+}
+
+// Reads opcodes from a file
+pub fn read(filename: &str) -> Option<Vec<(Op, Option<i32>)>> {
+    read_program(filename).map(|p| p.code)
 }
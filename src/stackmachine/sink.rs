@@ -0,0 +1,51 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/*
+ * Where a machine writes. Program output (`print`, `printstr`, `dbg`, ...)
+ * goes to the machine's output sink, stdout unless told otherwise, and the
+ * interpreter's own tracing goes to a separate trace sink that is off unless
+ * one is set. Sinks are shared, so forked children can write to the same one
+ * as their parent.
+ */
+
+pub type Sink = Arc<Mutex<dyn Write + Send>>;
+
+pub fn sink<W: Write + Send + 'static>(writer: W) -> Sink {
+    Arc::new(Mutex::new(writer))
+}
+
+pub fn stdout() -> Sink {
+    sink(io::stdout())
+}
+
+// Writes a line, panicking like `println!` if the sink fails
+pub fn write_line(sink: &Sink, line: std::fmt::Arguments) {
+    let mut writer = sink.lock().unwrap();
+    writeln!(writer, "{}", line).expect("Could not write to the output sink");
+}
+
+// A sink that keeps what is written, so it can be read back
+#[derive(Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::convert::TryFrom;
+
+use crate::stackmachine::heap::{Heap, Object};
+use crate::stackmachine::{Op, Trap};
+
+/*
+ * Strings as values. A string lives on the machine's `Heap` and the stack
+ * holds a handle to it, so it takes one cell however long it is and can be
+ * passed to functions, stored in memory and compared like any other value.
+ *
+ * Characters are Unicode code points, and `str.len`, `str.at` and `str.slice`
+ * count in code points. Binary opcodes pop a then b like the arithmetic ones,
+ * so `str.concat` puts the string that was on top first. An index outside the
+ * string traps, and so does a value that is not a string handle.
+ *
+ * The older convention of null-terminated characters on the stack is still
+ * what `pushstr`, `call`, `function` and `callext` use, and `printchars`
+ * prints such a string. `str.new` turns one into a handle.
+ */
+
+/*
+ * Pops character codes up to the terminating 0, first character on top.
+ * Traps if the stack runs out first instead of waiting for a terminator that
+ * will never come.
+ */
+pub fn pop_chars(op: Op, stack: &mut Vec<i32>) -> Result<Vec<i32>, Trap> {
+    let mut chars = Vec::new();
+    loop {
+        match stack.pop() {
+            Some(0) => return Ok(chars),
+            Some(c) => chars.push(c),
+            None => return Err(Trap::StackUnderflow(op)),
+        }
+    }
+}
+
+// A character code as a `char`, with U+FFFD standing in for invalid codes
+pub fn to_char(c: i32) -> char {
+    char::from_u32(c as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+// Whether `execute` runs `op`
+pub fn is_string(op: Op) -> bool {
+    matches!(
+        op,
+        Op::StrNew
+            | Op::StrLen
+            | Op::StrConcat
+            | Op::StrEq
+            | Op::StrAt
+            | Op::StrSlice
+            | Op::IntToStr
+    )
+}
+
+// Runs a string opcode on the stack, or returns `None` if `op` is not one
+pub fn execute(heap: &mut Heap, op: Op, stack: &mut Vec<i32>) -> Option<Result<(), Trap>> {
+    if !is_string(op) {
+        return None;
+    }
+    Some(string_op(heap, op, stack).map(|v| stack.push(v)))
+}
+
+fn string_op(heap: &mut Heap, op: Op, stack: &mut Vec<i32>) -> Result<i32, Trap> {
+    let string = |heap: &Heap, handle| heap.string(handle).map(str::to_string);
+    let bad_handle = Trap::BadHandle(op);
+    let v = match op {
+        Op::StrNew => {
+            let s = pop_chars(op, stack)?.into_iter().map(to_char).collect();
+            heap.alloc(Object::Str(s))
+        }
+        Op::StrLen => {
+            let s = heap.string(stack.pop().unwrap()).ok_or(bad_handle)?;
+            s.chars().count() as i32
+        }
+        Op::StrConcat => {
+            let a = stack.pop().unwrap();
+            let b = stack.pop().unwrap();
+            let a = string(heap, a).ok_or(bad_handle.clone())?;
+            let b = heap.string(b).ok_or(bad_handle)?;
+            let s = format!("{}{}", a, b);
+            heap.alloc(Object::Str(s))
+        }
+        Op::StrEq => {
+            let a = stack.pop().unwrap();
+            let b = stack.pop().unwrap();
+            let a = heap.string(a).ok_or(bad_handle.clone())?;
+            (a == heap.string(b).ok_or(bad_handle)?) as i32
+        }
+        Op::StrAt => {
+            let s = heap.string(stack.pop().unwrap()).ok_or(bad_handle)?;
+            let i = stack.pop().unwrap();
+            match usize::try_from(i).ok().and_then(|i| s.chars().nth(i)) {
+                Some(c) => c as i32,
+                None => return Err(Trap::OutOfRange(op)),
+            }
+        }
+        Op::StrSlice => {
+            let s = string(heap, stack.pop().unwrap()).ok_or(bad_handle)?;
+            let start = stack.pop().unwrap();
+            let end = stack.pop().unwrap();
+            let len = s.chars().count() as i32;
+            if start < 0 || start > end || end > len {
+                return Err(Trap::OutOfRange(op));
+            }
+            let slice = s
+                .chars()
+                .skip(start as usize)
+                .take((end - start) as usize)
+                .collect();
+            heap.alloc(Object::Str(slice))
+        }
+        _ => {
+            let a = stack.pop().unwrap();
+            heap.alloc(Object::Str(a.to_string()))
+        }
+    };
+    Ok(v)
+}
+
+#[cfg(test)]
+mod strings_test {
+
+    use super::*;
+
+    fn run(heap: &mut Heap, ops: &[Op], stack: &mut Vec<i32>) -> Result<(), Trap> {
+        for op in ops {
+            execute(heap, *op, stack).unwrap()?;
+        }
+        Ok(())
+    }
+
+    fn chars(s: &str) -> Vec<i32> {
+        let mut stack = vec![0];
+        stack.extend(s.chars().rev().map(|c| c as i32));
+        stack
+    }
+
+    #[test]
+    fn test_new_and_concat() {
+        let mut heap = Heap::new();
+        let mut stack = chars("world");
+        run(&mut heap, &[Op::StrNew], &mut stack).unwrap();
+        stack.extend(chars("hello "));
+        run(&mut heap, &[Op::StrNew], &mut stack).unwrap();
+
+        assert_eq!(vec![1, 2], stack);
+        assert_eq!(Some("hello "), heap.string(2));
+
+        run(&mut heap, &[Op::StrConcat, Op::StrLen], &mut stack).unwrap();
+
+        assert_eq!(vec![11], stack);
+        assert_eq!(Some("hello world"), heap.string(3));
+    }
+
+    #[test]
+    fn test_eq_at_slice() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(Object::Str("héllo".to_string()));
+        let b = heap.alloc(Object::Str("héllo".to_string()));
+        let mut stack = vec![a, b];
+        run(&mut heap, &[Op::StrEq], &mut stack).unwrap();
+        stack.extend([1, a]);
+        run(&mut heap, &[Op::StrAt], &mut stack).unwrap();
+        stack.extend([4, 1, a]);
+        run(&mut heap, &[Op::StrSlice], &mut stack).unwrap();
+
+        assert_eq!(vec![1, 'é' as i32, 3], stack);
+        assert_eq!(Some("éll"), heap.string(3));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut heap = Heap::new();
+        let s = heap.alloc(Object::Str("abc".to_string()));
+
+        let mut stack = vec![3, s];
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::StrAt)),
+            run(&mut heap, &[Op::StrAt], &mut stack)
+        );
+
+        let mut stack = vec![4, 2, s];
+        assert_eq!(
+            Err(Trap::OutOfRange(Op::StrSlice)),
+            run(&mut heap, &[Op::StrSlice], &mut stack)
+        );
+
+        let mut stack = vec![3, 3, s];
+        run(&mut heap, &[Op::StrSlice, Op::StrLen], &mut stack).unwrap();
+        assert_eq!(vec![0], stack);
+    }
+
+    #[test]
+    fn test_int_to_str() {
+        let mut heap = Heap::new();
+        let mut stack = vec![-42];
+        run(&mut heap, &[Op::IntToStr], &mut stack).unwrap();
+
+        assert_eq!(Some("-42"), heap.string(stack[0]));
+    }
+
+    #[test]
+    fn test_bad_handle() {
+        let mut heap = Heap::new();
+        let mut stack = vec![1];
+
+        assert_eq!(
+            Err(Trap::BadHandle(Op::StrLen)),
+            run(&mut heap, &[Op::StrLen], &mut stack)
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let mut heap = Heap::new();
+        let mut stack = vec![104, 105];
+
+        assert_eq!(
+            Err(Trap::StackUnderflow(Op::StrNew)),
+            run(&mut heap, &[Op::StrNew], &mut stack)
+        );
+    }
+}
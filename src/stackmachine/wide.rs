@@ -0,0 +1,258 @@
+use crate::stackmachine::function::Exec;
+use crate::stackmachine::{Op, Trap};
+
+/*
+ * 64-bit integers and floats on the 32-bit stack. Each takes two cells: the
+ * low word is pushed first and the high word sits on top, so the stack stays
+ * a `Vec<i32>` and external functions and `Builder` work unchanged. The typed
+ * opcodes (`i64.add`, `f.mul`, ...) read the cells as the type they name;
+ * nothing checks that the cells were pushed as that type.
+ *
+ * Binary opcodes pop a then b, like their 32-bit counterparts, and
+ * comparisons push a single 32-bit 1 or 0. 64-bit integers wrap on overflow
+ * and trap on division by zero. Floats follow IEEE 754, so dividing by zero
+ * gives an infinity. `f2i` and `f2l` truncate towards zero, saturate at the
+ * limits of the target type and turn NaN into 0.
+ */
+
+pub fn push_i64(stack: &mut Vec<i32>, v: i64) {
+    stack.push(v as i32);
+    stack.push((v >> 32) as i32);
+}
+
+pub fn pop_i64(stack: &mut Vec<i32>) -> i64 {
+    let hi = stack.pop().unwrap();
+    let lo = stack.pop().unwrap();
+    ((hi as i64) << 32) | (lo as u32 as i64)
+}
+
+pub fn push_f64(stack: &mut Vec<i32>, v: f64) {
+    push_i64(stack, v.to_bits() as i64);
+}
+
+pub fn pop_f64(stack: &mut Vec<i32>) -> f64 {
+    f64::from_bits(pop_i64(stack) as u64)
+}
+
+// The cells `i64.const` and `f.const` expand to, low word first
+pub fn i64_cells(v: i64) -> [i32; 2] {
+    [v as i32, (v >> 32) as i32]
+}
+
+pub fn f64_cells(v: f64) -> [i32; 2] {
+    i64_cells(v.to_bits() as i64)
+}
+
+// Reads a float literal, including `inf`, `-inf` and `nan`
+pub fn parse_f64(s: &str) -> Option<f64> {
+    s.parse().ok()
+}
+
+// How `f.print` shows a float: always with a fraction or an exponent
+pub fn format_f64(v: f64) -> String {
+    format!("{:?}", v)
+}
+
+// Whether `execute` runs `op`
+pub fn is_wide(op: Op) -> bool {
+    op.info().exec == Exec::Wide
+}
+
+/*
+ * Runs a typed opcode on the stack, or returns `None` if `op` is not one.
+ * `i64.print` and `f.print` are left to the caller; see `format_top`.
+ */
+pub fn execute(op: Op, stack: &mut Vec<i32>) -> Option<Result<(), Trap>> {
+    match op {
+        Op::I64Add | Op::I64Sub | Op::I64Mul | Op::I64Div | Op::I64Mod => {
+            let a = pop_i64(stack);
+            let b = pop_i64(stack);
+            let v = match op {
+                Op::I64Add => a.wrapping_add(b),
+                Op::I64Sub => a.wrapping_sub(b),
+                Op::I64Mul => a.wrapping_mul(b),
+                _ if b == 0 => return Some(Err(Trap::DivideByZero(op))),
+                Op::I64Div => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            };
+            push_i64(stack, v);
+        }
+        Op::I64Eq | Op::I64Lt | Op::I64Gt => {
+            let a = pop_i64(stack);
+            let b = pop_i64(stack);
+            let v = match op {
+                Op::I64Eq => a == b,
+                Op::I64Lt => a < b,
+                _ => a > b,
+            };
+            stack.push(v as i32);
+        }
+        Op::FAdd | Op::FSub | Op::FMul | Op::FDiv => {
+            let a = pop_f64(stack);
+            let b = pop_f64(stack);
+            let v = match op {
+                Op::FAdd => a + b,
+                Op::FSub => a - b,
+                Op::FMul => a * b,
+                _ => a / b,
+            };
+            push_f64(stack, v);
+        }
+        Op::FNeg | Op::FSqrt => {
+            let a = pop_f64(stack);
+            push_f64(stack, if op == Op::FNeg { -a } else { a.sqrt() });
+        }
+        Op::FEq | Op::FLt | Op::FGt => {
+            let a = pop_f64(stack);
+            let b = pop_f64(stack);
+            let v = match op {
+                Op::FEq => a == b,
+                Op::FLt => a < b,
+                _ => a > b,
+            };
+            stack.push(v as i32);
+        }
+        Op::I2F => {
+            let a = stack.pop().unwrap();
+            push_f64(stack, a as f64);
+        }
+        Op::F2I => {
+            let a = pop_f64(stack);
+            stack.push(a as i32);
+        }
+        Op::I2L => {
+            let a = stack.pop().unwrap();
+            push_i64(stack, a as i64);
+        }
+        Op::L2I => {
+            let a = pop_i64(stack);
+            stack.push(a as i32);
+        }
+        Op::L2F => {
+            let a = pop_i64(stack);
+            push_f64(stack, a as f64);
+        }
+        Op::F2L => {
+            let a = pop_f64(stack);
+            push_i64(stack, a as i64);
+        }
+        _ => return None,
+    }
+    Some(Ok(()))
+}
+
+// What `i64.print` or `f.print` shows for the value on top of the stack
+pub fn format_top(op: Op, stack: &[i32]) -> String {
+    if stack.len() < 2 {
+        panic!(
+            "`{}` needs two cells but the stack has {}.",
+            op,
+            stack.len()
+        );
+    }
+    let mut top = stack[stack.len() - 2..].to_vec();
+    match op {
+        Op::FPrint => format_f64(pop_f64(&mut top)),
+        _ => pop_i64(&mut top).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod wide_test {
+
+    use super::*;
+
+    fn run(ops: &[Op], stack: &mut Vec<i32>) -> Result<(), Trap> {
+        for op in ops {
+            execute(*op, stack).unwrap()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cells() {
+        let mut stack = Vec::new();
+        push_i64(&mut stack, -2);
+
+        assert_eq!(vec![-2, -1], stack);
+        assert_eq!(i64_cells(-2).to_vec(), stack);
+        assert_eq!(-2, pop_i64(&mut stack));
+
+        push_i64(&mut stack, 1 << 40);
+        push_f64(&mut stack, -0.5);
+
+        assert_eq!(-0.5, pop_f64(&mut stack));
+        assert_eq!(1 << 40, pop_i64(&mut stack));
+    }
+
+    #[test]
+    fn test_i64() {
+        let mut stack = Vec::new();
+        push_i64(&mut stack, 3);
+        push_i64(&mut stack, i64::MAX);
+        run(&[Op::I64Add], &mut stack).unwrap();
+
+        assert_eq!(i64::MIN + 2, pop_i64(&mut stack));
+
+        push_i64(&mut stack, 4);
+        push_i64(&mut stack, 1 << 32);
+        run(&[Op::I64Div, Op::L2I], &mut stack).unwrap();
+
+        assert_eq!(vec![1 << 30], stack);
+
+        push_i64(&mut stack, 0);
+        push_i64(&mut stack, 1);
+
+        assert_eq!(
+            Err(Trap::DivideByZero(Op::I64Mod)),
+            run(&[Op::I64Mod], &mut stack)
+        );
+    }
+
+    #[test]
+    fn test_f64() {
+        let mut stack = vec![2];
+        run(&[Op::I2F, Op::FSqrt], &mut stack).unwrap();
+        push_f64(&mut stack, 1.0);
+        run(&[Op::FDiv], &mut stack).unwrap();
+
+        assert_eq!(1.0 / 2f64.sqrt(), pop_f64(&mut stack));
+
+        push_f64(&mut stack, 0.0);
+        push_f64(&mut stack, -1.0);
+        run(&[Op::FDiv, Op::FNeg], &mut stack).unwrap();
+
+        assert_eq!(f64::INFINITY, pop_f64(&mut stack));
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut stack = vec![-7];
+        run(&[Op::I2L, Op::L2F, Op::F2L, Op::L2I], &mut stack).unwrap();
+
+        assert_eq!(vec![-7], stack);
+
+        push_f64(&mut stack, 1e20);
+        run(&[Op::F2I], &mut stack).unwrap();
+        push_f64(&mut stack, f64::NAN);
+        run(&[Op::F2I], &mut stack).unwrap();
+
+        assert_eq!(vec![-7, i32::MAX, 0], stack);
+    }
+
+    #[test]
+    fn test_compare_and_format() {
+        let mut stack = Vec::new();
+        push_f64(&mut stack, 1.5);
+        push_f64(&mut stack, 0.25);
+
+        assert_eq!("0.25", format_top(Op::FPrint, &stack));
+
+        run(&[Op::FLt], &mut stack).unwrap();
+
+        assert_eq!(vec![1], stack);
+        assert_eq!("1.0", format_f64(1.0));
+        assert_eq!("1e100", format_f64(1e100));
+        assert_eq!(Some(f64::NEG_INFINITY), parse_f64("-inf"));
+    }
+}